@@ -70,7 +70,7 @@ function getDefaultDBFileName(name: string, id: string) {
 /**
  * This function is called when the user clicks the "Save" button in the "Save Workspace" dialog.
  *
- * It will just copy the file to the given path
+ * It will write a consistent backup of the database to the given path
  */
 export async function saveDBFileAs(
   workspaceId: string
@@ -105,7 +105,10 @@ export async function saveDBFileAs(
       };
     }
 
-    await fs.copyFile(db.path, filePath);
+    // the online backup includes pages still in the `-wal` file, which a
+    // plain file copy would miss
+    const connection = await db.adapter.connectIfNeeded();
+    await connection.backupTo(filePath);
     logger.log('saved', filePath);
     if (!fakedResult) {
       mainRPC.showItemInFolder(filePath).catch(err => {
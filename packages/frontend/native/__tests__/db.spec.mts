@@ -10,3 +10,16 @@ test('db validate', async t => {
   const result = await SqliteConnection.validate(path);
   t.is(result, ValidationResult.MissingVersionColumn);
 });
+
+test('db validation report', async t => {
+  const path = fileURLToPath(
+    new URL('./fixtures/test01.affine', import.meta.url)
+  );
+  const report = await SqliteConnection.getValidationReport(path);
+  t.is(report.result, ValidationResult.MissingVersionColumn);
+  t.deepEqual(report.missingTables, ['version_info']);
+  t.deepEqual(report.missingColumns, []);
+  t.true(report.isSqlite);
+  t.is(report.schemaVersion, undefined);
+  t.is(report.error, undefined);
+});
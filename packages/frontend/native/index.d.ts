@@ -1,17 +1,77 @@
 /* auto-generated by NAPI-RS */
 /* eslint-disable */
+/**
+ * Shares one connection per workspace file between every caller, and
+ * closes the connections nobody uses anymore.
+ */
+export declare class ConnectionManager {
+  constructor(options?: ConnectionManagerOptions | undefined | null)
+  /**
+   * Connect to a workspace, or share the connection already open for the
+   * same file. Rejects if the file is open with other `options`. Every call
+   * has to be paired with `close` of the returned connection, which gives it
+   * back to the manager. In-memory workspaces are never shared, every call
+   * gets one of its own that is gone once closed.
+   */
+  open(path: string, options?: ConnectionOptions | undefined | null): Promise<SqliteConnection>
+  /**
+   * Close every released workspace right away, returns how many were
+   * closed.
+   */
+  closeIdle(): Promise<number>
+  /** Close every workspace, including the ones still in use. */
+  closeAll(): Promise<void>
+  /** Paths of the open workspaces. */
+  openPaths(): Promise<Array<string>>
+}
+
+/**
+ * Clones share the pool and the state of the original, see
+ * `ConnectionManager`.
+ */
 export declare class SqliteConnection {
-  constructor(path: string)
+  /**
+   * Pass `:memory:` as `path` for a workspace that only lives in memory
+   * until the connection is closed.
+   */
+  constructor(path: string, options?: ConnectionOptions | undefined | null)
   connect(): Promise<void>
   addBlob(key: string, blob: Uint8Array): Promise<void>
+  /**
+   * `None` if the blob does not exist, rejects with a `SqliteErrorCode` if
+   * it can not be read.
+   */
   getBlob(key: string): Promise<BlobRow | null>
   deleteBlob(key: string): Promise<void>
   getBlobKeys(): Promise<Array<string>>
-  getUpdates(docId?: string | undefined | null): Promise<Array<UpdateRow>>
+  /**
+   * Get all updates of a doc, nothing is returned for a trashed doc unless
+   * `include_trashed` is set.
+   */
+  getUpdates(docId?: string | undefined | null, includeTrashed?: boolean | undefined | null): Promise<Array<UpdateRow>>
+  /**
+   * Get at most `limit` updates of a doc with id greater than `after_id`,
+   * ordered by id. Trashed docs are filtered like in `get_updates`.
+   */
+  getUpdatesPage(docId: string | undefined | null, afterId: number | undefined | null, limit: number, includeTrashed?: boolean | undefined | null): Promise<UpdatesPage>
+  /**
+   * Get all updates of a doc inserted after the update with `after_id`, used
+   * to catch up incrementally. Trashed docs are filtered like in
+   * `get_updates`.
+   */
+  getUpdatesSince(docId: string | undefined | null, afterId: number, includeTrashed?: boolean | undefined | null): Promise<Array<UpdateRow>>
+  /** Delete every update of a doc together with its snapshot. */
   deleteUpdates(docId?: string | undefined | null): Promise<void>
   getUpdatesCount(docId?: string | undefined | null): Promise<number>
   getAllUpdates(): Promise<Array<UpdateRow>>
+  /** Same as `get_updates_page` but across every doc in the workspace. */
+  getAllUpdatesPage(afterId: number | undefined | null, limit: number): Promise<UpdatesPage>
   insertUpdates(updates: Array<InsertRow>): Promise<void>
+  /**
+   * Replace every update of a doc. The snapshot of the doc is kept and the
+   * new updates are applied on top of it, `get_updates` does not return the
+   * content merged into it.
+   */
   replaceUpdates(docId: string | undefined | null, updates: Array<InsertRow>): Promise<void>
   getServerClock(key: string): Promise<BlobRow | null>
   setServerClock(key: string, data: Uint8Array): Promise<void>
@@ -23,13 +83,304 @@ export declare class SqliteConnection {
   getSyncMetadataKeys(): Promise<Array<string>>
   clearSyncMetadata(): Promise<void>
   delSyncMetadata(key: string): Promise<void>
+  /** Superseded by `upgrade`, which also keeps a backup. */
   initVersion(): Promise<void>
+  /**
+   * Record `version` as a new row of the migration history, the earlier rows
+   * are kept. Superseded by `migrate`, which records every version it
+   * applies.
+   */
   setVersion(version: number): Promise<void>
   getMaxVersion(): Promise<number>
+  /**
+   * Close the connection. One returned by `ConnectionManager::open` is
+   * given back to the manager instead, which closes it once unused.
+   */
   close(): Promise<void>
   get isClose(): boolean
   static validate(path: string): Promise<ValidationResult>
+  /** Superseded by `upgrade`, which also keeps a backup. */
   migrateAddDocId(): Promise<void>
+  /** Merge all updates of a doc into a single row in one transaction. */
+  compactUpdates(docId?: string | undefined | null, options?: CompactionOptions | undefined | null): Promise<CompactionResult>
+  /**
+   * Compact docs automatically once their updates count passes `threshold`
+   * after `insert_updates`. `None` disables auto compaction.
+   */
+  setCompactionThreshold(threshold?: number | undefined | null): void
+  /**
+   * Run every pending migration in order, each one in its own transaction.
+   * Returns the migrations applied by this call.
+   */
+  migrate(): Promise<Array<MigrationRecord>>
+  /** List every version recorded in `version_info`. */
+  getMigrationHistory(): Promise<Array<MigrationRecord>>
+  /**
+   * List blobs ordered by key without loading their data. Blobs written
+   * before their size and mime type were stored are sniffed instead, which
+   * reads the whole blob in encrypted workspaces, and the result is stored
+   * so the next listing does not sniff them again.
+   */
+  listBlobs(options?: ListBlobsOptions | undefined | null): Promise<BlobsPage>
+  getBlobSize(key: string): Promise<number | null>
+  /**
+   * Read at most `length` bytes of a blob starting at `offset`, like a HTTP
+   * Range request. Returns `None` if the blob does not exist.
+   */
+  getBlobRange(key: string, offset: number, length: number): Promise<Buffer | null>
+  /**
+   * Start a chunked upload, chunks are staged and only become visible as a
+   * blob once `commit_blob_upload` is called.
+   */
+  beginBlobUpload(): string
+  appendBlobChunk(uploadId: string, chunk: Uint8Array): Promise<void>
+  /**
+   * Write every staged chunk of the upload into `key` in a single
+   * transaction, the chunks are copied one by one.
+   */
+  commitBlobUpload(uploadId: string, key: string): Promise<void>
+  abortBlobUpload(uploadId: string): Promise<void>
+  /**
+   * Copy a file into the blob `key` chunk by chunk, without going through
+   * JavaScript. Encrypted workspaces read the whole file at once.
+   */
+  importBlob(key: string, path: string): Promise<void>
+  /**
+   * Copy the blob `key` into a file chunk by chunk. Returns `false` if the
+   * blob does not exist. Encrypted workspaces decrypt the whole blob at once.
+   * The file is written to `<path>.tmp` and renamed once complete, a failed
+   * export leaves `path` untouched.
+   */
+  exportBlob(key: string, path: string): Promise<boolean>
+  /**
+   * Find blobs that are not referenced by any doc and delete them unless
+   * `dry_run` is set. The references are collected in the same transaction
+   * that deletes the blobs, so an update inserted in between can not lose its
+   * blob. Fails without deleting anything if a doc can not be decoded.
+   */
+  gcBlobs(options?: BlobGcOptions | undefined | null): Promise<BlobGcResult>
+  /**
+   * Copy a transactionally consistent snapshot of the workspace to `path`
+   * with the sqlite online backup API, while the connection stays usable.
+   * `progress` is called after every step. The backup starts over whenever
+   * another connection writes to the workspace, and fails if that happens
+   * more than 10 times, retry when the workspace is idle.
+   */
+  backupTo(path: string, progress?: ((err: Error | null, arg: BackupProgress) => any) | undefined | null): Promise<void>
+  /**
+   * Write a compacted copy of the workspace without free pages to `path`
+   * with `VACUUM INTO`. `progress` is called every 100ms at most while the
+   * copy is written, see `vacuum`.
+   */
+  vacuumInto(path: string, progress?: ((err: Error | null, arg: VacuumActivity) => any) | undefined | null): Promise<void>
+  /**
+   * Check the workspace file and every row of it. With `repair`, bad rows are
+   * moved into the `quarantine` table so the rest of the workspace can still
+   * be loaded. Nothing is moved if `PRAGMA integrity_check` fails, the file
+   * should be restored from a backup instead.
+   */
+  checkIntegrity(options?: IntegrityCheckOptions | undefined | null): Promise<IntegrityReport>
+  /**
+   * Bring a workspace file written by an older release up to the latest
+   * version, replaces fixing `MissingDocIdColumn` and `MissingVersionColumn`
+   * by hand. The original file is copied to a backup first and restored if
+   * any migration or the verification of the result fails. The file must
+   * not be open while it is upgraded.
+   */
+  static upgrade(path: string): Promise<UpgradeResult>
+  /**
+   * Inspect a workspace file before importing it and report every problem
+   * found, `validate` only returns the first one.
+   */
+  static getValidationReport(path: string): Promise<ValidationReport>
+  /**
+   * Re-encrypt every row with `key` in a single transaction, `None` decrypts
+   * the workspace. Other reads and writes wait until it is done. Encrypting
+   * drops the search index, rebuild it after decrypting. The file is vacuumed
+   * afterwards so no payload under the previous key is left behind, except
+   * the skipped quarantined rows.
+   */
+  rotateEncryptionKey(key?: Uint8Array | undefined | null): Promise<KeyRotationResult>
+  /**
+   * Call `callback` whenever rows change, through this connection or any
+   * other connection to the same file. Changes of other connections are
+   * read from the change log by polling and may be reported with a delay.
+   */
+  subscribe(callback: ((err: Error | null, arg: ChangeEvent) => any)): Promise<number>
+  unsubscribe(id: number): void
+  /**
+   * List every doc with updates or a snapshot in a single aggregate query,
+   * trashed docs included.
+   */
+  listDocs(): Promise<Array<DocMeta>>
+  /**
+   * Move a doc into the trash, its updates are kept until it is purged but
+   * hidden from `get_updates`. Trashing a trashed doc does nothing, a purged
+   * or restored doc is trashed again.
+   */
+  trashDoc(docId: string): Promise<void>
+  /**
+   * Take a doc out of the trash, returns `false` if it is not in the trash or
+   * was purged already. The tombstone is kept with `restored_at` set, so the
+   * restore can be synced.
+   */
+  restoreDoc(docId: string): Promise<boolean>
+  /** List the docs in the trash, ordered by the time they were trashed. */
+  getTrashedDocs(): Promise<Array<DocTombstone>>
+  /**
+   * List docs trashed, purged or restored after the tombstone with
+   * `after_id`, ordered by id.
+   */
+  getDocDeletionsSince(afterId?: number | undefined | null): Promise<Array<DocTombstone>>
+  /**
+   * Delete the updates of docs trashed before the retention period, their
+   * tombstones are kept so the deletion can still be synced. Returns the ids
+   * of the purged docs.
+   */
+  purgeDocs(options?: PurgeDocsOptions | undefined | null): Promise<Array<string>>
+  /**
+   * Search the text of every doc that is not trashed. Words match as
+   * prefixes, quoted words as a phrase. Not available for encrypted
+   * workspaces, their text is never indexed.
+   */
+  search(query: string, options?: SearchOptions | undefined | null): Promise<Array<SearchResult>>
+  /**
+   * Extract the text of a doc again and replace its entries in the search
+   * index.
+   */
+  reindexDoc(docId: string): Promise<void>
+  /**
+   * Drop the search index and build it again from every doc, returns the
+   * number of indexed docs. Call it after decrypting a workspace, encrypted
+   * workspaces are not indexed.
+   */
+  rebuildSearchIndex(): Promise<number>
+  /**
+   * Load a doc from its snapshot and the updates inserted after it, without
+   * replaying the whole update log. Docs compacted into a snapshot have to be
+   * loaded this way, `get_updates` only returns the newer updates.
+   */
+  loadDoc(docId?: string | undefined | null, options?: LoadDocOptions | undefined | null): Promise<LoadedDoc>
+  /**
+   * Get the state vector of a doc in form like `Y.encodeStateVector(doc)`.
+   * The stored state vector of the snapshot is returned as is if there are
+   * no newer updates.
+   */
+  getStateVector(docId?: string | undefined | null): Promise<Buffer>
+  /**
+   * Encode what a peer with `state_vector` lacks of a doc as a single update,
+   * in form like `Y.encodeStateAsUpdate(doc, state_vector)`. Without a state
+   * vector the whole doc is encoded.
+   */
+  diffSince(docId?: string | undefined | null, stateVector?: Uint8Array | undefined | null): Promise<Buffer>
+  /**
+   * Rebuild a doc as it was at `timestamp` by merging the updates inserted
+   * until then, `None` if it had no updates yet. Fails if the history before
+   * `timestamp` was merged into a snapshot, or compacted together with later
+   * updates.
+   */
+  getDocAt(docId: string | undefined | null, timestamp: Date): Promise<Buffer | null>
+  /**
+   * Group the updates of a doc into edit sessions, a new session starts
+   * whenever no update was inserted for `gap` seconds, 10 minutes by default.
+   * Sessions are ordered by time, updates merged into a snapshot or by
+   * compaction are not listed on their own.
+   */
+  listEditSessions(docId?: string | undefined | null, gap?: number | undefined | null): Promise<Array<EditSession>>
+  /**
+   * Report what takes up space in the workspace file. `limit` caps the
+   * largest docs and blobs, 10 by default.
+   */
+  getStorageStats(limit?: number | undefined | null): Promise<StorageStats>
+  /**
+   * Rewrite the workspace file without free pages. Blocks every other
+   * access until done, and needs as much free disk space as the file takes.
+   * `progress` is called once the rewrite starts, then every 100ms at most.
+   */
+  vacuum(options?: VacuumOptions | undefined | null, progress?: ((err: Error | null, arg: VacuumActivity) => any) | undefined | null): Promise<VacuumResult>
+  /**
+   * Free unused pages in steps of `pages_per_step`, 256 by default, without
+   * rewriting the file. Other connections can use the workspace between the
+   * steps, `progress` is called after every step. Needs a file switched to
+   * incremental auto vacuum by `vacuum`.
+   */
+  incrementalVacuum(pagesPerStep?: number | undefined | null, progress?: ((err: Error | null, arg: VacuumProgress) => any) | undefined | null): Promise<VacuumResult>
+  kvGet(namespace: string, key: string): Promise<BlobRow | null>
+  /** Get the entries of every key that exists, ordered by key. */
+  kvGetMany(namespace: string, keys: Array<string>): Promise<Array<BlobRow>>
+  /** Get every entry whose key starts with `prefix`, ordered by key. */
+  kvScan(namespace: string, prefix?: string | undefined | null): Promise<Array<BlobRow>>
+  /** List the keys starting with `prefix` without loading their values. */
+  kvKeys(namespace: string, prefix?: string | undefined | null): Promise<Array<string>>
+  kvSet(namespace: string, key: string, data: Uint8Array): Promise<void>
+  /** Set every entry in a single transaction. */
+  kvSetMany(namespace: string, entries: Array<KvEntry>): Promise<void>
+  kvDelete(namespace: string, key: string): Promise<void>
+  /** Delete every key in a single transaction. */
+  kvDeleteMany(namespace: string, keys: Array<string>): Promise<void>
+  /** Delete every entry of a namespace. */
+  kvClear(namespace: string): Promise<void>
+  /**
+   * Set `key` to `data` only if its current value is `expected`. A missing
+   * `expected` matches a missing key, a missing `data` deletes the key.
+   * Returns whether the value was changed.
+   */
+  kvCompareAndSet(namespace: string, key: string, expected?: Uint8Array | undefined | null, data?: Uint8Array | undefined | null): Promise<boolean>
+  /**
+   * Start a transaction, every operation of the returned handle is applied
+   * atomically on `commit`. Writes through other handles or the connection
+   * itself wait for it up to the busy timeout, 5 seconds by default, and fail
+   * with `BUSY` after that. It is rolled back once no operation started for
+   * `idle_timeout` milliseconds, defaults to 30 seconds. The handle holds the
+   * encryption key until it is finished, `rotate_encryption_key` waits for it
+   * and every other call of the connection waits behind a pending rotation.
+   */
+  beginTransaction(idleTimeout?: number | undefined | null): Promise<SqliteTransaction>
+}
+
+/**
+ * Handle of a transaction started by `begin_transaction`. Rolled back if it
+ * is garbage collected or stays idle for its timeout before `commit` is
+ * called. Trashing, restoring and snapshotting docs are not part of the
+ * transaction, call them on the connection once it is committed.
+ */
+export declare class SqliteTransaction {
+  addBlob(key: string, blob: Uint8Array): Promise<void>
+  getBlob(key: string): Promise<BlobRow | null>
+  deleteBlob(key: string): Promise<void>
+  /**
+   * Same as `SqliteConnection::get_updates`, including the writes of this
+   * transaction.
+   */
+  getUpdates(docId?: string | undefined | null, includeTrashed?: boolean | undefined | null): Promise<Array<UpdateRow>>
+  /**
+   * Insert updates, the compaction threshold is only checked by inserts of
+   * the connection.
+   */
+  insertUpdates(updates: Array<InsertRow>): Promise<void>
+  /** Delete every update of a doc together with its snapshot. */
+  deleteUpdates(docId?: string | undefined | null): Promise<void>
+  /** Replace every update of a doc, same as `SqliteConnection::replace_updates`. */
+  replaceUpdates(docId: string | undefined | null, updates: Array<InsertRow>): Promise<void>
+  kvGet(namespace: string, key: string): Promise<BlobRow | null>
+  kvSet(namespace: string, key: string, data: Uint8Array): Promise<void>
+  kvDelete(namespace: string, key: string): Promise<void>
+  getServerClock(key: string): Promise<BlobRow | null>
+  setServerClock(key: string, data: Uint8Array): Promise<void>
+  delServerClock(key: string): Promise<void>
+  getSyncMetadata(key: string): Promise<BlobRow | null>
+  setSyncMetadata(key: string, data: Uint8Array): Promise<void>
+  delSyncMetadata(key: string): Promise<void>
+  /**
+   * Apply every operation of this transaction, the handle can not be used
+   * afterwards.
+   */
+  commit(): Promise<void>
+  /**
+   * Discard every operation of this transaction, does nothing if it is
+   * already finished.
+   */
+  rollback(): Promise<void>
 }
 
 export interface BlobRow {
@@ -38,13 +389,347 @@ export interface BlobRow {
   timestamp: Date
 }
 
+export interface BackupProgress {
+  /** Pages left to copy. */
+  remaining: number
+  /** Pages of the workspace. */
+  total: number
+}
+
+export interface BlobGcOptions {
+  /**
+   * Only report unreferenced blobs without deleting them, defaults to
+   * `true`.
+   */
+  dryRun?: boolean
+  /**
+   * Keep blobs added in the last `grace_period` seconds even if they are not
+   * referenced yet, defaults to one day.
+   */
+  gracePeriod?: number
+}
+
+export interface BlobGcResult {
+  /** Keys of the unreferenced blobs older than the grace period. */
+  unreferenced: Array<string>
+  /** Total size of the unreferenced blobs in bytes. */
+  size: number
+  deleted: boolean
+}
+
+export interface BlobMeta {
+  key: string
+  size: number
+  mime: string
+  timestamp: Date
+}
+
+export interface BlobSize {
+  key: string
+  /** Stored size in bytes. */
+  size: number
+}
+
+export interface BlobsPage {
+  blobs: Array<BlobMeta>
+  /**
+   * key of the last blob in this page, pass it as `after_key` to fetch the
+   * next page. `None` if there are no more blobs.
+   */
+  nextCursor?: string
+}
+
+export interface ChangeEvent {
+  /**
+   * Table of the changed rows, or namespace of changed key-value entries.
+   * `*` if the rows that changed are not known, reload everything in this
+   * case.
+   */
+  table: string
+  docId?: string
+  key?: string
+  /** Id of an inserted update, `None` if updates of the doc were deleted. */
+  rowId?: number
+}
+
+export interface CompactionOptions {
+  /**
+   * Merge the updates into the snapshot of the doc instead of a single
+   * update, load the doc with `load_doc` afterwards. Defaults to `false`.
+   */
+  snapshot?: boolean
+}
+
+export interface CompactionResult {
+  beforeCount: number
+  beforeSize: number
+  afterCount: number
+  afterSize: number
+}
+
+export interface ConnectionManagerOptions {
+  /**
+   * Seconds a workspace stays open after its last reference is released,
+   * defaults to 60.
+   */
+  idleTimeout?: number
+  /**
+   * Most file handles held by all workspaces together. A workspace counts
+   * with two for the file and its WAL for each of its `max_connections` and
+   * for the connection polling for subscriptions, and one for the shared
+   * WAL index. The least recently used idle workspaces are closed to make
+   * room, defaults to 128.
+   */
+  maxOpenFiles?: number
+}
+
+export interface ConnectionOptions {
+  /**
+   * Defaults to `Wal` for new databases, existing ones stay in `Wal` if they
+   * were in it and use `Delete` otherwise.
+   */
+  journalMode?: JournalMode
+  /**
+   * Defaults to `Normal` if the database is in `Wal` mode once connected and
+   * `Full` otherwise.
+   */
+  synchronous?: SynchronousMode
+  /** How long to wait for a locked database, in milliseconds. */
+  busyTimeout?: number
+  /** Max connections of the pool, defaults to 4. */
+  maxConnections?: number
+  /**
+   * Open the database read-only. Missing files are not created and
+   * migrations are not run.
+   */
+  readOnly?: boolean
+  /** See `SqliteConnection::set_compaction_threshold`. */
+  compactionThreshold?: number
+  /**
+   * 32 bytes key to encrypt every row payload with. A plaintext workspace is
+   * encrypted on its first connection with a key, see
+   * `SqliteConnection::rotate_encryption_key` to change it later. Only the
+   * payloads are encrypted, these stay readable without the key: doc ids and
+   * the timestamps of updates and snapshots, snapshot state vectors, blob
+   * keys, sizes and mime types, kv namespaces and keys, upload ids, doc
+   * tombstones, the change log and the metadata of quarantined rows.
+   */
+  encryptionKey?: Uint8Array
+}
+
+export interface DocMeta {
+  /** `None` for the root doc. */
+  docId?: string
+  /** Updates not merged into the snapshot yet. */
+  updatesCount: number
+  /** Total size of the snapshot and the updates in bytes. */
+  size: number
+  firstTimestamp: Date
+  lastTimestamp: Date
+  /** Whether the doc is in the trash, see `SqliteConnection::trash_doc`. */
+  trashed: boolean
+}
+
+export interface DocSize {
+  docId?: string
+  /** Stored size of the snapshot and the updates in bytes. */
+  size: number
+}
+
+export interface DocTombstone {
+  /**
+   * Grows with every trash, purge and restore, pass the largest one seen as
+   * `after_id` to `get_doc_deletions_since` to fetch only newer changes.
+   */
+  id: number
+  docId: string
+  deletedAt: Date
+  /** `None` while the doc is in the trash and can still be restored. */
+  purgedAt?: Date
+  /** Set once the doc was taken out of the trash again. */
+  restoredAt?: Date
+}
+
+export interface EditSession {
+  start: Date
+  /** Pass it to `get_doc_at` to restore the doc as it was after this session. */
+  end: Date
+  updatesCount: number
+  firstUpdateId: number
+  lastUpdateId: number
+}
+
 export interface InsertRow {
   docId?: string
   data: Uint8Array
 }
 
+export interface IntegrityCheckOptions {
+  /** Move bad rows into the `quarantine` table, defaults to `false`. */
+  repair?: boolean
+  /**
+   * Doc ids known to the caller, updates of any other doc are flagged. Only
+   * empty doc ids are flagged if not set.
+   */
+  docIds?: Array<string>
+}
+
+export interface IntegrityReport {
+  /**
+   * Problems reported by `PRAGMA integrity_check`, empty if the file is
+   * healthy.
+   */
+  errors: Array<string>
+  /** Ids of updates that can not be decoded as a Y update. */
+  corruptedUpdates: Array<number>
+  /** Ids of updates with an unexpected `doc_id`. */
+  unexpectedDocIdUpdates: Array<number>
+  /** Keys of zero-length blobs. */
+  emptyBlobs: Array<string>
+  /** Rows moved into the `quarantine` table by this check. */
+  quarantined: number
+}
+
+export declare enum JournalMode {
+  Wal = 0,
+  Delete = 1,
+  Off = 2
+}
+
+export interface KeyRotationResult {
+  /**
+   * Ids of the quarantined rows that could not be decrypted with the previous
+   * key, they are kept as they were and can not be read with the new one.
+   */
+  skippedQuarantineIds: Array<number>
+}
+
+export interface KvEntry {
+  key: string
+  data: Uint8Array
+}
+
+export interface ListBlobsOptions {
+  /** Only list blobs whose key starts with `prefix`. */
+  prefix?: string
+  /** Only list blobs whose key sorts after `after_key`. */
+  afterKey?: string
+  limit?: number
+}
+
+export interface LoadDocOptions {
+  /**
+   * Merge the snapshot and the newer updates into a single binary, defaults
+   * to `false`.
+   */
+  merge?: boolean
+  /** Load a trashed doc as well, defaults to `false`. */
+  includeTrashed?: boolean
+}
+
+export interface LoadedDoc {
+  /**
+   * Merged state of the doc up to `snapshot_update_id`, or of the whole doc
+   * if loaded with `merge`.
+   */
+  snapshot?: Buffer
+  /** State vector of `snapshot`. */
+  stateVector?: Buffer
+  /** Id of the last update merged into the stored snapshot. */
+  snapshotUpdateId?: number
+  /** Time of the last update merged into the stored snapshot. */
+  snapshotUpdatedAt?: Date
+  /**
+   * Updates newer than the snapshot ordered by id, empty if loaded with
+   * `merge`.
+   */
+  updates: Array<UpdateRow>
+}
+
+export interface MigrationRecord {
+  version: number
+  description: string
+  timestamp: Date
+}
+
 export declare function mintChallengeResponse(resource: string, bits?: number | undefined | null): Promise<string>
 
+export interface PurgeDocsOptions {
+  /**
+   * Keep docs trashed in the last `retention_period` seconds, defaults to 30
+   * days. `0` purges every trashed doc.
+   */
+  retentionPeriod?: number
+}
+
+export interface SearchOptions {
+  /** Only search the blocks of this doc. */
+  docId?: string
+  /** Defaults to 20. */
+  limit?: number
+  /** Inserted before every matched term in snippets, defaults to `<b>`. */
+  highlightStart?: string
+  /** Inserted after every matched term in snippets, defaults to `</b>`. */
+  highlightEnd?: string
+}
+
+export interface SearchResult {
+  docId: string
+  blockId: string
+  /** Text of the block around the match, with matched terms highlighted. */
+  snippet: string
+  /** bm25 score of the match, lower is better. */
+  rank: number
+}
+
+/**
+ * Cause of a failed database operation, set as the `code` of the error a
+ * call rejects with. Errors that did not come from sqlite are `UNKNOWN`.
+ */
+export declare enum SqliteErrorCode {
+  /** Another connection holds the lock for longer than the busy timeout. */
+  Busy = 'BUSY',
+  /** A transaction of the same connection holds the lock. */
+  Locked = 'LOCKED',
+  /** The file or the connection is read-only. */
+  Readonly = 'READONLY',
+  Io = 'IO',
+  Corrupt = 'CORRUPT',
+  /** The disk is full. */
+  Full = 'FULL',
+  Constraint = 'CONSTRAINT',
+  /**
+   * The file is not a database, or it is encrypted with another key or
+   * without the key being given.
+   */
+  NotADatabase = 'NOT_A_DATABASE',
+  Unknown = 'UNKNOWN'
+}
+
+export interface StorageStats {
+  pageSize: number
+  pageCount: number
+  /** Unused pages, `vacuum` gives them back to the filesystem. */
+  freelistCount: number
+  /** Size of the database in bytes, without the WAL file. */
+  size: number
+  /** Tables and indexes ordered by size, largest first. */
+  tables: Array<TableSize>
+  largestDocs: Array<DocSize>
+  largestBlobs: Array<BlobSize>
+}
+
+export interface TableRowCount {
+  table: string
+  count: number
+}
+
+export interface TableSize {
+  /** Name of a table or index. */
+  name: string
+  size: number
+}
+
 export interface UpdateRow {
   id: number
   timestamp: Date
@@ -52,6 +737,90 @@ export interface UpdateRow {
   docId?: string
 }
 
+export declare enum SynchronousMode {
+  Off = 0,
+  Normal = 1,
+  Full = 2,
+  Extra = 3
+}
+
+export interface UpdatesPage {
+  updates: Array<UpdateRow>
+  /**
+   * id of the last row in this page, pass it as `after_id` to fetch the next
+   * page. `None` if there are no more rows.
+   */
+  nextCursor?: number
+}
+
+export interface UpgradeResult {
+  /** Result of `validate` before the upgrade. */
+  previous: ValidationResult
+  /**
+   * Copy of the original file, kept after a successful upgrade. `None` if
+   * the file was already up to date.
+   */
+  backupPath?: string
+  /** Migrations applied by the upgrade. */
+  migrations: Array<MigrationRecord>
+}
+
+/**
+ * Progress of a full vacuum. SQLite does not tell how much of the file is
+ * left, only that it is still working.
+ */
+export interface VacuumActivity {
+  /** Grows while the file is rewritten. */
+  steps: number
+  /** Milliseconds since the vacuum started. */
+  elapsed: number
+}
+
+export interface VacuumOptions {
+  /**
+   * Switch the file to incremental auto vacuum, so `incremental_vacuum` can
+   * free pages later without rewriting the whole file. Keeps the current
+   * mode if not set.
+   */
+  incremental?: boolean
+}
+
+export interface VacuumProgress {
+  /** Free pages left. */
+  remaining: number
+  /** Free pages when the vacuum started. */
+  total: number
+}
+
+export interface VacuumResult {
+  beforeSize: number
+  afterSize: number
+  /** Bytes given back to the filesystem. */
+  reclaimed: number
+}
+
+export interface ValidationReport {
+  result: ValidationResult
+  /** Latest version in `version_info`, `None` if the file is not versioned. */
+  schemaVersion?: number
+  missingTables: Array<string>
+  /** Missing columns of existing tables, in `table.column` form. */
+  missingColumns: Array<string>
+  rowCounts: Array<TableRowCount>
+  /** Size of the file in bytes. */
+  fileSize?: number
+  /** Whether the file starts with the sqlite header. */
+  isSqlite: boolean
+  /** Version of the sqlite library reading the file. */
+  sqliteVersion?: string
+  pageSize?: number
+  pageCount?: number
+  journalMode?: string
+  encoding?: string
+  /** Why the file could not be read, set if `result` is `GeneralError`. */
+  error?: string
+}
+
 export declare enum ValidationResult {
   MissingTables = 0,
   MissingDocIdColumn = 1,
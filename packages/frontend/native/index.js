@@ -361,7 +361,12 @@ if (!nativeBinding) {
   throw new Error(`Failed to load native binding`)
 }
 
+module.exports.ConnectionManager = nativeBinding.ConnectionManager
 module.exports.SqliteConnection = nativeBinding.SqliteConnection
+module.exports.SqliteTransaction = nativeBinding.SqliteTransaction
+module.exports.JournalMode = nativeBinding.JournalMode
 module.exports.mintChallengeResponse = nativeBinding.mintChallengeResponse
+module.exports.SqliteErrorCode = nativeBinding.SqliteErrorCode
+module.exports.SynchronousMode = nativeBinding.SynchronousMode
 module.exports.ValidationResult = nativeBinding.ValidationResult
 module.exports.verifyChallengeResponse = nativeBinding.verifyChallengeResponse
@@ -1,29 +1,87 @@
 // TODO
 // dynamic create it from JavaScript side
 // and remove this crate then.
+// Only used to check queries at compile time, the runtime schema is created by
+// the migrations in `affine_native::sqlite`, keep them in sync.
 pub const SCHEMA: &str = r#"CREATE TABLE IF NOT EXISTS "updates" (
   id INTEGER PRIMARY KEY AUTOINCREMENT,
   data BLOB NOT NULL,
   timestamp TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
-  doc_id TEXT
+  doc_id TEXT,
+  merged_from TIMESTAMP
 );
 CREATE TABLE IF NOT EXISTS "blobs" (
   key TEXT PRIMARY KEY NOT NULL,
   data BLOB NOT NULL,
-  timestamp TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL
+  timestamp TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+  size INTEGER,
+  mime TEXT
 );
 CREATE TABLE IF NOT EXISTS "version_info" (
   version NUMBER NOT NULL,
   timestamp TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL
 );
-CREATE TABLE IF NOT EXISTS "server_clock" (
-  key TEXT PRIMARY KEY NOT NULL,
+CREATE TABLE IF NOT EXISTS "blob_uploads" (
+  upload_id TEXT NOT NULL,
+  seq INTEGER NOT NULL,
   data BLOB NOT NULL,
-  timestamp TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL
+  timestamp TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+  PRIMARY KEY (upload_id, seq)
 );
-CREATE TABLE IF NOT EXISTS "sync_metadata" (
-  key TEXT PRIMARY KEY NOT NULL,
+CREATE TABLE IF NOT EXISTS "quarantine" (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  source TEXT NOT NULL,
+  reason TEXT NOT NULL,
+  row_id INTEGER,
+  key TEXT,
+  doc_id TEXT,
   data BLOB NOT NULL,
+  timestamp TIMESTAMP NOT NULL,
+  quarantined_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL
+);
+CREATE TABLE IF NOT EXISTS "encryption_info" (
+  id INTEGER PRIMARY KEY CHECK (id = 1),
+  key_check BLOB NOT NULL,
   timestamp TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL
+);
+CREATE TABLE IF NOT EXISTS "doc_tombstones" (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  doc_id TEXT NOT NULL UNIQUE,
+  deleted_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+  purged_at TIMESTAMP,
+  restored_at TIMESTAMP
+);
+CREATE TABLE IF NOT EXISTS "search_blocks" (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  doc_id TEXT NOT NULL,
+  block_id TEXT NOT NULL,
+  content TEXT NOT NULL
+);
+CREATE VIRTUAL TABLE IF NOT EXISTS "search_index" USING fts5(
+  content,
+  content = 'search_blocks',
+  content_rowid = 'id',
+  tokenize = 'unicode61 remove_diacritics 2'
+);
+CREATE TABLE IF NOT EXISTS "snapshots" (
+  doc_id TEXT UNIQUE,
+  data BLOB NOT NULL,
+  state_vector BLOB NOT NULL,
+  last_update_id INTEGER NOT NULL,
+  updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL
+);
+CREATE TABLE IF NOT EXISTS "kv" (
+  namespace TEXT NOT NULL,
+  key TEXT NOT NULL,
+  data BLOB NOT NULL,
+  timestamp TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+  PRIMARY KEY (namespace, key)
+);
+CREATE TABLE IF NOT EXISTS "change_log" (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  source TEXT NOT NULL,
+  doc_id TEXT,
+  key TEXT,
+  row_id INTEGER
 )
 "#;
@@ -0,0 +1,274 @@
+use std::{os::raw::c_int, ptr::NonNull, time::Duration};
+
+use libsqlite3_sys as ffi;
+use napi::threadsafe_function::{ThreadsafeFunction, ThreadsafeFunctionCallMode};
+use napi_derive::napi;
+use sqlx::{sqlite::SqliteConnectOptions, ConnectOptions, Connection};
+
+use super::{
+  error::{database_error, Result},
+  raw::sqlite_error,
+  stats::{report_activity, VacuumActivity},
+  SqliteConnection,
+};
+
+/// Pages copied by each step of an online backup.
+const BACKUP_PAGES_PER_STEP: c_int = 1024;
+
+/// How long to wait before retrying a step if the workspace is locked.
+const BACKUP_BUSY_DELAY: Duration = Duration::from_millis(50);
+
+/// Steps retried in a row while the workspace is locked before giving up.
+const BACKUP_MAX_BUSY_RETRIES: u32 = 200;
+
+/// Times a backup may start over because another connection wrote to the
+/// workspace before giving up.
+const BACKUP_MAX_RESTARTS: u32 = 10;
+
+#[napi(object)]
+pub struct BackupProgress {
+  /// Pages left to copy.
+  pub remaining: i32,
+  /// Pages of the workspace.
+  pub total: i32,
+}
+
+enum BackupStep {
+  Done,
+  More,
+  Busy,
+}
+
+/// A sqlite online backup from the workspace into another database.
+struct Backup {
+  dest: NonNull<ffi::sqlite3>,
+  handle: NonNull<ffi::sqlite3_backup>,
+}
+
+// SAFETY: the handle is only stepped while both connections are locked, and
+// the connections outlive it.
+unsafe impl Send for Backup {}
+
+impl Backup {
+  fn init(dest: NonNull<ffi::sqlite3>, source: NonNull<ffi::sqlite3>) -> anyhow::Result<Self> {
+    // SAFETY: both connections are valid and locked by the caller.
+    let handle = unsafe {
+      ffi::sqlite3_backup_init(
+        dest.as_ptr(),
+        c"main".as_ptr(),
+        source.as_ptr(),
+        c"main".as_ptr(),
+      )
+    };
+    match NonNull::new(handle) {
+      Some(handle) => Ok(Self { dest, handle }),
+      // SAFETY: `dest` is valid, errors of `sqlite3_backup_init` are stored on
+      // the destination connection.
+      None => Err(sqlite_error(dest, unsafe {
+        ffi::sqlite3_errcode(dest.as_ptr())
+      })),
+    }
+  }
+
+  fn step(&self, pages: c_int) -> anyhow::Result<BackupStep> {
+    // SAFETY: the handle is valid until dropped.
+    match unsafe { ffi::sqlite3_backup_step(self.handle.as_ptr(), pages) } {
+      ffi::SQLITE_DONE => Ok(BackupStep::Done),
+      ffi::SQLITE_OK => Ok(BackupStep::More),
+      ffi::SQLITE_BUSY | ffi::SQLITE_LOCKED => Ok(BackupStep::Busy),
+      code => Err(sqlite_error(self.dest, code)),
+    }
+  }
+
+  fn progress(&self) -> BackupProgress {
+    // SAFETY: the handle is valid until dropped.
+    unsafe {
+      BackupProgress {
+        remaining: ffi::sqlite3_backup_remaining(self.handle.as_ptr()),
+        total: ffi::sqlite3_backup_pagecount(self.handle.as_ptr()),
+      }
+    }
+  }
+}
+
+impl Drop for Backup {
+  fn drop(&mut self) {
+    // SAFETY: the handle was created by `sqlite3_backup_init` and is finished
+    // once, step errors are already reported by `step`.
+    unsafe {
+      ffi::sqlite3_backup_finish(self.handle.as_ptr());
+    }
+  }
+}
+
+#[napi]
+impl SqliteConnection {
+  /// Copy a transactionally consistent snapshot of the workspace to `path`
+  /// with the sqlite online backup API, while the connection stays usable.
+  /// `progress` is called after every step. The backup starts over whenever
+  /// another connection writes to the workspace, and fails if that happens
+  /// more than 10 times, retry when the workspace is idle.
+  #[napi]
+  pub async fn backup_to(
+    &self,
+    path: String,
+    progress: Option<ThreadsafeFunction<BackupProgress>>,
+  ) -> Result<()> {
+    self
+      .backup_with(path, BACKUP_PAGES_PER_STEP, move |step| {
+        if let Some(progress) = &progress {
+          progress.call(Ok(step), ThreadsafeFunctionCallMode::NonBlocking);
+        }
+      })
+      .await
+  }
+
+  /// Write a compacted copy of the workspace without free pages to `path`
+  /// with `VACUUM INTO`. `progress` is called every 100ms at most while the
+  /// copy is written, see `vacuum`.
+  #[napi]
+  pub async fn vacuum_into(
+    &self,
+    path: String,
+    progress: Option<ThreadsafeFunction<VacuumActivity>>,
+  ) -> Result<()> {
+    self
+      .vacuum_into_with(
+        path,
+        progress.map(|progress| {
+          move |activity: VacuumActivity| {
+            progress.call(Ok(activity), ThreadsafeFunctionCallMode::NonBlocking);
+          }
+        }),
+      )
+      .await
+  }
+}
+
+impl SqliteConnection {
+  /// `backup_to` copying `pages_per_step` pages at a time.
+  pub(crate) async fn backup_with(
+    &self,
+    path: String,
+    pages_per_step: c_int,
+    progress: impl FnMut(BackupProgress),
+  ) -> Result<()> {
+    if path == self.path {
+      return Err(database_error(anyhow::anyhow!(
+        "Can not backup a workspace into itself"
+      )));
+    }
+    // write into a temporary file first, so `path` never holds a partial copy
+    let temp_path = format!("{path}.tmp");
+    if let Err(err) = self.backup_into(&temp_path, pages_per_step, progress).await {
+      tokio::fs::remove_file(&temp_path).await.ok();
+      return Err(database_error(err));
+    }
+    tokio::fs::rename(&temp_path, &path)
+      .await
+      .map_err(database_error)?;
+    Ok(())
+  }
+
+  pub(crate) async fn vacuum_into_with(
+    &self,
+    path: String,
+    progress: Option<impl FnMut(VacuumActivity) + Send + 'static>,
+  ) -> Result<()> {
+    if path == self.path {
+      return Err(database_error(anyhow::anyhow!(
+        "Can not vacuum a workspace into itself"
+      )));
+    }
+    let temp_path = format!("{path}.tmp");
+    // `VACUUM INTO` refuses to overwrite an existing file
+    tokio::fs::remove_file(&temp_path).await.ok();
+    let mut connection = self.pool.acquire().await.map_err(database_error)?;
+    if let Some(progress) = progress {
+      report_activity(&mut connection, progress)
+        .await
+        .map_err(database_error)?;
+    }
+    let vacuumed = sqlx::query("VACUUM INTO ?")
+      .bind(&temp_path)
+      .execute(&mut *connection)
+      .await;
+    // the connection goes back to the pool, other queries must not report
+    connection
+      .lock_handle()
+      .await
+      .map_err(database_error)?
+      .remove_progress_handler();
+    if let Err(err) = vacuumed {
+      tokio::fs::remove_file(&temp_path).await.ok();
+      return Err(database_error(err));
+    }
+    tokio::fs::rename(&temp_path, &path)
+      .await
+      .map_err(database_error)?;
+    Ok(())
+  }
+
+  async fn backup_into(
+    &self,
+    path: &str,
+    pages_per_step: c_int,
+    mut progress: impl FnMut(BackupProgress),
+  ) -> anyhow::Result<()> {
+    let mut dest = SqliteConnectOptions::new()
+      .filename(path)
+      .create_if_missing(true)
+      .connect()
+      .await?;
+    let mut source = self.pool.acquire().await?;
+
+    let backup = {
+      let mut source_handle = source.lock_handle().await?;
+      let mut dest_handle = dest.lock_handle().await?;
+      Backup::init(dest_handle.as_raw_handle(), source_handle.as_raw_handle())?
+    };
+
+    let mut remaining = None;
+    let mut restarts = 0;
+    let mut busy_retries = 0;
+    loop {
+      let step = {
+        let _source_handle = source.lock_handle().await?;
+        let _dest_handle = dest.lock_handle().await?;
+        backup.step(pages_per_step)?
+      };
+      let current = backup.progress();
+      // every step copies pages unless the backup started over
+      if matches!(step, BackupStep::More)
+        && remaining.is_some_and(|remaining| current.remaining >= remaining)
+      {
+        restarts += 1;
+        if restarts > BACKUP_MAX_RESTARTS {
+          anyhow::bail!(
+            "The workspace changed more than {BACKUP_MAX_RESTARTS} times during the backup, retry when it is idle"
+          );
+        }
+      }
+      remaining = Some(current.remaining);
+      progress(current);
+      match step {
+        BackupStep::Done => break,
+        BackupStep::More => {
+          busy_retries = 0;
+          tokio::task::yield_now().await;
+        }
+        BackupStep::Busy => {
+          busy_retries += 1;
+          if busy_retries > BACKUP_MAX_BUSY_RETRIES {
+            anyhow::bail!("The workspace stayed locked during the backup, retry when it is idle");
+          }
+          tokio::time::sleep(BACKUP_BUSY_DELAY).await;
+        }
+      }
+    }
+
+    drop(backup);
+    dest.close().await?;
+    Ok(())
+  }
+}
@@ -0,0 +1,645 @@
+use std::{
+  os::raw::c_int,
+  ptr::{self, NonNull},
+};
+
+use chrono::NaiveDateTime;
+use file_format::FileFormat;
+use libsqlite3_sys as ffi;
+use napi::bindgen_prelude::{Buffer, Uint8Array};
+use napi_derive::napi;
+use tokio::{
+  fs::File,
+  io::{AsyncReadExt, AsyncWriteExt},
+};
+
+use super::{
+  encryption::{Cipher, RowIdentity},
+  error::{database_error, Result},
+  immediate::ImmediateTransaction,
+  kv::prefix_end,
+  raw::sqlite_error,
+  ChangeEvent, SqliteConnection,
+};
+
+/// Bytes read from the head of a blob to detect its mime type.
+const MIME_SNIFF_LENGTH: i64 = 4096;
+
+/// Size of the chunks used to copy blobs from and to the filesystem.
+const COPY_CHUNK_SIZE: usize = 1024 * 1024;
+
+#[napi(object)]
+pub struct BlobMeta {
+  pub key: String,
+  pub size: i64,
+  pub mime: String,
+  pub timestamp: NaiveDateTime,
+}
+
+#[napi(object)]
+#[derive(Default)]
+pub struct ListBlobsOptions {
+  /// Only list blobs whose key starts with `prefix`.
+  pub prefix: Option<String>,
+  /// Only list blobs whose key sorts after `after_key`.
+  pub after_key: Option<String>,
+  pub limit: Option<u32>,
+}
+
+#[napi(object)]
+pub struct BlobsPage {
+  pub blobs: Vec<BlobMeta>,
+  /// key of the last blob in this page, pass it as `after_key` to fetch the
+  /// next page. `None` if there are no more blobs.
+  pub next_cursor: Option<String>,
+}
+
+/// A handle of sqlite incremental blob I/O on `blobs.data`, reads and writes
+/// part of a blob without loading the whole value into memory.
+struct IncrementalBlob {
+  db: NonNull<ffi::sqlite3>,
+  handle: NonNull<ffi::sqlite3_blob>,
+}
+
+impl IncrementalBlob {
+  fn open(db: NonNull<ffi::sqlite3>, rowid: i64, writable: bool) -> anyhow::Result<Self> {
+    let mut handle = ptr::null_mut();
+    // SAFETY: `db` is a valid connection locked by the caller for the whole
+    // lifetime of the returned handle.
+    let code = unsafe {
+      ffi::sqlite3_blob_open(
+        db.as_ptr(),
+        c"main".as_ptr(),
+        c"blobs".as_ptr(),
+        c"data".as_ptr(),
+        rowid,
+        writable as c_int,
+        &mut handle,
+      )
+    };
+    match NonNull::new(handle) {
+      Some(handle) if code == ffi::SQLITE_OK => Ok(Self { db, handle }),
+      _ => Err(sqlite_error(db, code)),
+    }
+  }
+
+  fn read(&self, buffer: &mut [u8], offset: usize) -> anyhow::Result<()> {
+    // SAFETY: `buffer` is valid for `buffer.len()` bytes, sqlite checks the
+    // range against the blob size.
+    let code = unsafe {
+      ffi::sqlite3_blob_read(
+        self.handle.as_ptr(),
+        buffer.as_mut_ptr().cast(),
+        buffer.len() as c_int,
+        offset as c_int,
+      )
+    };
+    if code == ffi::SQLITE_OK {
+      Ok(())
+    } else {
+      Err(sqlite_error(self.db, code))
+    }
+  }
+
+  fn write(&self, data: &[u8], offset: usize) -> anyhow::Result<()> {
+    // SAFETY: `data` is valid for `data.len()` bytes, sqlite checks the range
+    // against the blob size.
+    let code = unsafe {
+      ffi::sqlite3_blob_write(
+        self.handle.as_ptr(),
+        data.as_ptr().cast(),
+        data.len() as c_int,
+        offset as c_int,
+      )
+    };
+    if code == ffi::SQLITE_OK {
+      Ok(())
+    } else {
+      Err(sqlite_error(self.db, code))
+    }
+  }
+}
+
+impl Drop for IncrementalBlob {
+  fn drop(&mut self) {
+    // SAFETY: the handle was opened by `sqlite3_blob_open` and is closed once.
+    unsafe {
+      ffi::sqlite3_blob_close(self.handle.as_ptr());
+    }
+  }
+}
+
+/// Open the blob at `rowid` on `connection` and run `f` with it. The
+/// connection is locked until `f` returns.
+async fn with_blob<T>(
+  connection: &mut sqlx::SqliteConnection,
+  rowid: i64,
+  writable: bool,
+  f: impl FnOnce(&IncrementalBlob) -> anyhow::Result<T>,
+) -> anyhow::Result<T> {
+  let mut handle = connection.lock_handle().await?;
+  let blob = IncrementalBlob::open(handle.as_raw_handle(), rowid, writable)?;
+  f(&blob)
+}
+
+/// Mime type of a blob detected from its first bytes.
+pub(crate) fn blob_mime(data: &[u8]) -> String {
+  let head = &data[..data.len().min(MIME_SNIFF_LENGTH as usize)];
+  FileFormat::from_bytes(head).media_type().to_string()
+}
+
+/// Read and decrypt the whole blob `key`, encrypted payloads can not be read
+/// in parts.
+async fn read_sealed_blob(
+  connection: &mut sqlx::SqliteConnection,
+  cipher: &Cipher,
+  key: &str,
+) -> anyhow::Result<Option<Vec<u8>>> {
+  let Some(row) = sqlx::query!("SELECT data FROM blobs WHERE key = ?", key)
+    .fetch_optional(&mut *connection)
+    .await?
+  else {
+    return Ok(None);
+  };
+  Ok(Some(
+    cipher
+      .open(&row.data, &RowIdentity::blob(key))?
+      .into_owned(),
+  ))
+}
+
+/// Write the whole blob `key` along with its size and mime type, encrypted if
+/// the workspace is.
+pub(crate) async fn write_blob(
+  connection: &mut sqlx::SqliteConnection,
+  cipher: &Cipher,
+  key: &str,
+  data: &[u8],
+) -> anyhow::Result<()> {
+  let size = data.len() as i64;
+  let mime = blob_mime(data);
+  let data = cipher.seal(data, &RowIdentity::blob(key))?;
+  let data = data.as_ref();
+  sqlx::query!(
+    "INSERT INTO blobs (key, data, size, mime) VALUES ($1, $2, $3, $4) ON CONFLICT(key) DO UPDATE SET data = excluded.data, size = excluded.size, mime = excluded.mime",
+    key,
+    data,
+    size,
+    mime,
+  )
+  .execute(&mut *connection)
+  .await?;
+  Ok(())
+}
+
+/// Keep the first bytes of a blob copied in chunks to detect its mime type.
+fn extend_head(head: &mut Vec<u8>, chunk: &[u8]) {
+  let missing = (MIME_SNIFF_LENGTH as usize).saturating_sub(head.len());
+  head.extend_from_slice(&chunk[..chunk.len().min(missing)]);
+}
+
+async fn set_blob_mime(
+  connection: &mut sqlx::SqliteConnection,
+  rowid: i64,
+  head: &[u8],
+) -> anyhow::Result<()> {
+  let mime = blob_mime(head);
+  sqlx::query!("UPDATE blobs SET mime = ? WHERE rowid = ?", mime, rowid)
+    .execute(&mut *connection)
+    .await?;
+  Ok(())
+}
+
+/// Store the size and mime type of blobs written before they were stored,
+/// unless the blob was replaced in the meantime.
+async fn store_sniffed(
+  pool: &sqlx::SqlitePool,
+  sniffed: &[(String, i64, String)],
+) -> anyhow::Result<()> {
+  let mut transaction = pool.begin().await?;
+  for (key, size, mime) in sniffed {
+    sqlx::query!(
+      "UPDATE blobs SET size = ?, mime = ? WHERE key = ? AND mime IS NULL",
+      size,
+      mime,
+      key
+    )
+    .execute(&mut *transaction)
+    .await?;
+  }
+  transaction.commit().await?;
+  Ok(())
+}
+
+#[napi]
+impl SqliteConnection {
+  /// List blobs ordered by key without loading their data. Blobs written
+  /// before their size and mime type were stored are sniffed instead, which
+  /// reads the whole blob in encrypted workspaces, and the result is stored
+  /// so the next listing does not sniff them again.
+  #[napi]
+  pub async fn list_blobs(&self, options: Option<ListBlobsOptions>) -> Result<BlobsPage> {
+    let cipher = self.cipher.read().await;
+    let ListBlobsOptions {
+      prefix,
+      after_key,
+      limit,
+    } = options.unwrap_or_default();
+    let prefix = prefix.unwrap_or_default();
+    let end = prefix_end(&prefix);
+    // negative limit means no limit in sqlite
+    let limit_value = limit.map(|limit| limit as i64).unwrap_or(-1);
+    // encrypted blobs can only be decrypted as a whole, sqlite blobs never
+    // exceed `i32::MAX` bytes
+    let sniff_length = if cipher.is_enabled() {
+      i32::MAX as i64
+    } else {
+      MIME_SNIFF_LENGTH
+    };
+
+    let rows = sqlx::query!(
+      r#"SELECT key, length(data) AS "stored_size!: i64", size, mime, CASE WHEN mime IS NULL THEN substr(data, 1, $1) END AS "head: Vec<u8>", timestamp
+      FROM blobs
+      WHERE ($2 IS NULL OR key > $2) AND key >= $3 AND ($4 IS NULL OR key < $4)
+      ORDER BY key
+      LIMIT $5"#,
+      sniff_length,
+      after_key,
+      prefix,
+      end,
+      limit_value
+    )
+    .fetch_all(&self.pool)
+    .await
+    .map_err(database_error)?;
+
+    let mut sniffed = Vec::new();
+    let blobs = rows
+      .into_iter()
+      .map(|row| {
+        let size = row
+          .size
+          .unwrap_or_else(|| cipher.plaintext_size(row.stored_size));
+        let mime = match row.mime {
+          Some(mime) => mime,
+          None => {
+            let head = row.head.unwrap_or_default();
+            let mime = blob_mime(&cipher.open(&head, &RowIdentity::blob(&row.key))?);
+            sniffed.push((row.key.clone(), size, mime.clone()));
+            mime
+          }
+        };
+        Ok(BlobMeta {
+          size,
+          key: row.key,
+          mime,
+          timestamp: row.timestamp,
+        })
+      })
+      .collect::<anyhow::Result<Vec<_>>>()
+      .map_err(database_error)?;
+    if !sniffed.is_empty() && !self.read_only {
+      // the listing does not depend on it, a busy workspace sniffs them again
+      // next time
+      store_sniffed(&self.pool, &sniffed).await.ok();
+    }
+
+    let next_cursor = match limit {
+      Some(limit) if blobs.len() as u32 >= limit => blobs.last().map(|blob| blob.key.clone()),
+      _ => None,
+    };
+    Ok(BlobsPage { blobs, next_cursor })
+  }
+
+  #[napi]
+  pub async fn get_blob_size(&self, key: String) -> Result<Option<i64>> {
+    let cipher = self.cipher.read().await;
+    let size = sqlx::query!(
+      r#"SELECT length(data) AS "stored_size!: i64", size FROM blobs WHERE key = ?"#,
+      key
+    )
+    .fetch_optional(&self.pool)
+    .await
+    .map_err(database_error)?
+    .map(|row| {
+      row
+        .size
+        .unwrap_or_else(|| cipher.plaintext_size(row.stored_size))
+    });
+    Ok(size)
+  }
+
+  /// Read at most `length` bytes of a blob starting at `offset`, like a HTTP
+  /// Range request. Returns `None` if the blob does not exist.
+  #[napi]
+  pub async fn get_blob_range(
+    &self,
+    key: String,
+    offset: i64,
+    length: i64,
+  ) -> Result<Option<Buffer>> {
+    let cipher = self.cipher.read().await;
+    let mut connection = self.pool.acquire().await.map_err(database_error)?;
+    if cipher.is_enabled() {
+      let Some(data) = read_sealed_blob(&mut connection, &cipher, &key)
+        .await
+        .map_err(database_error)?
+      else {
+        return Ok(None);
+      };
+      let size = data.len() as i64;
+      let start = offset.clamp(0, size);
+      let end = start.saturating_add(length.max(0)).min(size);
+      return Ok(Some(data[start as usize..end as usize].to_vec().into()));
+    }
+
+    let Some(row) = sqlx::query!(
+      r#"SELECT rowid AS "rowid!: i64", length(data) AS "size!: i64" FROM blobs WHERE key = ?"#,
+      key
+    )
+    .fetch_optional(connection.as_mut())
+    .await
+    .map_err(database_error)?
+    else {
+      return Ok(None);
+    };
+
+    let start = offset.clamp(0, row.size);
+    let end = start.saturating_add(length.max(0)).min(row.size);
+    let mut buffer = vec![0; (end - start) as usize];
+    if !buffer.is_empty() {
+      with_blob(&mut connection, row.rowid, false, |blob| {
+        blob.read(&mut buffer, start as usize)
+      })
+      .await
+      .map_err(database_error)?;
+    }
+    Ok(Some(buffer.into()))
+  }
+
+  /// Start a chunked upload, chunks are staged and only become visible as a
+  /// blob once `commit_blob_upload` is called.
+  #[napi]
+  pub fn begin_blob_upload(&self) -> String {
+    uuid::Uuid::new_v4().to_string()
+  }
+
+  #[napi]
+  pub async fn append_blob_chunk(&self, upload_id: String, chunk: Uint8Array) -> Result<()> {
+    let cipher = self.cipher.read().await;
+    let chunk = cipher
+      .seal(chunk.as_ref(), &RowIdentity::blob_upload(&upload_id))
+      .map_err(database_error)?;
+    let chunk = chunk.as_ref();
+    // the next `seq` is read and written under the write lock, so concurrent
+    // appends can not pick the same one
+    let mut transaction = ImmediateTransaction::begin(&self.pool)
+      .await
+      .map_err(database_error)?;
+    sqlx::query!(
+      "INSERT INTO blob_uploads (upload_id, seq, data) VALUES ($1, (SELECT COALESCE(MAX(seq) + 1, 0) FROM blob_uploads WHERE upload_id = $1), $2)",
+      upload_id,
+      chunk
+    )
+    .execute(&mut *transaction)
+    .await
+    .map_err(database_error)?;
+    transaction.commit().await.map_err(database_error)?;
+    Ok(())
+  }
+
+  /// Write every staged chunk of the upload into `key` in a single
+  /// transaction, the chunks are copied one by one.
+  #[napi]
+  pub async fn commit_blob_upload(&self, upload_id: String, key: String) -> Result<()> {
+    let cipher = self.cipher.read().await;
+    let mut transaction = ImmediateTransaction::begin(&self.pool)
+      .await
+      .map_err(database_error)?;
+    let chunks = sqlx::query!(
+      r#"SELECT seq, length(data) AS "size!: i64" FROM blob_uploads WHERE upload_id = ? ORDER BY seq"#,
+      upload_id
+    )
+    .fetch_all(&mut *transaction)
+    .await
+    .map_err(database_error)?;
+    if chunks.is_empty() {
+      return Err(database_error(anyhow::anyhow!(
+        "Blob upload {upload_id} not found"
+      )));
+    }
+
+    if cipher.is_enabled() {
+      // every chunk is sealed on its own, the blob has to be sealed as a whole
+      let mut data = Vec::new();
+      for chunk in chunks {
+        let chunk = sqlx::query!(
+          "SELECT data FROM blob_uploads WHERE upload_id = ? AND seq = ?",
+          upload_id,
+          chunk.seq
+        )
+        .fetch_one(&mut *transaction)
+        .await
+        .map_err(database_error)?
+        .data;
+        let chunk = cipher
+          .open(&chunk, &RowIdentity::blob_upload(&upload_id))
+          .map_err(database_error)?;
+        data.extend_from_slice(&chunk);
+      }
+      write_blob(&mut transaction, &cipher, &key, &data)
+        .await
+        .map_err(database_error)?;
+    } else {
+      let size: i64 = chunks.iter().map(|chunk| chunk.size).sum();
+      let rowid = sqlx::query!(
+        r#"INSERT INTO blobs (key, data, size, mime) VALUES ($1, zeroblob($2), $2, NULL) ON CONFLICT(key) DO UPDATE SET data = excluded.data, size = excluded.size, mime = NULL RETURNING rowid AS "rowid!: i64""#,
+        key,
+        size
+      )
+      .fetch_one(&mut *transaction)
+      .await
+      .map_err(database_error)?
+      .rowid;
+
+      let mut offset = 0;
+      let mut head = Vec::new();
+      for chunk in chunks {
+        let data = sqlx::query!(
+          "SELECT data FROM blob_uploads WHERE upload_id = ? AND seq = ?",
+          upload_id,
+          chunk.seq
+        )
+        .fetch_one(&mut *transaction)
+        .await
+        .map_err(database_error)?
+        .data;
+        with_blob(&mut transaction, rowid, true, |blob| {
+          blob.write(&data, offset)
+        })
+        .await
+        .map_err(database_error)?;
+        extend_head(&mut head, &data);
+        offset += data.len();
+      }
+      set_blob_mime(&mut transaction, rowid, &head)
+        .await
+        .map_err(database_error)?;
+    }
+
+    sqlx::query!("DELETE FROM blob_uploads WHERE upload_id = ?", upload_id)
+      .execute(&mut *transaction)
+      .await
+      .map_err(database_error)?;
+    transaction.commit().await.map_err(database_error)?;
+    self.notify(vec![ChangeEvent::keyed("blobs", Some(key))]);
+    Ok(())
+  }
+
+  #[napi]
+  pub async fn abort_blob_upload(&self, upload_id: String) -> Result<()> {
+    sqlx::query!("DELETE FROM blob_uploads WHERE upload_id = ?", upload_id)
+      .execute(&self.pool)
+      .await
+      .map_err(database_error)?;
+    Ok(())
+  }
+
+  /// Copy a file into the blob `key` chunk by chunk, without going through
+  /// JavaScript. Encrypted workspaces read the whole file at once.
+  #[napi]
+  pub async fn import_blob(&self, key: String, path: String) -> Result<()> {
+    let cipher = self.cipher.read().await;
+    if cipher.is_enabled() {
+      let data = tokio::fs::read(&path).await.map_err(database_error)?;
+      let mut connection = self.pool.acquire().await.map_err(database_error)?;
+      write_blob(&mut connection, &cipher, &key, &data)
+        .await
+        .map_err(database_error)?;
+      self.notify(vec![ChangeEvent::keyed("blobs", Some(key))]);
+      return Ok(());
+    }
+
+    let mut file = File::open(&path).await.map_err(database_error)?;
+    let size = file.metadata().await.map_err(database_error)?.len() as i64;
+
+    let mut transaction = self.pool.begin().await.map_err(database_error)?;
+    let rowid = sqlx::query!(
+      r#"INSERT INTO blobs (key, data, size, mime) VALUES ($1, zeroblob($2), $2, NULL) ON CONFLICT(key) DO UPDATE SET data = excluded.data, size = excluded.size, mime = NULL RETURNING rowid AS "rowid!: i64""#,
+      key,
+      size
+    )
+    .fetch_one(&mut *transaction)
+    .await
+    .map_err(database_error)?
+    .rowid;
+
+    let mut buffer = vec![0; COPY_CHUNK_SIZE];
+    let mut offset = 0;
+    let mut head = Vec::new();
+    loop {
+      let read = file.read(&mut buffer).await.map_err(database_error)?;
+      if read == 0 {
+        break;
+      }
+      if (offset + read) as i64 > size {
+        return Err(database_error(anyhow::anyhow!(
+          "{path} changed while importing"
+        )));
+      }
+      with_blob(&mut transaction, rowid, true, |blob| {
+        blob.write(&buffer[..read], offset)
+      })
+      .await
+      .map_err(database_error)?;
+      extend_head(&mut head, &buffer[..read]);
+      offset += read;
+    }
+    if offset as i64 != size {
+      return Err(database_error(anyhow::anyhow!(
+        "{path} changed while importing"
+      )));
+    }
+    set_blob_mime(&mut transaction, rowid, &head)
+      .await
+      .map_err(database_error)?;
+
+    transaction.commit().await.map_err(database_error)?;
+    self.notify(vec![ChangeEvent::keyed("blobs", Some(key))]);
+    Ok(())
+  }
+
+  /// Copy the blob `key` into a file chunk by chunk. Returns `false` if the
+  /// blob does not exist. Encrypted workspaces decrypt the whole blob at once.
+  /// The file is written to `<path>.tmp` and renamed once complete, a failed
+  /// export leaves `path` untouched.
+  #[napi]
+  pub async fn export_blob(&self, key: String, path: String) -> Result<bool> {
+    let tmp_path = format!("{path}.tmp");
+    match self.write_blob_file(&key, &tmp_path).await {
+      Ok(true) => {
+        tokio::fs::rename(&tmp_path, &path)
+          .await
+          .map_err(database_error)?;
+        Ok(true)
+      }
+      Ok(false) => Ok(false),
+      Err(err) => {
+        tokio::fs::remove_file(&tmp_path).await.ok();
+        Err(err)
+      }
+    }
+  }
+
+  async fn write_blob_file(&self, key: &str, path: &str) -> Result<bool> {
+    let cipher = self.cipher.read().await;
+    if cipher.is_enabled() {
+      let mut connection = self.pool.acquire().await.map_err(database_error)?;
+      let Some(data) = read_sealed_blob(&mut connection, &cipher, key)
+        .await
+        .map_err(database_error)?
+      else {
+        return Ok(false);
+      };
+      let mut file = File::create(&path).await.map_err(database_error)?;
+      file.write_all(&data).await.map_err(database_error)?;
+      file.sync_all().await.map_err(database_error)?;
+      return Ok(true);
+    }
+
+    // read every chunk from the same snapshot
+    let mut transaction = self.pool.begin().await.map_err(database_error)?;
+    let Some(row) = sqlx::query!(
+      r#"SELECT rowid AS "rowid!: i64", length(data) AS "size!: i64" FROM blobs WHERE key = ?"#,
+      key
+    )
+    .fetch_optional(&mut *transaction)
+    .await
+    .map_err(database_error)?
+    else {
+      return Ok(false);
+    };
+
+    let mut file = File::create(&path).await.map_err(database_error)?;
+    let size = row.size as usize;
+    let mut buffer = vec![0; COPY_CHUNK_SIZE.min(size)];
+    let mut offset = 0;
+    while offset < size {
+      let length = COPY_CHUNK_SIZE.min(size - offset);
+      with_blob(&mut transaction, row.rowid, false, |blob| {
+        blob.read(&mut buffer[..length], offset)
+      })
+      .await
+      .map_err(database_error)?;
+      file
+        .write_all(&buffer[..length])
+        .await
+        .map_err(database_error)?;
+      offset += length;
+    }
+    file.sync_all().await.map_err(database_error)?;
+
+    transaction.commit().await.map_err(database_error)?;
+    Ok(true)
+  }
+}
@@ -0,0 +1,164 @@
+use napi_derive::napi;
+
+use super::{
+  encryption::RowIdentity,
+  error::{database_error, Result},
+  immediate::ImmediateTransaction,
+  ydoc::merge_updates,
+  ChangeEvent, SqliteConnection,
+};
+
+#[napi(object)]
+#[derive(Default)]
+pub struct CompactionOptions {
+  /// Merge the updates into the snapshot of the doc instead of a single
+  /// update, load the doc with `load_doc` afterwards. Defaults to `false`.
+  pub snapshot: Option<bool>,
+}
+
+#[napi(object)]
+pub struct CompactionResult {
+  pub before_count: i64,
+  pub before_size: i64,
+  pub after_count: i64,
+  pub after_size: i64,
+}
+
+#[napi]
+impl SqliteConnection {
+  /// Merge all updates of a doc into a single row in one transaction. The
+  /// transaction takes the write lock up front, so a concurrent writer makes
+  /// it wait instead of failing with `BUSY`.
+  #[napi]
+  pub async fn compact_updates(
+    &self,
+    doc_id: Option<String>,
+    options: Option<CompactionOptions>,
+  ) -> Result<CompactionResult> {
+    if options.unwrap_or_default().snapshot.unwrap_or(false) {
+      return self.write_snapshot(doc_id).await.map_err(database_error);
+    }
+    let cipher = self.cipher.read().await;
+    let mut transaction = ImmediateTransaction::begin(&self.pool)
+      .await
+      .map_err(database_error)?;
+
+    let rows = match &doc_id {
+      Some(doc_id) => sqlx::query!(
+        "SELECT data, timestamp, merged_from FROM updates WHERE doc_id = ? ORDER BY id",
+        doc_id
+      )
+      .fetch_all(&mut *transaction)
+      .await
+      .map(|rows| {
+        rows
+          .into_iter()
+          .map(|row| (row.data, row.timestamp, row.merged_from))
+          .collect::<Vec<_>>()
+      })
+      .map_err(database_error)?,
+      None => sqlx::query!(
+        "SELECT data, timestamp, merged_from FROM updates WHERE doc_id is NULL ORDER BY id"
+      )
+      .fetch_all(&mut *transaction)
+      .await
+      .map(|rows| {
+        rows
+          .into_iter()
+          .map(|row| (row.data, row.timestamp, row.merged_from))
+          .collect::<Vec<_>>()
+      })
+      .map_err(database_error)?,
+    };
+
+    let before_count = rows.len() as i64;
+    let before_size = rows.iter().map(|(data, ..)| data.len() as i64).sum();
+    let identity = RowIdentity::update(doc_id.as_deref());
+    let rows = rows
+      .iter()
+      .map(|(data, timestamp, merged_from)| {
+        Ok((cipher.open(data, &identity)?, *timestamp, *merged_from))
+      })
+      .collect::<anyhow::Result<Vec<_>>>()
+      .map_err(database_error)?;
+
+    // nothing to merge
+    if rows.len() <= 1 {
+      return Ok(CompactionResult {
+        before_count,
+        before_size,
+        after_count: before_count,
+        after_size: before_size,
+      });
+    }
+
+    let merged =
+      merge_updates(rows.iter().map(|(data, ..)| data.as_ref())).map_err(database_error)?;
+    let merged = cipher.seal(&merged, &identity).map_err(database_error)?;
+    let merged = merged.as_ref();
+    // keep the time of the latest merged update
+    let timestamp = rows.last().map(|(_, timestamp, _)| *timestamp);
+    // and the time of the earliest one, `get_doc_at` can not split the row
+    let merged_from = rows
+      .iter()
+      .map(|(_, timestamp, merged_from)| merged_from.unwrap_or(*timestamp))
+      .min();
+
+    match &doc_id {
+      Some(doc_id) => sqlx::query!("DELETE FROM updates WHERE doc_id = ?", doc_id)
+        .execute(&mut *transaction)
+        .await
+        .map_err(database_error)?,
+      None => sqlx::query!("DELETE FROM updates WHERE doc_id is NULL")
+        .execute(&mut *transaction)
+        .await
+        .map_err(database_error)?,
+    };
+
+    let id = sqlx::query!(
+      "INSERT INTO updates (data, doc_id, timestamp, merged_from) VALUES ($1, $2, $3, $4)",
+      merged,
+      doc_id,
+      timestamp,
+      merged_from
+    )
+    .execute(&mut *transaction)
+    .await
+    .map_err(database_error)?
+    .last_insert_rowid();
+
+    transaction.commit().await.map_err(database_error)?;
+    drop(cipher);
+    self.schedule_index(doc_id.clone());
+    self.notify(vec![
+      ChangeEvent::update(doc_id.clone(), None),
+      ChangeEvent::update(doc_id, Some(id)),
+    ]);
+
+    Ok(CompactionResult {
+      before_count,
+      before_size,
+      after_count: 1,
+      after_size: merged.len() as i64,
+    })
+  }
+
+  /// Compact docs automatically once their updates count passes `threshold`
+  /// after `insert_updates`. `None` disables auto compaction.
+  #[napi]
+  pub fn set_compaction_threshold(&self, threshold: Option<u32>) {
+    *self.compaction_threshold.write() = threshold;
+  }
+
+  /// Runs after the inserted updates are committed, so callers must not fail
+  /// the insert for its errors.
+  pub(crate) async fn compact_if_needed(&self, doc_id: Option<String>) -> Result<()> {
+    let Some(threshold) = *self.compaction_threshold.read() else {
+      return Ok(());
+    };
+    if self.get_updates_count(doc_id.clone()).await? > threshold as i64 {
+      self.compact_updates(doc_id, None).await?;
+    }
+    Ok(())
+  }
+}
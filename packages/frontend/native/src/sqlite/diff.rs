@@ -0,0 +1,74 @@
+use napi::bindgen_prelude::{Buffer, Uint8Array};
+use napi_derive::napi;
+use y_octo::StateVector;
+
+use super::{
+  error::{database_error, Result},
+  ydoc::{apply_updates, decode_state_vector, encode_state_vector},
+  SqliteConnection,
+};
+
+#[napi]
+impl SqliteConnection {
+  /// Get the state vector of a doc in form like `Y.encodeStateVector(doc)`.
+  /// The stored state vector of the snapshot is returned as is if there are
+  /// no newer updates.
+  #[napi]
+  pub async fn get_state_vector(&self, doc_id: Option<String>) -> Result<Buffer> {
+    let cipher = self.cipher.read().await;
+    let mut transaction = self.pool.begin().await.map_err(database_error)?;
+    let snapshot = sqlx::query!(
+      "SELECT state_vector, last_update_id FROM snapshots WHERE doc_id IS ?",
+      doc_id
+    )
+    .fetch_optional(&mut *transaction)
+    .await
+    .map_err(database_error)?;
+    if let Some(snapshot) = snapshot {
+      let newer = sqlx::query!(
+        "SELECT id FROM updates WHERE doc_id IS ? AND id > ? LIMIT 1",
+        doc_id,
+        snapshot.last_update_id
+      )
+      .fetch_optional(&mut *transaction)
+      .await
+      .map_err(database_error)?;
+      if newer.is_none() {
+        return Ok(snapshot.state_vector.into());
+      }
+    }
+    transaction.commit().await.map_err(database_error)?;
+
+    let payloads = self
+      .read_doc_payloads(&cipher, doc_id.as_deref())
+      .await
+      .map_err(database_error)?;
+    let doc = apply_updates(payloads.iter().map(|data| data.as_slice())).map_err(database_error)?;
+    Ok(encode_state_vector(&doc).map_err(database_error)?.into())
+  }
+
+  /// Encode what a peer with `state_vector` lacks of a doc as a single update,
+  /// in form like `Y.encodeStateAsUpdate(doc, state_vector)`. Without a state
+  /// vector the whole doc is encoded.
+  #[napi]
+  pub async fn diff_since(
+    &self,
+    doc_id: Option<String>,
+    state_vector: Option<Uint8Array>,
+  ) -> Result<Buffer> {
+    let state_vector = match &state_vector {
+      Some(state_vector) => decode_state_vector(state_vector).map_err(database_error)?,
+      None => StateVector::default(),
+    };
+    let cipher = self.cipher.read().await;
+    let payloads = self
+      .read_doc_payloads(&cipher, doc_id.as_deref())
+      .await
+      .map_err(database_error)?;
+    let doc = apply_updates(payloads.iter().map(|data| data.as_slice())).map_err(database_error)?;
+    let diff = doc
+      .encode_state_as_update_v1(&state_vector)
+      .map_err(|err| database_error(anyhow::anyhow!("failed to encode update: {err}")))?;
+    Ok(diff.into())
+  }
+}
@@ -0,0 +1,58 @@
+use chrono::NaiveDateTime;
+use napi_derive::napi;
+
+use super::{
+  error::{database_error, Result},
+  SqliteConnection,
+};
+
+#[napi(object)]
+pub struct DocMeta {
+  /// `None` for the root doc.
+  pub doc_id: Option<String>,
+  /// Updates not merged into the snapshot yet.
+  pub updates_count: i64,
+  /// Total size of the snapshot and the updates in bytes.
+  pub size: i64,
+  pub first_timestamp: NaiveDateTime,
+  pub last_timestamp: NaiveDateTime,
+  /// Whether the doc is in the trash, see `SqliteConnection::trash_doc`.
+  pub trashed: bool,
+}
+
+#[napi]
+impl SqliteConnection {
+  /// List every doc with updates or a snapshot in a single aggregate query,
+  /// trashed docs included.
+  #[napi]
+  pub async fn list_docs(&self) -> Result<Vec<DocMeta>> {
+    let cipher = self.cipher.read().await;
+    let overhead = cipher.overhead();
+    let docs = sqlx::query_as!(
+      DocMeta,
+      r#"SELECT
+        docs.doc_id AS "doc_id?",
+        COUNT(docs.id) AS "updates_count!: i64",
+        SUM(docs.size) AS "size!: i64",
+        MIN(docs.timestamp) AS "first_timestamp!: NaiveDateTime",
+        MAX(docs.timestamp) AS "last_timestamp!: NaiveDateTime",
+        EXISTS(
+          SELECT 1 FROM doc_tombstones
+          WHERE doc_tombstones.doc_id = docs.doc_id AND purged_at IS NULL AND restored_at IS NULL
+        ) AS "trashed!: bool"
+      FROM (
+        SELECT doc_id, id, length(data) - ? AS size, timestamp FROM updates
+        UNION ALL
+        SELECT doc_id, NULL, length(data) - ?, updated_at FROM snapshots
+      ) AS docs
+      GROUP BY docs.doc_id
+      ORDER BY docs.doc_id"#,
+      overhead,
+      overhead
+    )
+    .fetch_all(&self.pool)
+    .await
+    .map_err(database_error)?;
+    Ok(docs)
+  }
+}
@@ -0,0 +1,411 @@
+use std::borrow::Cow;
+
+use chacha20poly1305::{
+  aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+  XChaCha20Poly1305, XNonce,
+};
+use napi::bindgen_prelude::{Buffer, Uint8Array};
+use napi_derive::napi;
+use sqlx::{sqlite::SqliteRow, Row};
+
+use super::{
+  blob::blob_mime,
+  error::{database_error, KeyMismatch, Result},
+  immediate::ImmediateTransaction,
+  options::MEMORY_PATH,
+  BlobRow, SqliteConnection, UpdateRow,
+};
+
+/// Bytes of the random nonce stored in front of every encrypted payload.
+const NONCE_LENGTH: usize = 24;
+
+/// Bytes of the authentication tag appended to every encrypted payload.
+const TAG_LENGTH: usize = 16;
+
+/// Sealed into `encryption_info` to tell whether a key is the right one.
+const KEY_CHECK: &[u8] = b"affine workspace key check";
+
+/// Tables whose `data` column is encrypted, with the columns identifying a
+/// row, see `RowIdentity`.
+const ENCRYPTED_TABLES: &[(&str, &str)] = &[
+  ("updates", "doc_id"),
+  ("blobs", "key"),
+  ("kv", "namespace, key"),
+  ("blob_uploads", "upload_id"),
+  ("quarantine", "source, doc_id, key"),
+  ("snapshots", "doc_id"),
+];
+
+/// Tables kept for earlier releases with plaintext copies of rows now in `kv`,
+/// see migration 11.
+const LEGACY_TABLES: &[&str] = &["server_clock", "sync_metadata"];
+
+/// Rows re-encrypted per query while rotating the key.
+const REENCRYPT_BATCH_SIZE: i64 = 64;
+
+/// Identity of the row a payload is stored in, bound to the ciphertext as
+/// associated data. A payload copied into another row or table fails to
+/// decrypt instead of being read as that row.
+pub(crate) struct RowIdentity(Vec<u8>);
+
+impl RowIdentity {
+  fn new(table: &str, parts: &[Option<&str>]) -> Self {
+    let mut identity = table.as_bytes().to_vec();
+    for part in parts {
+      match part {
+        Some(part) => {
+          identity.push(1);
+          identity.extend_from_slice(&(part.len() as u32).to_le_bytes());
+          identity.extend_from_slice(part.as_bytes());
+        }
+        None => identity.push(0),
+      }
+    }
+    Self(identity)
+  }
+
+  pub(crate) fn update(doc_id: Option<&str>) -> Self {
+    Self::new("updates", &[doc_id])
+  }
+
+  pub(crate) fn snapshot(doc_id: Option<&str>) -> Self {
+    Self::new("snapshots", &[doc_id])
+  }
+
+  pub(crate) fn blob(key: &str) -> Self {
+    Self::new("blobs", &[Some(key)])
+  }
+
+  pub(crate) fn kv(namespace: &str, key: &str) -> Self {
+    Self::new("kv", &[Some(namespace), Some(key)])
+  }
+
+  pub(crate) fn blob_upload(upload_id: &str) -> Self {
+    Self::new("blob_uploads", &[Some(upload_id)])
+  }
+
+  /// Quarantined payloads are moved as they are and keep the identity of the
+  /// row they came from.
+  pub(crate) fn quarantined(source: &str, doc_id: Option<&str>, key: Option<&str>) -> Self {
+    match source {
+      "blobs" => Self::blob(key.unwrap_or_default()),
+      _ => Self::update(doc_id),
+    }
+  }
+
+  fn key_check() -> Self {
+    Self::new("encryption_info", &[])
+  }
+
+  fn of(table: &str, row: &SqliteRow) -> Self {
+    match table {
+      "updates" => Self::update(row.get(2)),
+      "blobs" => Self::blob(row.get(2)),
+      "kv" => Self::kv(row.get(2), row.get(3)),
+      "blob_uploads" => Self::blob_upload(row.get(2)),
+      "quarantine" => Self::quarantined(row.get(2), row.get(3), row.get(4)),
+      "snapshots" => Self::snapshot(row.get(2)),
+      _ => unreachable!("{table} is not encrypted"),
+    }
+  }
+}
+
+/// Encrypts row payloads with XChaCha20-Poly1305, payloads are stored as
+/// `nonce || ciphertext || tag`. Passes payloads through unchanged if the
+/// workspace is not encrypted.
+#[derive(Clone, Default)]
+pub(crate) struct Cipher(Option<XChaCha20Poly1305>);
+
+impl Cipher {
+  pub(crate) fn new(key: Option<&[u8]>) -> anyhow::Result<Self> {
+    match key {
+      Some(key) => XChaCha20Poly1305::new_from_slice(key)
+        .map(|cipher| Self(Some(cipher)))
+        .map_err(|_| anyhow::anyhow!("Encryption key must be 32 bytes long")),
+      None => Ok(Self(None)),
+    }
+  }
+
+  pub(crate) fn is_enabled(&self) -> bool {
+    self.0.is_some()
+  }
+
+  /// Bytes added to every payload stored in the database.
+  pub(crate) fn overhead(&self) -> i64 {
+    if self.is_enabled() {
+      (NONCE_LENGTH + TAG_LENGTH) as i64
+    } else {
+      0
+    }
+  }
+
+  /// Size of the plaintext of a payload that is `size` bytes long in the
+  /// database.
+  pub(crate) fn plaintext_size(&self, size: i64) -> i64 {
+    (size - self.overhead()).max(0)
+  }
+
+  pub(crate) fn seal<'a>(
+    &self,
+    data: &'a [u8],
+    row: &RowIdentity,
+  ) -> anyhow::Result<Cow<'a, [u8]>> {
+    let Some(cipher) = &self.0 else {
+      return Ok(Cow::Borrowed(data));
+    };
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let payload = Payload {
+      msg: data,
+      aad: &row.0,
+    };
+    let ciphertext = cipher
+      .encrypt(&nonce, payload)
+      .map_err(|_| anyhow::anyhow!("failed to encrypt payload"))?;
+    let mut sealed = Vec::with_capacity(NONCE_LENGTH + ciphertext.len());
+    sealed.extend_from_slice(&nonce);
+    sealed.extend_from_slice(&ciphertext);
+    Ok(Cow::Owned(sealed))
+  }
+
+  pub(crate) fn open<'a>(
+    &self,
+    data: &'a [u8],
+    row: &RowIdentity,
+  ) -> anyhow::Result<Cow<'a, [u8]>> {
+    let Some(cipher) = &self.0 else {
+      return Ok(Cow::Borrowed(data));
+    };
+    if data.len() < NONCE_LENGTH + TAG_LENGTH {
+      return Err(anyhow::anyhow!("encrypted payload is too short"));
+    }
+    let (nonce, ciphertext) = data.split_at(NONCE_LENGTH);
+    let payload = Payload {
+      msg: ciphertext,
+      aad: &row.0,
+    };
+    cipher
+      .decrypt(XNonce::from_slice(nonce), payload)
+      .map(Cow::Owned)
+      .map_err(|_| anyhow::anyhow!("failed to decrypt payload"))
+  }
+
+  pub(crate) fn open_buffer(&self, data: Buffer, row: &RowIdentity) -> anyhow::Result<Buffer> {
+    if !self.is_enabled() {
+      return Ok(data);
+    }
+    Ok(self.open(&data, row)?.into_owned().into())
+  }
+
+  pub(crate) fn open_blob_row(&self, row: BlobRow) -> anyhow::Result<BlobRow> {
+    let identity = RowIdentity::blob(&row.key);
+    Ok(BlobRow {
+      data: self.open_buffer(row.data, &identity)?,
+      ..row
+    })
+  }
+
+  pub(crate) fn open_kv_row(&self, namespace: &str, row: BlobRow) -> anyhow::Result<BlobRow> {
+    let identity = RowIdentity::kv(namespace, &row.key);
+    Ok(BlobRow {
+      data: self.open_buffer(row.data, &identity)?,
+      ..row
+    })
+  }
+
+  pub(crate) fn open_update_rows(&self, rows: Vec<UpdateRow>) -> anyhow::Result<Vec<UpdateRow>> {
+    rows
+      .into_iter()
+      .map(|row| {
+        let identity = RowIdentity::update(row.doc_id.as_deref());
+        Ok(UpdateRow {
+          data: self.open_buffer(row.data, &identity)?,
+          ..row
+        })
+      })
+      .collect()
+  }
+}
+
+/// Read the sealed key check, `None` if the workspace is not encrypted.
+async fn read_key_check(
+  connection: &mut sqlx::SqliteConnection,
+) -> anyhow::Result<Option<Vec<u8>>> {
+  // read-only connections skip migrations, the table may not exist
+  let table: Option<String> = sqlx::query_scalar(
+    "SELECT name FROM sqlite_master WHERE type='table' AND name='encryption_info'",
+  )
+  .fetch_optional(&mut *connection)
+  .await?;
+  if table.is_none() {
+    return Ok(None);
+  }
+  Ok(
+    sqlx::query!("SELECT key_check FROM encryption_info WHERE id = 1")
+      .fetch_optional(&mut *connection)
+      .await?
+      .map(|row| row.key_check),
+  )
+}
+
+#[napi(object)]
+pub struct KeyRotationResult {
+  /// Ids of the quarantined rows that could not be decrypted with the previous
+  /// key, they are kept as they were and can not be read with the new one.
+  pub skipped_quarantine_ids: Vec<i64>,
+}
+
+#[napi]
+impl SqliteConnection {
+  /// Re-encrypt every row with `key` in a single transaction, `None` decrypts
+  /// the workspace. Other reads and writes wait until it is done. Encrypting
+  /// drops the search index, rebuild it after decrypting. The file is vacuumed
+  /// afterwards so no payload under the previous key is left behind, except
+  /// the skipped quarantined rows.
+  #[napi]
+  pub async fn rotate_encryption_key(&self, key: Option<Uint8Array>) -> Result<KeyRotationResult> {
+    if self.read_only {
+      return Err(database_error(anyhow::anyhow!(
+        "Can not change the key of a read-only workspace"
+      )));
+    }
+    let next = Cipher::new(key.as_ref().map(|key| key.as_ref())).map_err(database_error)?;
+    let mut cipher = self.cipher.write().await;
+    let skipped_quarantine_ids = self
+      .reencrypt(&cipher, &next)
+      .await
+      .map_err(database_error)?;
+    *cipher = next;
+    Ok(KeyRotationResult {
+      skipped_quarantine_ids,
+    })
+  }
+
+  /// Check the key given at construction against the workspace, a plaintext
+  /// workspace is encrypted on its first connection with a key.
+  pub(crate) async fn init_encryption(&self) -> anyhow::Result<()> {
+    let cipher = self.cipher.write().await;
+    let mut connection = self.pool.acquire().await?;
+    let key_check = read_key_check(&mut connection).await?;
+    drop(connection);
+
+    match key_check {
+      Some(key_check) => {
+        if !cipher.is_enabled() {
+          return Err(KeyMismatch("Workspace is encrypted, an encryption key is required").into());
+        }
+        if !cipher
+          .open(&key_check, &RowIdentity::key_check())
+          .is_ok_and(|check| check.as_ref() == KEY_CHECK)
+        {
+          return Err(KeyMismatch("Wrong encryption key").into());
+        }
+      }
+      None if cipher.is_enabled() => {
+        if self.read_only {
+          return Err(anyhow::anyhow!("Can not encrypt a read-only workspace"));
+        }
+        // plaintext rows always open, nothing is skipped
+        self.reencrypt(&Cipher::default(), &cipher).await?;
+      }
+      None => {}
+    }
+    Ok(())
+  }
+
+  /// Returns the ids of the quarantined rows that `current` can not decrypt.
+  async fn reencrypt(&self, current: &Cipher, next: &Cipher) -> anyhow::Result<Vec<i64>> {
+    let mut transaction = ImmediateTransaction::begin(&self.pool).await?;
+    let mut skipped = Vec::new();
+    for (table, columns) in ENCRYPTED_TABLES {
+      let mut after_rowid = 0;
+      loop {
+        let rows = sqlx::query(&format!(
+          "SELECT rowid, data, {columns} FROM {table} WHERE rowid > ? ORDER BY rowid LIMIT ?"
+        ))
+        .bind(after_rowid)
+        .bind(REENCRYPT_BATCH_SIZE)
+        .fetch_all(&mut *transaction)
+        .await?;
+        let Some(last) = rows.last() else {
+          break;
+        };
+        after_rowid = last.get(0);
+
+        for row in rows {
+          let rowid: i64 = row.get(0);
+          let data: Vec<u8> = row.get(1);
+          let identity = RowIdentity::of(table, &row);
+          let plaintext = match current.open(&data, &identity) {
+            Ok(plaintext) => plaintext,
+            // quarantined rows may be unreadable anyway, keep them as they are
+            Err(_) if *table == "quarantine" => {
+              skipped.push(rowid);
+              continue;
+            }
+            Err(err) => return Err(err.context(format!("failed to decrypt {table} row {rowid}"))),
+          };
+          let sealed = next.seal(&plaintext, &identity)?;
+          if *table == "blobs" {
+            // fill in the metadata of blobs written before it was stored
+            let data = sealed.as_ref();
+            let size = plaintext.len() as i64;
+            let mime = blob_mime(&plaintext);
+            sqlx::query!(
+              "UPDATE blobs SET data = ?, size = ?, mime = ? WHERE rowid = ?",
+              data,
+              size,
+              mime,
+              rowid
+            )
+            .execute(&mut *transaction)
+            .await?;
+          } else {
+            sqlx::query(&format!("UPDATE {table} SET data = ? WHERE rowid = ?"))
+              .bind(sealed.as_ref())
+              .bind(rowid)
+              .execute(&mut *transaction)
+              .await?;
+          }
+        }
+      }
+    }
+
+    if next.is_enabled() {
+      // the text of encrypted workspaces is never indexed
+      sqlx::query!("DELETE FROM search_blocks")
+        .execute(&mut *transaction)
+        .await?;
+      // earlier releases can not read an encrypted workspace anyway, the
+      // plaintext copies they would need are dropped
+      for table in LEGACY_TABLES {
+        sqlx::query(&format!("DROP TABLE IF EXISTS {table}"))
+          .execute(&mut *transaction)
+          .await?;
+      }
+      let key_check = next.seal(KEY_CHECK, &RowIdentity::key_check())?;
+      let key_check = key_check.as_ref();
+      sqlx::query!(
+        "INSERT INTO encryption_info (id, key_check) VALUES (1, $1) ON CONFLICT(id) DO UPDATE SET key_check = excluded.key_check, timestamp = CURRENT_TIMESTAMP",
+        key_check
+      )
+      .execute(&mut *transaction)
+      .await?;
+    } else {
+      sqlx::query!("DELETE FROM encryption_info")
+        .execute(&mut *transaction)
+        .await?;
+    }
+    transaction.commit().await?;
+
+    // the previous payloads are still in free pages and in the WAL, rebuild
+    // the file and empty the WAL so they are gone from the disk
+    if self.path != MEMORY_PATH {
+      let mut connection = self.pool.acquire().await?;
+      sqlx::query("VACUUM").execute(&mut *connection).await?;
+      sqlx::query("PRAGMA wal_checkpoint(TRUNCATE)")
+        .execute(&mut *connection)
+        .await?;
+    }
+    Ok(skipped)
+  }
+}
@@ -0,0 +1,133 @@
+use napi_derive::napi;
+
+/// Cause of a failed database operation, set as the `code` of the error a
+/// call rejects with. Errors that did not come from sqlite are `UNKNOWN`.
+#[napi(string_enum = "SCREAMING_SNAKE_CASE")]
+#[derive(Debug)]
+pub enum SqliteErrorCode {
+  /// Another connection holds the lock for longer than the busy timeout.
+  Busy,
+  /// A transaction of the same connection holds the lock.
+  Locked,
+  /// The file or the connection is read-only.
+  Readonly,
+  Io,
+  Corrupt,
+  /// The disk is full.
+  Full,
+  Constraint,
+  /// The file is not a database, or it is encrypted with another key or
+  /// without the key being given.
+  NotADatabase,
+  Unknown,
+}
+
+impl SqliteErrorCode {
+  pub(crate) fn as_str(&self) -> &'static str {
+    match self {
+      SqliteErrorCode::Busy => "BUSY",
+      SqliteErrorCode::Locked => "LOCKED",
+      SqliteErrorCode::Readonly => "READONLY",
+      SqliteErrorCode::Io => "IO",
+      SqliteErrorCode::Corrupt => "CORRUPT",
+      SqliteErrorCode::Full => "FULL",
+      SqliteErrorCode::Constraint => "CONSTRAINT",
+      SqliteErrorCode::NotADatabase => "NOT_A_DATABASE",
+      SqliteErrorCode::Unknown => "UNKNOWN",
+    }
+  }
+
+  /// Code of an sqlite result code, extended result codes keep the primary
+  /// code in the lowest byte.
+  pub(crate) fn from_result_code(code: i32) -> Self {
+    match code & 0xff {
+      5 => SqliteErrorCode::Busy,
+      6 => SqliteErrorCode::Locked,
+      8 => SqliteErrorCode::Readonly,
+      10 => SqliteErrorCode::Io,
+      11 => SqliteErrorCode::Corrupt,
+      13 => SqliteErrorCode::Full,
+      19 => SqliteErrorCode::Constraint,
+      26 => SqliteErrorCode::NotADatabase,
+      _ => SqliteErrorCode::Unknown,
+    }
+  }
+}
+
+impl AsRef<str> for SqliteErrorCode {
+  fn as_ref(&self) -> &str {
+    self.as_str()
+  }
+}
+
+/// Result of a call that rejects with a `SqliteErrorCode` as its `code`.
+pub type Result<T> = napi::Result<T, SqliteErrorCode>;
+
+/// Failed call of the raw sqlite api, see `raw::sqlite_error`.
+#[derive(Debug)]
+pub(crate) struct RawSqliteError {
+  pub(crate) code: i32,
+  pub(crate) message: String,
+}
+
+impl std::fmt::Display for RawSqliteError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "sqlite error ({}): {}", self.code, self.message)
+  }
+}
+
+impl std::error::Error for RawSqliteError {}
+
+/// The workspace is encrypted with another key than the one of the
+/// connection, reported as `NOT_A_DATABASE` like a file sqlite can not read.
+#[derive(Debug)]
+pub(crate) struct KeyMismatch(pub(crate) &'static str);
+
+impl std::fmt::Display for KeyMismatch {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.write_str(self.0)
+  }
+}
+
+impl std::error::Error for KeyMismatch {}
+
+impl From<&sqlx::Error> for SqliteErrorCode {
+  fn from(err: &sqlx::Error) -> Self {
+    match err {
+      sqlx::Error::Database(err) => err
+        .code()
+        .and_then(|code| code.parse().ok())
+        .map_or(SqliteErrorCode::Unknown, SqliteErrorCode::from_result_code),
+      sqlx::Error::Io(_) => SqliteErrorCode::Io,
+      // every connection of the pool is held by someone else
+      sqlx::Error::PoolTimedOut => SqliteErrorCode::Busy,
+      _ => SqliteErrorCode::Unknown,
+    }
+  }
+}
+
+/// Turn an error into the error of a call, with the `SqliteErrorCode` of the
+/// first sqlite error in its chain as `code`.
+pub(crate) fn database_error(err: impl Into<anyhow::Error>) -> napi::Error<SqliteErrorCode> {
+  let err = err.into();
+  let code = err
+    .chain()
+    .find_map(|cause| {
+      if let Some(cause) = cause.downcast_ref::<sqlx::Error>() {
+        return Some(SqliteErrorCode::from(cause));
+      }
+      if cause.is::<KeyMismatch>() {
+        return Some(SqliteErrorCode::NotADatabase);
+      }
+      let cause = cause.downcast_ref::<RawSqliteError>()?;
+      Some(SqliteErrorCode::from_result_code(cause.code))
+    })
+    .unwrap_or(SqliteErrorCode::Unknown);
+  napi::Error::new(code, format!("{err:#}"))
+}
+
+/// Report the error of work that has no call to reject, like rolling back an
+/// idle transaction, through the `log` facade.
+pub(crate) fn log_error(context: &str, err: impl std::fmt::Display) {
+  log::error!("{context}: {err}");
+}
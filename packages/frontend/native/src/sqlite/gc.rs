@@ -0,0 +1,284 @@
+use std::collections::HashSet;
+
+use chrono::{Duration, NaiveDateTime, Utc};
+use napi_derive::napi;
+
+use super::{
+  encryption::{Cipher, RowIdentity},
+  error::{database_error, Result},
+  immediate::ImmediateTransaction,
+  snapshot::read_doc_payloads,
+  ydoc::{apply_updates, collect_strings},
+  ChangeEvent, SqliteConnection,
+};
+
+/// Default grace period of blob gc in seconds, one day.
+const DEFAULT_GRACE_PERIOD: u32 = 24 * 60 * 60;
+
+#[napi(object)]
+#[derive(Default)]
+pub struct BlobGcOptions {
+  /// Only report unreferenced blobs without deleting them, defaults to
+  /// `true`.
+  pub dry_run: Option<bool>,
+  /// Keep blobs added in the last `grace_period` seconds even if they are not
+  /// referenced yet, defaults to one day.
+  pub grace_period: Option<u32>,
+}
+
+#[napi(object)]
+pub struct BlobGcResult {
+  /// Keys of the unreferenced blobs older than the grace period.
+  pub unreferenced: Vec<String>,
+  /// Total size of the unreferenced blobs in bytes.
+  pub size: i64,
+  pub deleted: bool,
+}
+
+#[napi]
+impl SqliteConnection {
+  /// Find blobs that are not referenced by any doc and delete them unless
+  /// `dry_run` is set. The references are collected in a read transaction,
+  /// the write lock is only taken to recheck the rows written since then and
+  /// delete the blobs, so an update inserted in between can not lose its
+  /// blob. Fails without deleting anything if a doc can not be decoded.
+  #[napi]
+  pub async fn gc_blobs(&self, options: Option<BlobGcOptions>) -> Result<BlobGcResult> {
+    let options = options.unwrap_or_default();
+    let dry_run = options.dry_run.unwrap_or(true);
+    let grace_period = options.grace_period.unwrap_or(DEFAULT_GRACE_PERIOD);
+    let deadline = Utc::now().naive_utc() - Duration::seconds(grace_period as i64);
+    let cipher = self.cipher.read().await;
+
+    let mut transaction = self.pool.begin().await.map_err(database_error)?;
+    let collected = find_unreferenced(&mut transaction, &cipher, deadline).await?;
+    transaction.commit().await.map_err(database_error)?;
+    if dry_run {
+      return Ok(BlobGcResult::new(collected.unreferenced, &cipher, false));
+    }
+
+    let mut transaction = ImmediateTransaction::begin(&self.pool)
+      .await
+      .map_err(database_error)?;
+    let unreferenced = recheck_unreferenced(&mut transaction, &cipher, collected, deadline).await?;
+    for (key, _) in &unreferenced {
+      sqlx::query!("DELETE FROM blobs WHERE key = ?", key)
+        .execute(&mut *transaction)
+        .await
+        .map_err(database_error)?;
+    }
+    // chunks of uploads that were never committed
+    sqlx::query!("DELETE FROM blob_uploads WHERE timestamp < ?", deadline)
+      .execute(&mut *transaction)
+      .await
+      .map_err(database_error)?;
+    transaction.commit().await.map_err(database_error)?;
+    self.notify(
+      unreferenced
+        .iter()
+        .map(|(key, _)| ChangeEvent::keyed("blobs", Some(key.clone())))
+        .collect(),
+    );
+    Ok(BlobGcResult::new(unreferenced, &cipher, true))
+  }
+}
+
+impl BlobGcResult {
+  fn new(unreferenced: Vec<(String, i64)>, cipher: &Cipher, deleted: bool) -> Self {
+    let size = unreferenced
+      .iter()
+      .map(|(_, size)| cipher.plaintext_size(*size))
+      .sum();
+    Self {
+      unreferenced: unreferenced.into_iter().map(|(key, _)| key).collect(),
+      size,
+      deleted,
+    }
+  }
+}
+
+/// Unreferenced blobs found by `find_unreferenced`, with the last ids of the
+/// rows it has read.
+struct Collected {
+  unreferenced: Vec<(String, i64)>,
+  last_update_id: i64,
+  last_quarantine_id: i64,
+}
+
+/// Keys and stored sizes of the blobs older than `deadline` that no doc
+/// references. A doc with a root type that can not be read references every
+/// blob.
+async fn find_unreferenced(
+  connection: &mut sqlx::SqliteConnection,
+  cipher: &Cipher,
+  deadline: NaiveDateTime,
+) -> Result<Collected> {
+  let last_update_id = sqlx::query!(r#"SELECT MAX(id) AS "id: i64" FROM updates"#)
+    .fetch_one(&mut *connection)
+    .await
+    .map_err(database_error)?
+    .id
+    .unwrap_or(0);
+  let last_quarantine_id = sqlx::query!(r#"SELECT MAX(id) AS "id: i64" FROM quarantine"#)
+    .fetch_one(&mut *connection)
+    .await
+    .map_err(database_error)?
+    .id
+    .unwrap_or(0);
+  let mut unreferenced = sqlx::query!(
+    r#"SELECT key, length(data) AS "size!: i64" FROM blobs WHERE timestamp < ? ORDER BY key"#,
+    deadline
+  )
+  .fetch_all(&mut *connection)
+  .await
+  .map_err(database_error)?
+  .into_iter()
+  .map(|blob| (blob.key, blob.size))
+  .collect::<Vec<_>>();
+
+  let doc_ids = if unreferenced.is_empty() {
+    Vec::new()
+  } else {
+    sqlx::query!("SELECT doc_id FROM updates UNION SELECT doc_id FROM snapshots")
+      .fetch_all(&mut *connection)
+      .await
+      .map_err(database_error)?
+  };
+  for doc_id in doc_ids.into_iter().map(|row| row.doc_id) {
+    let decrypted = read_doc_payloads(connection, cipher, doc_id.as_deref())
+      .await
+      .map_err(|err| database_error(err.context(format!("failed to read doc {doc_id:?}"))))?;
+    let doc = apply_updates(decrypted.iter().map(|update| update.as_ref()))
+      .map_err(|err| database_error(err.context(format!("failed to decode doc {doc_id:?}"))))?;
+    let mut strings = HashSet::new();
+    if !collect_strings(&doc, &mut strings) {
+      unreferenced.clear();
+      break;
+    }
+    // strings split over several items, like rich text or its attributes,
+    // are still found in the bytes of the updates
+    unreferenced.retain(|(key, _)| {
+      !strings.contains(key) && !decrypted.iter().any(|data| contains(data, key.as_bytes()))
+    });
+  }
+
+  let quarantined = read_quarantined(connection, cipher, 0).await?;
+  unreferenced.retain(|(key, _)| {
+    !quarantined
+      .iter()
+      .any(|data| contains(data, key.as_bytes()))
+  });
+  Ok(Collected {
+    unreferenced,
+    last_update_id,
+    last_quarantine_id,
+  })
+}
+
+/// Drop the blobs of `collected` that were referenced or replaced since they
+/// were collected. The updates and snapshots written since then may not decode
+/// on their own, look for the keys in their bytes instead.
+async fn recheck_unreferenced(
+  connection: &mut sqlx::SqliteConnection,
+  cipher: &Cipher,
+  collected: Collected,
+  deadline: NaiveDateTime,
+) -> Result<Vec<(String, i64)>> {
+  let Collected {
+    unreferenced,
+    last_update_id,
+    last_quarantine_id,
+  } = collected;
+  if unreferenced.is_empty() {
+    return Ok(unreferenced);
+  }
+  let current = sqlx::query!(
+    r#"SELECT key, length(data) AS "size!: i64" FROM blobs WHERE timestamp < ?"#,
+    deadline
+  )
+  .fetch_all(&mut *connection)
+  .await
+  .map_err(database_error)?
+  .into_iter()
+  .map(|blob| (blob.key, blob.size))
+  .collect::<HashSet<_>>();
+
+  let mut written = Vec::new();
+  let updates = sqlx::query!(
+    "SELECT doc_id, data FROM updates WHERE id > ?",
+    last_update_id
+  )
+  .fetch_all(&mut *connection)
+  .await
+  .map_err(database_error)?;
+  for row in updates {
+    let identity = RowIdentity::update(row.doc_id.as_deref());
+    written.push(
+      cipher
+        .open(&row.data, &identity)
+        .map_err(database_error)?
+        .into_owned(),
+    );
+  }
+  let snapshots = sqlx::query!(
+    "SELECT doc_id, data FROM snapshots WHERE last_update_id > ?",
+    last_update_id
+  )
+  .fetch_all(&mut *connection)
+  .await
+  .map_err(database_error)?;
+  for row in snapshots {
+    let identity = RowIdentity::snapshot(row.doc_id.as_deref());
+    written.push(
+      cipher
+        .open(&row.data, &identity)
+        .map_err(database_error)?
+        .into_owned(),
+    );
+  }
+  written.extend(read_quarantined(connection, cipher, last_quarantine_id).await?);
+
+  Ok(
+    unreferenced
+      .into_iter()
+      .filter(|blob| current.contains(blob))
+      .filter(|(key, _)| !written.iter().any(|data| contains(data, key.as_bytes())))
+      .collect(),
+  )
+}
+
+/// Payloads of the updates quarantined after `after_id`, decrypted if
+/// possible. They may not decode, or not without the updates they depend on,
+/// but strings are stored as plain UTF-8 in updates.
+async fn read_quarantined(
+  connection: &mut sqlx::SqliteConnection,
+  cipher: &Cipher,
+  after_id: i64,
+) -> Result<Vec<Vec<u8>>> {
+  let rows = sqlx::query!(
+    "SELECT doc_id, data FROM quarantine WHERE source = 'updates' AND id > ?",
+    after_id
+  )
+  .fetch_all(&mut *connection)
+  .await
+  .map_err(database_error)?;
+  Ok(
+    rows
+      .into_iter()
+      .map(|row| {
+        let decrypted = cipher
+          .open(&row.data, &RowIdentity::update(row.doc_id.as_deref()))
+          .map(|data| data.into_owned())
+          .ok();
+        decrypted.unwrap_or(row.data)
+      })
+      .collect(),
+  )
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+  needle.is_empty()
+    || haystack
+      .windows(needle.len())
+      .any(|window| window == needle)
+}
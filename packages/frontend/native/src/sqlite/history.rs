@@ -0,0 +1,143 @@
+use chrono::{Duration, NaiveDateTime};
+use napi::bindgen_prelude::Buffer;
+use napi_derive::napi;
+
+use super::{
+  encryption::RowIdentity,
+  error::{database_error, Result},
+  ydoc::merge_updates,
+  SqliteConnection,
+};
+
+/// Default gap between two edit sessions in seconds, 10 minutes.
+const DEFAULT_SESSION_GAP: u32 = 10 * 60;
+
+#[napi(object)]
+pub struct EditSession {
+  pub start: NaiveDateTime,
+  /// Pass it to `get_doc_at` to restore the doc as it was after this session.
+  pub end: NaiveDateTime,
+  pub updates_count: i64,
+  pub first_update_id: i64,
+  pub last_update_id: i64,
+}
+
+#[napi]
+impl SqliteConnection {
+  /// Rebuild a doc as it was at `timestamp` by merging the updates inserted
+  /// until then, `None` if it had no updates yet. Fails if the history before
+  /// `timestamp` was merged into a snapshot, or compacted together with later
+  /// updates.
+  #[napi]
+  pub async fn get_doc_at(
+    &self,
+    doc_id: Option<String>,
+    timestamp: NaiveDateTime,
+  ) -> Result<Option<Buffer>> {
+    let cipher = self.cipher.read().await;
+    let mut transaction = self.pool.begin().await.map_err(database_error)?;
+    let snapshot = sqlx::query!(
+      "SELECT data, last_update_id, updated_at FROM snapshots WHERE doc_id IS ?",
+      doc_id
+    )
+    .fetch_optional(&mut *transaction)
+    .await
+    .map_err(database_error)?;
+    if let Some(snapshot) = &snapshot {
+      if snapshot.updated_at > timestamp {
+        return Err(database_error(anyhow::anyhow!(
+          "History of doc {doc_id:?} before {} was merged into a snapshot",
+          snapshot.updated_at
+        )));
+      }
+    }
+    let after_id = snapshot
+      .as_ref()
+      .map_or(0, |snapshot| snapshot.last_update_id);
+    // a compacted row takes the time of its latest update
+    let compacted = sqlx::query!(
+      r#"SELECT merged_from AS "merged_from!: NaiveDateTime" FROM updates
+      WHERE doc_id IS ? AND id > ? AND merged_from <= ? AND timestamp > ?"#,
+      doc_id,
+      after_id,
+      timestamp,
+      timestamp
+    )
+    .fetch_optional(&mut *transaction)
+    .await
+    .map_err(database_error)?;
+    if let Some(compacted) = compacted {
+      return Err(database_error(anyhow::anyhow!(
+        "History of doc {doc_id:?} after {} was compacted",
+        compacted.merged_from
+      )));
+    }
+    // apply in insertion order, timestamps do not have to be increasing
+    let updates = sqlx::query!(
+      "SELECT data FROM updates WHERE doc_id IS ? AND id > ? AND timestamp <= ? ORDER BY id",
+      doc_id,
+      after_id,
+      timestamp
+    )
+    .fetch_all(&mut *transaction)
+    .await
+    .map_err(database_error)?;
+    transaction.commit().await.map_err(database_error)?;
+
+    let snapshot_identity = RowIdentity::snapshot(doc_id.as_deref());
+    let update_identity = RowIdentity::update(doc_id.as_deref());
+    let payloads = snapshot
+      .map(|snapshot| (snapshot.data, &snapshot_identity))
+      .into_iter()
+      .chain(updates.into_iter().map(|row| (row.data, &update_identity)))
+      .map(|(data, identity)| cipher.open(&data, identity).map(|data| data.into_owned()))
+      .collect::<anyhow::Result<Vec<_>>>()
+      .map_err(database_error)?;
+    if payloads.is_empty() {
+      return Ok(None);
+    }
+    let merged =
+      merge_updates(payloads.iter().map(|data| data.as_slice())).map_err(database_error)?;
+    Ok(Some(merged.into()))
+  }
+
+  /// Group the updates of a doc into edit sessions, a new session starts
+  /// whenever no update was inserted for `gap` seconds, 10 minutes by default.
+  /// Sessions are ordered by time, updates merged into a snapshot or by
+  /// compaction are not listed on their own.
+  #[napi]
+  pub async fn list_edit_sessions(
+    &self,
+    doc_id: Option<String>,
+    gap: Option<u32>,
+  ) -> Result<Vec<EditSession>> {
+    let gap = Duration::seconds(gap.unwrap_or(DEFAULT_SESSION_GAP) as i64);
+    let updates = sqlx::query!(
+      "SELECT id, timestamp FROM updates WHERE doc_id IS ? ORDER BY timestamp, id",
+      doc_id
+    )
+    .fetch_all(&self.pool)
+    .await
+    .map_err(database_error)?;
+
+    let mut sessions: Vec<EditSession> = Vec::new();
+    for update in updates {
+      match sessions.last_mut() {
+        Some(session) if update.timestamp - session.end <= gap => {
+          session.end = update.timestamp;
+          session.updates_count += 1;
+          session.first_update_id = session.first_update_id.min(update.id);
+          session.last_update_id = session.last_update_id.max(update.id);
+        }
+        _ => sessions.push(EditSession {
+          start: update.timestamp,
+          end: update.timestamp,
+          updates_count: 1,
+          first_update_id: update.id,
+          last_update_id: update.id,
+        }),
+      }
+    }
+    Ok(sessions)
+  }
+}
@@ -0,0 +1,48 @@
+use std::ops::{Deref, DerefMut};
+
+use sqlx::{pool::PoolConnection, Pool, Sqlite};
+
+/// A transaction that takes the write lock when it begins. A deferred
+/// transaction that reads before it writes fails with `BUSY` right away if
+/// another connection is writing, this one waits for the busy timeout
+/// instead. Rolled back by the pool if it is dropped before `commit`, see
+/// `ConnectionOptions::to_pool_options`.
+pub(crate) struct ImmediateTransaction {
+  connection: PoolConnection<Sqlite>,
+}
+
+impl ImmediateTransaction {
+  pub(crate) async fn begin(pool: &Pool<Sqlite>) -> sqlx::Result<Self> {
+    let mut connection = pool.acquire().await?;
+    sqlx::query("BEGIN IMMEDIATE")
+      .execute(&mut *connection)
+      .await?;
+    Ok(Self { connection })
+  }
+
+  pub(crate) async fn commit(mut self) -> sqlx::Result<()> {
+    sqlx::query("COMMIT").execute(&mut *self.connection).await?;
+    Ok(())
+  }
+
+  pub(crate) async fn rollback(mut self) -> sqlx::Result<()> {
+    sqlx::query("ROLLBACK")
+      .execute(&mut *self.connection)
+      .await?;
+    Ok(())
+  }
+}
+
+impl Deref for ImmediateTransaction {
+  type Target = sqlx::SqliteConnection;
+
+  fn deref(&self) -> &Self::Target {
+    &self.connection
+  }
+}
+
+impl DerefMut for ImmediateTransaction {
+  fn deref_mut(&mut self) -> &mut Self::Target {
+    &mut self.connection
+  }
+}
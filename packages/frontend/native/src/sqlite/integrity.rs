@@ -0,0 +1,198 @@
+use std::collections::HashSet;
+
+use napi_derive::napi;
+use sqlx::Row;
+
+use super::{
+  encryption::RowIdentity,
+  error::{database_error, Result},
+  ydoc::apply_updates,
+  ChangeEvent, SqliteConnection,
+};
+
+/// Updates decoded per query while checking a workspace.
+const CHECK_BATCH_SIZE: i64 = 256;
+
+#[napi(object)]
+#[derive(Default)]
+pub struct IntegrityCheckOptions {
+  /// Move bad rows into the `quarantine` table, defaults to `false`.
+  pub repair: Option<bool>,
+  /// Doc ids known to the caller, updates of any other doc are flagged. Only
+  /// empty doc ids are flagged if not set.
+  pub doc_ids: Option<Vec<String>>,
+}
+
+#[napi(object)]
+pub struct IntegrityReport {
+  /// Problems reported by `PRAGMA integrity_check`, empty if the file is
+  /// healthy.
+  pub errors: Vec<String>,
+  /// Ids of updates that can not be decoded as a Y update.
+  pub corrupted_updates: Vec<i64>,
+  /// Ids of updates with an unexpected `doc_id`.
+  pub unexpected_doc_id_updates: Vec<i64>,
+  /// Keys of zero-length blobs.
+  pub empty_blobs: Vec<String>,
+  /// Rows moved into the `quarantine` table by this check.
+  pub quarantined: i64,
+}
+
+impl IntegrityReport {
+  pub(crate) fn is_healthy(&self) -> bool {
+    self.errors.is_empty()
+      && self.corrupted_updates.is_empty()
+      && self.unexpected_doc_id_updates.is_empty()
+      && self.empty_blobs.is_empty()
+  }
+}
+
+#[napi]
+impl SqliteConnection {
+  /// Check the workspace file and every row of it. With `repair`, bad rows are
+  /// moved into the `quarantine` table so the rest of the workspace can still
+  /// be loaded. Nothing is moved if `PRAGMA integrity_check` fails, the file
+  /// should be restored from a backup instead.
+  #[napi]
+  pub async fn check_integrity(
+    &self,
+    options: Option<IntegrityCheckOptions>,
+  ) -> Result<IntegrityReport> {
+    let options = options.unwrap_or_default();
+    let repair = options.repair.unwrap_or(false);
+    if repair && self.read_only {
+      return Err(database_error(anyhow::anyhow!(
+        "Can not repair a read-only workspace"
+      )));
+    }
+    let known_doc_ids = options
+      .doc_ids
+      .map(|doc_ids| doc_ids.into_iter().collect::<HashSet<_>>());
+
+    let errors = sqlx::query("PRAGMA integrity_check")
+      .fetch_all(&self.pool)
+      .await
+      .map_err(database_error)?
+      .into_iter()
+      .map(|row| row.get::<String, _>(0))
+      // a healthy file reports a single `ok` row
+      .filter(|message| message != "ok")
+      .collect::<Vec<_>>();
+
+    let cipher = self.cipher.read().await;
+    let mut corrupted_updates = Vec::new();
+    let mut unexpected_doc_id_updates = Vec::new();
+    let mut after_id = 0;
+    loop {
+      let rows = sqlx::query!(
+        "SELECT id, data, doc_id FROM updates WHERE id > ? ORDER BY id LIMIT ?",
+        after_id,
+        CHECK_BATCH_SIZE
+      )
+      .fetch_all(&self.pool)
+      .await
+      .map_err(database_error)?;
+      let Some(last) = rows.last() else {
+        break;
+      };
+      after_id = last.id;
+
+      for row in rows {
+        let decoded = cipher
+          .open(&row.data, &RowIdentity::update(row.doc_id.as_deref()))
+          .and_then(|update| apply_updates([update.as_ref()]));
+        if decoded.is_err() {
+          corrupted_updates.push(row.id);
+        } else if let Some(doc_id) = &row.doc_id {
+          // the root doc is stored with a NULL doc id, never an empty one
+          let unexpected = doc_id.is_empty()
+            || known_doc_ids
+              .as_ref()
+              .is_some_and(|doc_ids| !doc_ids.contains(doc_id));
+          if unexpected {
+            unexpected_doc_id_updates.push(row.id);
+          }
+        }
+      }
+    }
+
+    // encrypted empty blobs still hold a nonce and a tag
+    let empty_size = cipher.overhead();
+    let empty_blobs = sqlx::query!(
+      "SELECT key FROM blobs WHERE length(data) <= ? ORDER BY key",
+      empty_size
+    )
+    .fetch_all(&self.pool)
+    .await
+    .map(|rows| rows.into_iter().map(|row| row.key).collect::<Vec<_>>())
+    .map_err(database_error)?;
+
+    let mut report = IntegrityReport {
+      errors,
+      corrupted_updates,
+      unexpected_doc_id_updates,
+      empty_blobs,
+      quarantined: 0,
+    };
+    if repair && report.errors.is_empty() && !report.is_healthy() {
+      report.quarantined = self
+        .quarantine(&report, empty_size)
+        .await
+        .map_err(database_error)?;
+      self.notify(vec![ChangeEvent::any()]);
+    }
+    Ok(report)
+  }
+
+  async fn quarantine(&self, report: &IntegrityReport, empty_size: i64) -> anyhow::Result<i64> {
+    let mut transaction = self.pool.begin().await?;
+    let mut quarantined = 0;
+
+    let updates = report
+      .corrupted_updates
+      .iter()
+      .map(|id| (*id, "corrupted update"))
+      .chain(
+        report
+          .unexpected_doc_id_updates
+          .iter()
+          .map(|id| (*id, "unexpected doc id")),
+      );
+    for (id, reason) in updates {
+      sqlx::query!(
+        r#"INSERT INTO quarantine (source, reason, row_id, doc_id, data, timestamp)
+        SELECT 'updates', $1, id, doc_id, data, timestamp FROM updates WHERE id = $2"#,
+        reason,
+        id
+      )
+      .execute(&mut *transaction)
+      .await?;
+      quarantined += sqlx::query!("DELETE FROM updates WHERE id = ?", id)
+        .execute(&mut *transaction)
+        .await?
+        .rows_affected() as i64;
+    }
+
+    for key in &report.empty_blobs {
+      sqlx::query!(
+        r#"INSERT INTO quarantine (source, reason, key, data, timestamp)
+        SELECT 'blobs', 'empty blob', key, data, timestamp FROM blobs WHERE key = ? AND length(data) <= ?"#,
+        key,
+        empty_size
+      )
+      .execute(&mut *transaction)
+      .await?;
+      quarantined += sqlx::query!(
+        "DELETE FROM blobs WHERE key = ? AND length(data) <= ?",
+        key,
+        empty_size
+      )
+      .execute(&mut *transaction)
+      .await?
+      .rows_affected() as i64;
+    }
+
+    transaction.commit().await?;
+    Ok(quarantined)
+  }
+}
@@ -0,0 +1,271 @@
+use napi::bindgen_prelude::Uint8Array;
+use napi_derive::napi;
+
+use super::{
+  encryption::RowIdentity,
+  error::{database_error, Result},
+  immediate::ImmediateTransaction,
+  BlobRow, ChangeEvent, SqliteConnection,
+};
+
+/// Namespace of the former `server_clock` table.
+pub(crate) const SERVER_CLOCK: &str = "server_clock";
+
+/// Namespace of the former `sync_metadata` table.
+pub(crate) const SYNC_METADATA: &str = "sync_metadata";
+
+/// Smallest key after every key starting with `prefix`, `None` if there is
+/// none. Keys compare by their UTF-8 bytes, which follow the order of the code
+/// points, so scanning `prefix..end` uses the primary key instead of testing
+/// every row.
+pub(crate) fn prefix_end(prefix: &str) -> Option<String> {
+  let mut chars = prefix.chars().collect::<Vec<_>>();
+  while let Some(last) = chars.pop() {
+    let next = (last as u32 + 1..=char::MAX as u32).find_map(char::from_u32);
+    if let Some(next) = next {
+      chars.push(next);
+      return Some(chars.into_iter().collect());
+    }
+  }
+  None
+}
+
+#[napi(object)]
+pub struct KvEntry {
+  pub key: String,
+  pub data: Uint8Array,
+}
+
+#[napi]
+impl SqliteConnection {
+  #[napi]
+  pub async fn kv_get(&self, namespace: String, key: String) -> Result<Option<BlobRow>> {
+    let cipher = self.cipher.read().await;
+    let row = sqlx::query_as!(
+      BlobRow,
+      "SELECT key, data, timestamp FROM kv WHERE namespace = ? AND key = ?",
+      namespace,
+      key
+    )
+    .fetch_optional(&self.pool)
+    .await
+    .map_err(database_error)?;
+    row
+      .map(|row| cipher.open_kv_row(&namespace, row))
+      .transpose()
+      .map_err(database_error)
+  }
+
+  /// Get the entries of every key that exists, ordered by key.
+  #[napi]
+  pub async fn kv_get_many(&self, namespace: String, keys: Vec<String>) -> Result<Vec<BlobRow>> {
+    let cipher = self.cipher.read().await;
+    let mut transaction = self.pool.begin().await.map_err(database_error)?;
+    let mut rows = Vec::with_capacity(keys.len());
+    for key in keys {
+      let row = sqlx::query_as!(
+        BlobRow,
+        "SELECT key, data, timestamp FROM kv WHERE namespace = ? AND key = ?",
+        namespace,
+        key
+      )
+      .fetch_optional(&mut *transaction)
+      .await
+      .map_err(database_error)?;
+      if let Some(row) = row {
+        rows.push(
+          cipher
+            .open_kv_row(&namespace, row)
+            .map_err(database_error)?,
+        );
+      }
+    }
+    transaction.commit().await.map_err(database_error)?;
+    rows.sort_by(|a, b| a.key.cmp(&b.key));
+    rows.dedup_by(|a, b| a.key == b.key);
+    Ok(rows)
+  }
+
+  /// Get every entry whose key starts with `prefix`, ordered by key.
+  #[napi]
+  pub async fn kv_scan(&self, namespace: String, prefix: Option<String>) -> Result<Vec<BlobRow>> {
+    let cipher = self.cipher.read().await;
+    let prefix = prefix.unwrap_or_default();
+    let end = prefix_end(&prefix);
+    let rows = sqlx::query_as!(
+      BlobRow,
+      "SELECT key, data, timestamp FROM kv WHERE namespace = $1 AND key >= $2 AND ($3 IS NULL OR key < $3) ORDER BY key",
+      namespace,
+      prefix,
+      end
+    )
+    .fetch_all(&self.pool)
+    .await
+    .map_err(database_error)?;
+    rows
+      .into_iter()
+      .map(|row| cipher.open_kv_row(&namespace, row))
+      .collect::<anyhow::Result<Vec<_>>>()
+      .map_err(database_error)
+  }
+
+  /// List the keys starting with `prefix` without loading their values.
+  #[napi]
+  pub async fn kv_keys(&self, namespace: String, prefix: Option<String>) -> Result<Vec<String>> {
+    let prefix = prefix.unwrap_or_default();
+    let end = prefix_end(&prefix);
+    let keys = sqlx::query!(
+      "SELECT key FROM kv WHERE namespace = $1 AND key >= $2 AND ($3 IS NULL OR key < $3) ORDER BY key",
+      namespace,
+      prefix,
+      end
+    )
+    .fetch_all(&self.pool)
+    .await
+    .map(|rows| rows.into_iter().map(|row| row.key).collect())
+    .map_err(database_error)?;
+    Ok(keys)
+  }
+
+  #[napi]
+  pub async fn kv_set(&self, namespace: String, key: String, data: Uint8Array) -> Result<()> {
+    self
+      .kv_set_many(namespace, vec![KvEntry { key, data }])
+      .await
+  }
+
+  /// Set every entry in a single transaction.
+  #[napi]
+  pub async fn kv_set_many(&self, namespace: String, entries: Vec<KvEntry>) -> Result<()> {
+    let cipher = self.cipher.read().await;
+    let mut transaction = self.pool.begin().await.map_err(database_error)?;
+    let mut events = Vec::with_capacity(entries.len());
+    for KvEntry { key, data } in entries {
+      let data = cipher
+        .seal(data.as_ref(), &RowIdentity::kv(&namespace, &key))
+        .map_err(database_error)?;
+      let data = data.as_ref();
+      sqlx::query!(
+        "INSERT INTO kv (namespace, key, data) VALUES ($1, $2, $3) ON CONFLICT(namespace, key) DO UPDATE SET data = excluded.data",
+        namespace,
+        key,
+        data
+      )
+      .execute(&mut *transaction)
+      .await
+      .map_err(database_error)?;
+      events.push(ChangeEvent::keyed(&namespace, Some(key)));
+    }
+    transaction.commit().await.map_err(database_error)?;
+    self.notify(events);
+    Ok(())
+  }
+
+  #[napi]
+  pub async fn kv_delete(&self, namespace: String, key: String) -> Result<()> {
+    self.kv_delete_many(namespace, vec![key]).await
+  }
+
+  /// Delete every key in a single transaction.
+  #[napi]
+  pub async fn kv_delete_many(&self, namespace: String, keys: Vec<String>) -> Result<()> {
+    let mut transaction = self.pool.begin().await.map_err(database_error)?;
+    for key in &keys {
+      sqlx::query!(
+        "DELETE FROM kv WHERE namespace = ? AND key = ?",
+        namespace,
+        key
+      )
+      .execute(&mut *transaction)
+      .await
+      .map_err(database_error)?;
+    }
+    transaction.commit().await.map_err(database_error)?;
+    self.notify(
+      keys
+        .into_iter()
+        .map(|key| ChangeEvent::keyed(&namespace, Some(key)))
+        .collect(),
+    );
+    Ok(())
+  }
+
+  /// Delete every entry of a namespace.
+  #[napi]
+  pub async fn kv_clear(&self, namespace: String) -> Result<()> {
+    sqlx::query!("DELETE FROM kv WHERE namespace = ?", namespace)
+      .execute(&self.pool)
+      .await
+      .map_err(database_error)?;
+    self.notify(vec![ChangeEvent::keyed(&namespace, None)]);
+    Ok(())
+  }
+
+  /// Set `key` to `data` only if its current value is `expected`. A missing
+  /// `expected` matches a missing key, a missing `data` deletes the key.
+  /// Returns whether the value was changed.
+  #[napi]
+  pub async fn kv_compare_and_set(
+    &self,
+    namespace: String,
+    key: String,
+    expected: Option<Uint8Array>,
+    data: Option<Uint8Array>,
+  ) -> Result<bool> {
+    let cipher = self.cipher.read().await;
+    // takes the write lock before reading the current value, a concurrent
+    // write waits instead of changing it in between
+    let mut transaction = ImmediateTransaction::begin(&self.pool)
+      .await
+      .map_err(database_error)?;
+    let current = sqlx::query!(
+      "SELECT data FROM kv WHERE namespace = ? AND key = ?",
+      namespace,
+      key
+    )
+    .fetch_optional(&mut *transaction)
+    .await
+    .map_err(database_error)?
+    .map(|row| {
+      cipher
+        .open(&row.data, &RowIdentity::kv(&namespace, &key))
+        .map(|data| data.into_owned())
+    })
+    .transpose()
+    .map_err(database_error)?;
+    if current.as_deref() != expected.as_ref().map(|expected| expected.as_ref()) {
+      return Ok(false);
+    }
+
+    match data {
+      Some(data) => {
+        let data = cipher
+          .seal(data.as_ref(), &RowIdentity::kv(&namespace, &key))
+          .map_err(database_error)?;
+        let data = data.as_ref();
+        sqlx::query!(
+          "INSERT INTO kv (namespace, key, data) VALUES ($1, $2, $3) ON CONFLICT(namespace, key) DO UPDATE SET data = excluded.data",
+          namespace,
+          key,
+          data
+        )
+        .execute(&mut *transaction)
+        .await
+        .map_err(database_error)?;
+      }
+      None => {
+        sqlx::query!(
+          "DELETE FROM kv WHERE namespace = ? AND key = ?",
+          namespace,
+          key
+        )
+        .execute(&mut *transaction)
+        .await
+        .map_err(database_error)?;
+      }
+    }
+    transaction.commit().await.map_err(database_error)?;
+    self.notify(vec![ChangeEvent::keyed(&namespace, Some(key))]);
+    Ok(true)
+  }
+}
@@ -0,0 +1,355 @@
+use std::{
+  collections::HashMap,
+  mem::Discriminant,
+  path::{Path, PathBuf},
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+  },
+  time::{Duration, Instant},
+};
+
+use napi_derive::napi;
+use tokio::sync::{Mutex, OnceCell};
+
+use super::{
+  error::{database_error, Result},
+  options::MEMORY_PATH,
+  ConnectionOptions, JournalMode, SqliteConnection, SynchronousMode,
+};
+
+/// Default time a workspace without references stays open, in seconds.
+const DEFAULT_IDLE_TIMEOUT: u32 = 60;
+
+/// Default limit of file handles held by all workspaces together.
+const DEFAULT_MAX_OPEN_FILES: u32 = 128;
+
+/// Default `max_connections` of a pool, see `ConnectionOptions`.
+const DEFAULT_MAX_CONNECTIONS: u32 = 4;
+
+#[napi(object)]
+#[derive(Default)]
+pub struct ConnectionManagerOptions {
+  /// Seconds a workspace stays open after its last reference is released,
+  /// defaults to 60.
+  pub idle_timeout: Option<u32>,
+  /// Most file handles held by all workspaces together. A workspace counts
+  /// with two for the file and its WAL for each of its `max_connections` and
+  /// for the connection polling for subscriptions, and one for the shared
+  /// WAL index. The least recently used idle workspaces are closed to make
+  /// room, defaults to 128.
+  pub max_open_files: Option<u32>,
+}
+
+/// Options a workspace was opened with, every caller sharing its connection
+/// has to ask for the same.
+#[derive(PartialEq)]
+struct OpenedWith {
+  journal_mode: Option<Discriminant<JournalMode>>,
+  synchronous: Option<Discriminant<SynchronousMode>>,
+  busy_timeout: Option<u32>,
+  max_connections: Option<u32>,
+  read_only: Option<bool>,
+  compaction_threshold: Option<u32>,
+  encryption_key: Option<Vec<u8>>,
+}
+
+impl OpenedWith {
+  fn new(options: &ConnectionOptions) -> Self {
+    Self {
+      journal_mode: options.journal_mode.as_ref().map(std::mem::discriminant),
+      synchronous: options.synchronous.as_ref().map(std::mem::discriminant),
+      busy_timeout: options.busy_timeout,
+      max_connections: options.max_connections,
+      read_only: options.read_only,
+      compaction_threshold: options.compaction_threshold,
+      encryption_key: options.encryption_key.as_ref().map(|key| key.to_vec()),
+    }
+  }
+}
+
+struct Entry {
+  /// Set by the first caller once connected, callers opening the same file
+  /// in the meantime wait for it without holding the lock of every entry.
+  connection: Arc<OnceCell<SqliteConnection>>,
+  opened_with: OpenedWith,
+  references: u32,
+  last_used: Instant,
+  /// File handles the pool may hold.
+  handles: u32,
+}
+
+type Entries = Arc<Mutex<HashMap<PathBuf, Entry>>>;
+
+/// Shares one connection per workspace file between every caller, and
+/// closes the connections nobody uses anymore.
+#[napi]
+pub struct ConnectionManager {
+  entries: Entries,
+  idle_timeout: Duration,
+  max_open_files: u32,
+}
+
+/// A reference taken by `ConnectionManager::open`, closing the connection it
+/// was returned with gives it back instead.
+pub(crate) struct Lease {
+  entries: Entries,
+  path: PathBuf,
+  idle_timeout: Duration,
+  released: AtomicBool,
+}
+
+impl Lease {
+  /// Give the reference back, does nothing if it already is.
+  pub(crate) async fn release(&self) -> Result<()> {
+    if self.released.swap(true, Ordering::AcqRel) {
+      return Ok(());
+    }
+    release(&self.entries, &self.path, self.idle_timeout).await
+  }
+}
+
+/// File handles a workspace may hold: the file and its WAL for each of the
+/// `max_connections` and for the connection polling for subscriptions, and
+/// the shared WAL index of the file.
+fn file_handles(max_connections: u32) -> u32 {
+  (max_connections + 1) * 2 + 1
+}
+
+/// Resolve symlinks and relative parts, so every spelling of a path maps to
+/// the same connection. Files that do not exist yet are resolved through
+/// their directory.
+fn canonical_path(path: &str) -> PathBuf {
+  let path = Path::new(path);
+  if let Ok(path) = path.canonicalize() {
+    return path;
+  }
+  match (path.parent(), path.file_name()) {
+    (Some(parent), Some(name)) => parent
+      .canonicalize()
+      .map(|parent| parent.join(name))
+      .unwrap_or_else(|_| path.to_path_buf()),
+    _ => path.to_path_buf(),
+  }
+}
+
+/// Close the connections of removed entries, outside of the lock so opening
+/// other workspaces does not wait for them.
+async fn close_entries(removed: Vec<Entry>) {
+  for entry in removed {
+    if let Some(connection) = entry.connection.get() {
+      connection.shutdown().await;
+    }
+  }
+}
+
+/// Close the workspaces released at least `idle_timeout` ago, returns how
+/// many were closed.
+async fn close_expired(entries: &Entries, idle_timeout: Duration) -> u32 {
+  let expired = {
+    let mut entries = entries.lock().await;
+    let expired = entries
+      .iter()
+      .filter(|(_, entry)| entry.references == 0 && entry.last_used.elapsed() >= idle_timeout)
+      .map(|(path, _)| path.clone())
+      .collect::<Vec<_>>();
+    expired
+      .iter()
+      .filter_map(|path| entries.remove(path))
+      .collect::<Vec<_>>()
+  };
+  let closed = expired.len() as u32;
+  close_entries(expired).await;
+  closed
+}
+
+async fn release(entries: &Entries, path: &Path, idle_timeout: Duration) -> Result<()> {
+  let mut locked = entries.lock().await;
+  let Some(entry) = locked.get_mut(path).filter(|entry| entry.references > 0) else {
+    return Err(database_error(anyhow::anyhow!(
+      "Workspace {} is not open",
+      path.display()
+    )));
+  };
+  entry.references -= 1;
+  entry.last_used = Instant::now();
+  if entry.references == 0 {
+    let entries = entries.clone();
+    tokio::spawn(async move {
+      tokio::time::sleep(idle_timeout).await;
+      close_expired(&entries, idle_timeout).await;
+    });
+  }
+  Ok(())
+}
+
+#[napi]
+impl ConnectionManager {
+  #[napi(constructor)]
+  pub fn new(options: Option<ConnectionManagerOptions>) -> Self {
+    let options = options.unwrap_or_default();
+    Self {
+      entries: Default::default(),
+      idle_timeout: Duration::from_secs(
+        options.idle_timeout.unwrap_or(DEFAULT_IDLE_TIMEOUT).into(),
+      ),
+      max_open_files: options.max_open_files.unwrap_or(DEFAULT_MAX_OPEN_FILES),
+    }
+  }
+
+  /// Connect to a workspace, or share the connection already open for the
+  /// same file. Rejects if the file is open with other `options`. Every call
+  /// has to be paired with `close` of the returned connection, which gives it
+  /// back to the manager. In-memory workspaces are never shared, every call
+  /// gets one of its own that is gone once closed.
+  #[napi]
+  pub async fn open(
+    &self,
+    path: String,
+    options: Option<ConnectionOptions>,
+  ) -> Result<SqliteConnection> {
+    if path == MEMORY_PATH {
+      let connection = SqliteConnection::new(path, options)?;
+      connection.connect().await?;
+      return Ok(connection);
+    }
+    let path = canonical_path(&path);
+    let opened_with = OpenedWith::new(options.as_ref().unwrap_or(&Default::default()));
+    let (cell, evicted) = {
+      let mut entries = self.entries.lock().await;
+      match entries.get_mut(&path) {
+        Some(entry) => {
+          if entry.opened_with != opened_with {
+            return Err(database_error(anyhow::anyhow!(
+              "Workspace {} is already open with other options",
+              path.display()
+            )));
+          }
+          entry.references += 1;
+          entry.last_used = Instant::now();
+          (entry.connection.clone(), Vec::new())
+        }
+        None => {
+          let handles = file_handles(
+            opened_with
+              .max_connections
+              .unwrap_or(DEFAULT_MAX_CONNECTIONS),
+          );
+          let evicted = self.make_room(&mut entries, &path, handles)?;
+          let cell = Arc::new(OnceCell::new());
+          entries.insert(
+            path.clone(),
+            Entry {
+              connection: cell.clone(),
+              opened_with,
+              references: 1,
+              last_used: Instant::now(),
+              handles,
+            },
+          );
+          (cell, evicted)
+        }
+      }
+    };
+    close_entries(evicted).await;
+
+    let file = path.to_string_lossy().into_owned();
+    let connected = cell
+      .get_or_try_init(|| async move {
+        let connection = SqliteConnection::new(file, options)?;
+        connection.connect().await?;
+        Ok(connection)
+      })
+      .await
+      .cloned();
+    match connected {
+      Ok(connection) => Ok(connection.leased(Lease {
+        entries: self.entries.clone(),
+        path,
+        idle_timeout: self.idle_timeout,
+        released: AtomicBool::new(false),
+      })),
+      Err(err) => {
+        let mut entries = self.entries.lock().await;
+        if let Some(entry) = entries
+          .get_mut(&path)
+          .filter(|entry| Arc::ptr_eq(&entry.connection, &cell))
+        {
+          entry.references -= 1;
+          // the next caller tries to connect again
+          if entry.references == 0 && !cell.initialized() {
+            entries.remove(&path);
+          }
+        }
+        Err(err)
+      }
+    }
+  }
+
+  /// Close every released workspace right away, returns how many were
+  /// closed.
+  #[napi]
+  pub async fn close_idle(&self) -> u32 {
+    close_expired(&self.entries, Duration::ZERO).await
+  }
+
+  /// Close every workspace, including the ones still in use.
+  #[napi]
+  pub async fn close_all(&self) {
+    let removed = {
+      let mut entries = self.entries.lock().await;
+      entries.drain().map(|(_, entry)| entry).collect()
+    };
+    close_entries(removed).await;
+  }
+
+  /// Paths of the open workspaces.
+  #[napi]
+  pub async fn open_paths(&self) -> Vec<String> {
+    let entries = self.entries.lock().await;
+    entries
+      .iter()
+      .filter(|(_, entry)| entry.connection.initialized())
+      .map(|(path, _)| path.to_string_lossy().into_owned())
+      .collect()
+  }
+}
+
+impl ConnectionManager {
+  /// Remove the least recently used idle workspaces until `handles` more fit
+  /// into `max_open_files`, returns the removed entries to close. Nothing is
+  /// removed if they do not fit even then.
+  fn make_room(
+    &self,
+    entries: &mut HashMap<PathBuf, Entry>,
+    path: &Path,
+    handles: u32,
+  ) -> Result<Vec<Entry>> {
+    let mut open_files = entries.values().map(|entry| entry.handles).sum::<u32>();
+    let mut idle = entries
+      .iter()
+      .filter(|(_, entry)| entry.references == 0)
+      .map(|(path, entry)| (entry.last_used, path.clone(), entry.handles))
+      .collect::<Vec<_>>();
+    idle.sort_by_key(|(last_used, ..)| *last_used);
+    let mut idle = idle.into_iter();
+    let mut evicted = Vec::new();
+    while open_files + handles > self.max_open_files {
+      let Some((_, idle_path, idle_handles)) = idle.next() else {
+        return Err(database_error(anyhow::anyhow!(
+          "Can not open {} without exceeding {} open files, release other workspaces first",
+          path.display(),
+          self.max_open_files
+        )));
+      };
+      open_files -= idle_handles;
+      evicted.push(idle_path);
+    }
+    Ok(
+      evicted
+        .iter()
+        .filter_map(|path| entries.remove(path))
+        .collect(),
+    )
+  }
+}
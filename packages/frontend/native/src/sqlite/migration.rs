@@ -0,0 +1,500 @@
+use chrono::NaiveDateTime;
+use napi_derive::napi;
+use sqlx::Row;
+
+use super::{
+  error::{database_error, Result},
+  SqliteConnection,
+};
+
+/// A single step of a migration. Every step must be idempotent, so that files
+/// created before the registry existed can replay the whole history safely.
+enum MigrationStep {
+  Sql(&'static str),
+  AddColumn {
+    table: &'static str,
+    column: &'static str,
+    definition: &'static str,
+  },
+  /// Copy the rows of a `key`/`data` table into `kv` under `namespace`. The
+  /// table is kept for earlier releases but no longer written.
+  CopyIntoKv {
+    table: &'static str,
+    namespace: &'static str,
+  },
+}
+
+const VERSION_INFO_SCHEMA: &str = r#"CREATE TABLE IF NOT EXISTS "version_info" (
+  version NUMBER NOT NULL,
+  timestamp TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL
+)"#;
+
+/// Full-text index over `search_blocks`, kept in sync by triggers. Rows of
+/// `search_blocks` are only inserted and deleted, never updated.
+const SEARCH_INDEX_SCHEMA: &str = r#"CREATE VIRTUAL TABLE IF NOT EXISTS "search_index" USING fts5(
+  content,
+  content = 'search_blocks',
+  content_rowid = 'id',
+  tokenize = 'unicode61 remove_diacritics 2'
+)"#;
+
+struct Migration {
+  version: i32,
+  description: &'static str,
+  steps: &'static [MigrationStep],
+}
+
+/// All migrations of the workspace database, ordered by version.
+/// Append new migrations to the end, never modify released ones.
+const MIGRATIONS: &[Migration] = &[
+  Migration {
+    version: 1,
+    description: "create updates and blobs tables",
+    steps: &[
+      MigrationStep::Sql(
+        r#"CREATE TABLE IF NOT EXISTS "updates" (
+          id INTEGER PRIMARY KEY AUTOINCREMENT,
+          data BLOB NOT NULL,
+          timestamp TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL
+        )"#,
+      ),
+      MigrationStep::Sql(
+        r#"CREATE TABLE IF NOT EXISTS "blobs" (
+          key TEXT PRIMARY KEY NOT NULL,
+          data BLOB NOT NULL,
+          timestamp TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL
+        )"#,
+      ),
+    ],
+  },
+  Migration {
+    version: 2,
+    description: "add doc_id column to updates",
+    steps: &[MigrationStep::AddColumn {
+      table: "updates",
+      column: "doc_id",
+      definition: "TEXT",
+    }],
+  },
+  Migration {
+    version: 3,
+    description: "create version_info table",
+    steps: &[MigrationStep::Sql(VERSION_INFO_SCHEMA)],
+  },
+  Migration {
+    version: 4,
+    description: "create sync tables and doc_id index",
+    steps: &[
+      MigrationStep::Sql(
+        r#"CREATE TABLE IF NOT EXISTS "server_clock" (
+          key TEXT PRIMARY KEY NOT NULL,
+          data BLOB NOT NULL,
+          timestamp TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL
+        )"#,
+      ),
+      MigrationStep::Sql(
+        r#"CREATE TABLE IF NOT EXISTS "sync_metadata" (
+          key TEXT PRIMARY KEY NOT NULL,
+          data BLOB NOT NULL,
+          timestamp TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL
+        )"#,
+      ),
+      MigrationStep::Sql("CREATE INDEX IF NOT EXISTS idx_doc_id ON updates(doc_id)"),
+    ],
+  },
+  Migration {
+    version: 5,
+    description: "create blob_uploads table",
+    steps: &[MigrationStep::Sql(
+      r#"CREATE TABLE IF NOT EXISTS "blob_uploads" (
+        upload_id TEXT NOT NULL,
+        seq INTEGER NOT NULL,
+        data BLOB NOT NULL,
+        timestamp TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+        PRIMARY KEY (upload_id, seq)
+      )"#,
+    )],
+  },
+  Migration {
+    version: 6,
+    description: "create quarantine table",
+    steps: &[MigrationStep::Sql(
+      r#"CREATE TABLE IF NOT EXISTS "quarantine" (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        source TEXT NOT NULL,
+        reason TEXT NOT NULL,
+        row_id INTEGER,
+        key TEXT,
+        doc_id TEXT,
+        data BLOB NOT NULL,
+        timestamp TIMESTAMP NOT NULL,
+        quarantined_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL
+      )"#,
+    )],
+  },
+  Migration {
+    version: 7,
+    description: "create encryption_info table",
+    steps: &[MigrationStep::Sql(
+      r#"CREATE TABLE IF NOT EXISTS "encryption_info" (
+        id INTEGER PRIMARY KEY CHECK (id = 1),
+        key_check BLOB NOT NULL,
+        timestamp TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL
+      )"#,
+    )],
+  },
+  Migration {
+    version: 8,
+    description: "create doc_tombstones table",
+    steps: &[MigrationStep::Sql(
+      r#"CREATE TABLE IF NOT EXISTS "doc_tombstones" (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        doc_id TEXT NOT NULL UNIQUE,
+        deleted_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+        purged_at TIMESTAMP
+      )"#,
+    )],
+  },
+  Migration {
+    version: 9,
+    description: "create search index",
+    steps: &[
+      MigrationStep::Sql(
+        r#"CREATE TABLE IF NOT EXISTS "search_blocks" (
+          id INTEGER PRIMARY KEY AUTOINCREMENT,
+          doc_id TEXT NOT NULL,
+          block_id TEXT NOT NULL,
+          content TEXT NOT NULL
+        )"#,
+      ),
+      MigrationStep::Sql(
+        "CREATE INDEX IF NOT EXISTS idx_search_blocks_doc_id ON search_blocks(doc_id)",
+      ),
+      MigrationStep::Sql(SEARCH_INDEX_SCHEMA),
+      MigrationStep::Sql(
+        r#"CREATE TRIGGER IF NOT EXISTS search_blocks_insert AFTER INSERT ON search_blocks BEGIN
+          INSERT INTO search_index (rowid, content) VALUES (new.id, new.content);
+        END"#,
+      ),
+      MigrationStep::Sql(
+        r#"CREATE TRIGGER IF NOT EXISTS search_blocks_delete AFTER DELETE ON search_blocks BEGIN
+          INSERT INTO search_index (search_index, rowid, content) VALUES ('delete', old.id, old.content);
+        END"#,
+      ),
+    ],
+  },
+  Migration {
+    version: 10,
+    description: "create snapshots table",
+    steps: &[MigrationStep::Sql(
+      r#"CREATE TABLE IF NOT EXISTS "snapshots" (
+        doc_id TEXT UNIQUE,
+        data BLOB NOT NULL,
+        state_vector BLOB NOT NULL,
+        last_update_id INTEGER NOT NULL,
+        updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL
+      )"#,
+    )],
+  },
+  Migration {
+    version: 11,
+    description: "copy sync tables into kv table",
+    // the sync tables stay in place, so that a downgraded release still finds
+    // them. It reads the clocks and metadata as they were at the upgrade and
+    // resyncs from there, what was written after the upgrade is only in `kv`.
+    steps: &[
+      MigrationStep::Sql(
+        r#"CREATE TABLE IF NOT EXISTS "kv" (
+          namespace TEXT NOT NULL,
+          key TEXT NOT NULL,
+          data BLOB NOT NULL,
+          timestamp TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+          PRIMARY KEY (namespace, key)
+        )"#,
+      ),
+      MigrationStep::CopyIntoKv {
+        table: "server_clock",
+        namespace: "server_clock",
+      },
+      MigrationStep::CopyIntoKv {
+        table: "sync_metadata",
+        namespace: "sync_metadata",
+      },
+    ],
+  },
+  Migration {
+    version: 12,
+    description: "add size and mime columns to blobs",
+    steps: &[
+      MigrationStep::AddColumn {
+        table: "blobs",
+        column: "size",
+        definition: "INTEGER",
+      },
+      MigrationStep::AddColumn {
+        table: "blobs",
+        column: "mime",
+        definition: "TEXT",
+      },
+    ],
+  },
+  Migration {
+    version: 13,
+    description: "create change_log table",
+    // every changed row logs one row, subscribed or not, since other
+    // processes may subscribe to the file at any time; deleting many rows,
+    // e.g. clearing a namespace, logs as many
+    steps: &[
+      MigrationStep::Sql(
+        r#"CREATE TABLE IF NOT EXISTS "change_log" (
+          id INTEGER PRIMARY KEY AUTOINCREMENT,
+          source TEXT NOT NULL,
+          doc_id TEXT,
+          key TEXT,
+          row_id INTEGER
+        )"#,
+      ),
+      // only the latest rows are kept, a connection that falls that far behind
+      // reloads everything; trimmed every 1000 rows instead of on every write
+      MigrationStep::Sql(
+        r#"CREATE TRIGGER IF NOT EXISTS "change_log_trim" AFTER INSERT ON "change_log"
+        WHEN new.id % 1000 = 0 BEGIN
+          DELETE FROM change_log WHERE id <= new.id - 10000;
+        END"#,
+      ),
+      MigrationStep::Sql(
+        r#"CREATE TRIGGER IF NOT EXISTS "updates_insert_log" AFTER INSERT ON "updates" BEGIN
+          INSERT INTO change_log (source, doc_id, key, row_id) VALUES ('updates', new.doc_id, NULL, new.id);
+        END"#,
+      ),
+      MigrationStep::Sql(
+        r#"CREATE TRIGGER IF NOT EXISTS "updates_delete_log" AFTER DELETE ON "updates" BEGIN
+          INSERT INTO change_log (source, doc_id, key, row_id) VALUES ('updates', old.doc_id, NULL, NULL);
+        END"#,
+      ),
+      MigrationStep::Sql(
+        r#"CREATE TRIGGER IF NOT EXISTS "blobs_insert_log" AFTER INSERT ON "blobs" BEGIN
+          INSERT INTO change_log (source, doc_id, key, row_id) VALUES ('blobs', NULL, new.key, NULL);
+        END"#,
+      ),
+      MigrationStep::Sql(
+        r#"CREATE TRIGGER IF NOT EXISTS "blobs_update_log" AFTER UPDATE ON "blobs" BEGIN
+          INSERT INTO change_log (source, doc_id, key, row_id) VALUES ('blobs', NULL, new.key, NULL);
+        END"#,
+      ),
+      MigrationStep::Sql(
+        r#"CREATE TRIGGER IF NOT EXISTS "blobs_delete_log" AFTER DELETE ON "blobs" BEGIN
+          INSERT INTO change_log (source, doc_id, key, row_id) VALUES ('blobs', NULL, old.key, NULL);
+        END"#,
+      ),
+      MigrationStep::Sql(
+        r#"CREATE TRIGGER IF NOT EXISTS "kv_insert_log" AFTER INSERT ON "kv" BEGIN
+          INSERT INTO change_log (source, doc_id, key, row_id) VALUES (new.namespace, NULL, new.key, NULL);
+        END"#,
+      ),
+      MigrationStep::Sql(
+        r#"CREATE TRIGGER IF NOT EXISTS "kv_update_log" AFTER UPDATE ON "kv" BEGIN
+          INSERT INTO change_log (source, doc_id, key, row_id) VALUES (new.namespace, NULL, new.key, NULL);
+        END"#,
+      ),
+      MigrationStep::Sql(
+        r#"CREATE TRIGGER IF NOT EXISTS "kv_delete_log" AFTER DELETE ON "kv" BEGIN
+          INSERT INTO change_log (source, doc_id, key, row_id) VALUES (old.namespace, NULL, old.key, NULL);
+        END"#,
+      ),
+      MigrationStep::Sql(
+        r#"CREATE TRIGGER IF NOT EXISTS "doc_tombstones_insert_log" AFTER INSERT ON "doc_tombstones" BEGIN
+          INSERT INTO change_log (source, doc_id, key, row_id) VALUES ('doc_tombstones', new.doc_id, NULL, new.id);
+        END"#,
+      ),
+      MigrationStep::Sql(
+        r#"CREATE TRIGGER IF NOT EXISTS "doc_tombstones_update_log" AFTER UPDATE ON "doc_tombstones" BEGIN
+          INSERT INTO change_log (source, doc_id, key, row_id) VALUES ('doc_tombstones', new.doc_id, NULL, new.id);
+        END"#,
+      ),
+      MigrationStep::Sql(
+        r#"CREATE TRIGGER IF NOT EXISTS "doc_tombstones_delete_log" AFTER DELETE ON "doc_tombstones" BEGIN
+          INSERT INTO change_log (source, doc_id, key, row_id) VALUES ('doc_tombstones', old.doc_id, NULL, NULL);
+        END"#,
+      ),
+    ],
+  },
+  Migration {
+    version: 14,
+    description: "add restored_at column to doc_tombstones",
+    steps: &[MigrationStep::AddColumn {
+      table: "doc_tombstones",
+      column: "restored_at",
+      definition: "TIMESTAMP",
+    }],
+  },
+  Migration {
+    version: 15,
+    description: "add merged_from column to updates",
+    steps: &[MigrationStep::AddColumn {
+      table: "updates",
+      column: "merged_from",
+      definition: "TIMESTAMP",
+    }],
+  },
+  Migration {
+    version: 16,
+    description: "index updates by doc_id and timestamp",
+    steps: &[
+      MigrationStep::Sql(
+        "CREATE INDEX IF NOT EXISTS idx_doc_id_timestamp ON updates(doc_id, timestamp)",
+      ),
+      // covered by the new index
+      MigrationStep::Sql("DROP INDEX IF EXISTS idx_doc_id"),
+    ],
+  },
+];
+
+/// latest version
+pub(crate) const LATEST_VERSION: i32 = MIGRATIONS[MIGRATIONS.len() - 1].version;
+
+#[napi(object)]
+pub struct MigrationRecord {
+  pub version: i32,
+  pub description: String,
+  pub timestamp: NaiveDateTime,
+}
+
+fn describe(version: i32) -> String {
+  MIGRATIONS
+    .iter()
+    .find(|migration| migration.version == version)
+    .map(|migration| migration.description.to_string())
+    .unwrap_or_else(|| "unknown".to_string())
+}
+
+pub(crate) async fn column_exists(
+  connection: &mut sqlx::SqliteConnection,
+  table: &str,
+  column: &str,
+) -> sqlx::Result<bool> {
+  let count: i64 = sqlx::query("SELECT COUNT(*) FROM pragma_table_info(?) WHERE name = ?")
+    .bind(table)
+    .bind(column)
+    .fetch_one(&mut *connection)
+    .await?
+    .get(0);
+  Ok(count > 0)
+}
+
+async fn table_exists(connection: &mut sqlx::SqliteConnection, table: &str) -> sqlx::Result<bool> {
+  let count: i64 =
+    sqlx::query("SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = ?")
+      .bind(table)
+      .fetch_one(&mut *connection)
+      .await?
+      .get(0);
+  Ok(count > 0)
+}
+
+impl MigrationStep {
+  async fn run(&self, connection: &mut sqlx::SqliteConnection) -> sqlx::Result<()> {
+    match self {
+      MigrationStep::Sql(sql) => {
+        sqlx::query(sql).execute(&mut *connection).await?;
+      }
+      MigrationStep::AddColumn {
+        table,
+        column,
+        definition,
+      } => {
+        if !column_exists(connection, table, column).await? {
+          sqlx::query(&format!(
+            "ALTER TABLE {table} ADD COLUMN {column} {definition}"
+          ))
+          .execute(&mut *connection)
+          .await?;
+        }
+      }
+      MigrationStep::CopyIntoKv { table, namespace } => {
+        if table_exists(connection, table).await? {
+          sqlx::query(&format!(
+            "INSERT OR IGNORE INTO kv (namespace, key, data, timestamp) SELECT ?, key, data, timestamp FROM {table}"
+          ))
+          .bind(namespace)
+          .execute(&mut *connection)
+          .await?;
+        }
+      }
+    }
+    Ok(())
+  }
+}
+
+#[napi]
+impl SqliteConnection {
+  /// Run every pending migration in order, each one in its own transaction.
+  /// Returns the migrations applied by this call.
+  #[napi]
+  pub async fn migrate(&self) -> Result<Vec<MigrationRecord>> {
+    // versions are recorded in `version_info`, so it has to exist before the
+    // first migration runs
+    sqlx::query(VERSION_INFO_SCHEMA)
+      .execute(&self.pool)
+      .await
+      .map_err(database_error)?;
+    let current: i64 = sqlx::query("SELECT COALESCE(MAX(version), 0) FROM version_info")
+      .fetch_one(&self.pool)
+      .await
+      .map_err(database_error)?
+      .get(0);
+
+    if current > LATEST_VERSION as i64 {
+      return Err(database_error(anyhow::anyhow!(
+        "Workspace version {current} is newer than the latest supported version {LATEST_VERSION}"
+      )));
+    }
+
+    let mut applied = Vec::new();
+    for migration in MIGRATIONS
+      .iter()
+      .filter(|migration| migration.version as i64 > current)
+    {
+      let mut transaction = self.pool.begin().await.map_err(database_error)?;
+      for step in migration.steps {
+        step.run(&mut transaction).await.map_err(database_error)?;
+      }
+      let timestamp: NaiveDateTime =
+        sqlx::query("INSERT INTO version_info (version) VALUES (?) RETURNING timestamp")
+          .bind(migration.version)
+          .fetch_one(&mut *transaction)
+          .await
+          .map_err(database_error)?
+          .get(0);
+      transaction.commit().await.map_err(database_error)?;
+      applied.push(MigrationRecord {
+        version: migration.version,
+        description: migration.description.to_string(),
+        timestamp,
+      });
+    }
+    Ok(applied)
+  }
+
+  /// List every version recorded in `version_info`.
+  #[napi]
+  pub async fn get_migration_history(&self) -> Result<Vec<MigrationRecord>> {
+    let rows = sqlx::query("SELECT version, timestamp FROM version_info ORDER BY version")
+      .fetch_all(&self.pool)
+      .await
+      .map_err(database_error)?;
+    Ok(
+      rows
+        .into_iter()
+        .map(|row| {
+          let version: i32 = row.get(0);
+          MigrationRecord {
+            version,
+            description: describe(version),
+            timestamp: row.get(1),
+          }
+        })
+        .collect(),
+    )
+  }
+}
@@ -1,14 +1,73 @@
+use std::sync::Arc;
+
 use chrono::NaiveDateTime;
 use napi::bindgen_prelude::{Buffer, Uint8Array};
 use napi_derive::napi;
-use sqlx::{
-  migrate::MigrateDatabase,
-  sqlite::{Sqlite, SqliteConnectOptions, SqlitePoolOptions},
-  Pool, Row,
+use parking_lot::{Mutex, RwLock};
+use sqlx::{migrate::MigrateDatabase, sqlite::Sqlite, Pool};
+
+mod backup;
+mod blob;
+mod compaction;
+mod diff;
+mod doc;
+mod encryption;
+mod error;
+mod gc;
+mod history;
+mod immediate;
+mod integrity;
+mod kv;
+mod manager;
+mod migration;
+mod options;
+mod raw;
+mod search;
+mod snapshot;
+mod stats;
+mod subscription;
+mod tombstone;
+mod transaction;
+mod upgrade;
+mod validation;
+mod ydoc;
+
+#[cfg(test)]
+mod tests;
+
+pub use backup::BackupProgress;
+use blob::write_blob;
+pub use blob::{BlobMeta, BlobsPage, ListBlobsOptions};
+pub use compaction::{CompactionOptions, CompactionResult};
+pub use doc::DocMeta;
+pub use encryption::KeyRotationResult;
+use encryption::{Cipher, RowIdentity};
+pub use error::SqliteErrorCode;
+use error::{database_error, log_error, Result};
+pub use gc::{BlobGcOptions, BlobGcResult};
+pub use history::EditSession;
+pub use integrity::{IntegrityCheckOptions, IntegrityReport};
+pub use kv::KvEntry;
+use kv::{SERVER_CLOCK, SYNC_METADATA};
+use manager::Lease;
+pub use manager::{ConnectionManager, ConnectionManagerOptions};
+pub use migration::MigrationRecord;
+use migration::LATEST_VERSION;
+use options::MEMORY_PATH;
+pub use options::{ConnectionOptions, JournalMode, SynchronousMode};
+use search::IndexQueue;
+pub use search::{SearchOptions, SearchResult};
+pub use snapshot::{LoadDocOptions, LoadedDoc};
+pub use stats::{
+  BlobSize, DocSize, StorageStats, TableSize, VacuumActivity, VacuumOptions, VacuumProgress,
+  VacuumResult,
 };
-
-// latest version
-const LATEST_VERSION: i32 = 4;
+pub use subscription::ChangeEvent;
+use subscription::Subscriptions;
+pub use tombstone::{DocTombstone, PurgeDocsOptions};
+pub use transaction::SqliteTransaction;
+pub use upgrade::UpgradeResult;
+pub use validation::{TableRowCount, ValidationReport};
 
 #[napi(object)]
 pub struct BlobRow {
@@ -31,10 +90,44 @@ pub struct InsertRow {
   pub data: Uint8Array,
 }
 
+#[napi(object)]
+pub struct UpdatesPage {
+  pub updates: Vec<UpdateRow>,
+  /// id of the last row in this page, pass it as `after_id` to fetch the next
+  /// page. `None` if there are no more rows.
+  pub next_cursor: Option<i64>,
+}
+
+impl UpdatesPage {
+  fn new(updates: Vec<UpdateRow>, limit: u32) -> Self {
+    let next_cursor = if updates.len() as u32 >= limit {
+      updates.last().map(|row| row.id)
+    } else {
+      None
+    };
+    Self {
+      updates,
+      next_cursor,
+    }
+  }
+}
+
+/// Clones share the pool and the state of the original, see
+/// `ConnectionManager`.
 #[napi]
+#[derive(Clone)]
 pub struct SqliteConnection {
   pool: Pool<Sqlite>,
   path: String,
+  read_only: bool,
+  compaction_threshold: Arc<RwLock<Option<u32>>>,
+  /// Held for reading until every read or write of row payloads is done, so a
+  /// key rotation never interleaves with them.
+  cipher: Arc<tokio::sync::RwLock<Cipher>>,
+  subscriptions: Arc<Mutex<Subscriptions>>,
+  index_queue: Arc<Mutex<IndexQueue>>,
+  /// Set on connections returned by `ConnectionManager::open`.
+  lease: Option<Arc<Lease>>,
 }
 
 #[napi]
@@ -48,85 +141,112 @@ pub enum ValidationResult {
 
 #[napi]
 impl SqliteConnection {
+  /// Pass `:memory:` as `path` for a workspace that only lives in memory
+  /// until the connection is closed.
   #[napi(constructor)]
-  pub fn new(path: String) -> napi::Result<Self> {
-    let sqlite_options = SqliteConnectOptions::new()
-      .filename(&path)
-      .foreign_keys(false)
-      .journal_mode(sqlx::sqlite::SqliteJournalMode::Off);
-    let pool = SqlitePoolOptions::new()
-      .max_connections(4)
-      .connect_lazy_with(sqlite_options);
-    Ok(Self { pool, path })
+  pub fn new(path: String, options: Option<ConnectionOptions>) -> Result<Self> {
+    let options = options.unwrap_or_default();
+    let cipher = Cipher::new(options.encryption_key.as_ref().map(|key| key.as_ref()))
+      .map_err(database_error)?;
+    let pool = options
+      .to_pool_options(&path)
+      .connect_lazy_with(options.to_connect_options(&path));
+    Ok(Self {
+      pool,
+      path,
+      read_only: options.is_read_only(),
+      compaction_threshold: Arc::new(RwLock::new(options.compaction_threshold)),
+      cipher: Arc::new(tokio::sync::RwLock::new(cipher)),
+      subscriptions: Default::default(),
+      index_queue: Default::default(),
+      lease: None,
+    })
   }
 
   #[napi]
-  pub async fn connect(&self) -> napi::Result<()> {
-    if !Sqlite::database_exists(&self.path).await.unwrap_or(false) {
+  pub async fn connect(&self) -> Result<()> {
+    if self.read_only {
+      // fail early if the file is missing or unreadable
+      self.pool.acquire().await.map_err(database_error)?;
+      self.init_encryption().await.map_err(database_error)?;
+      return Ok(());
+    }
+    if self.path != MEMORY_PATH && !Sqlite::database_exists(&self.path).await.unwrap_or(false) {
       Sqlite::create_database(&self.path)
         .await
-        .map_err(anyhow::Error::from)?;
+        .map_err(database_error)?;
     };
-    let mut connection = self.pool.acquire().await.map_err(anyhow::Error::from)?;
-    sqlx::query(affine_schema::SCHEMA)
-      .execute(connection.as_mut())
-      .await
-      .map_err(anyhow::Error::from)?;
-    self.migrate_add_doc_id().await?;
-    self.migrate_add_doc_id_index().await?;
-    connection.detach();
+    self.migrate().await?;
+    self.init_encryption().await.map_err(database_error)?;
     Ok(())
   }
 
   #[napi]
-  pub async fn add_blob(&self, key: String, blob: Uint8Array) -> napi::Result<()> {
-    let blob = blob.as_ref();
-    sqlx::query_as!(
-      BlobRow,
-      "INSERT INTO blobs (key, data) VALUES ($1, $2) ON CONFLICT(key) DO UPDATE SET data = excluded.data",
-      key,
-      blob,
-    )
-    .execute(&self.pool)
-    .await
-    .map_err(anyhow::Error::from)?;
+  pub async fn add_blob(&self, key: String, blob: Uint8Array) -> Result<()> {
+    let cipher = self.cipher.read().await;
+    let mut connection = self.pool.acquire().await.map_err(database_error)?;
+    write_blob(&mut connection, &cipher, &key, blob.as_ref())
+      .await
+      .map_err(database_error)?;
+    self.notify(vec![ChangeEvent::keyed("blobs", Some(key))]);
     Ok(())
   }
 
+  /// `None` if the blob does not exist, rejects with a `SqliteErrorCode` if
+  /// it can not be read.
   #[napi]
-  pub async fn get_blob(&self, key: String) -> Option<BlobRow> {
-    sqlx::query_as!(
+  pub async fn get_blob(&self, key: String) -> Result<Option<BlobRow>> {
+    let cipher = self.cipher.read().await;
+    let row = sqlx::query_as!(
       BlobRow,
       "SELECT key, data, timestamp FROM blobs WHERE key = ?",
       key
     )
-    .fetch_one(&self.pool)
+    .fetch_optional(&self.pool)
     .await
-    .ok()
+    .map_err(database_error)?;
+    row
+      .map(|row| cipher.open_blob_row(row))
+      .transpose()
+      .map_err(database_error)
   }
 
   #[napi]
-  pub async fn delete_blob(&self, key: String) -> napi::Result<()> {
+  pub async fn delete_blob(&self, key: String) -> Result<()> {
     sqlx::query!("DELETE FROM blobs WHERE key = ?", key)
       .execute(&self.pool)
       .await
-      .map_err(anyhow::Error::from)?;
+      .map_err(database_error)?;
+    self.notify(vec![ChangeEvent::keyed("blobs", Some(key))]);
     Ok(())
   }
 
   #[napi]
-  pub async fn get_blob_keys(&self) -> napi::Result<Vec<String>> {
+  pub async fn get_blob_keys(&self) -> Result<Vec<String>> {
     let keys = sqlx::query!("SELECT key FROM blobs")
       .fetch_all(&self.pool)
       .await
       .map(|rows| rows.into_iter().map(|row| row.key).collect())
-      .map_err(anyhow::Error::from)?;
+      .map_err(database_error)?;
     Ok(keys)
   }
 
+  /// Get all updates of a doc, nothing is returned for a trashed doc unless
+  /// `include_trashed` is set.
   #[napi]
-  pub async fn get_updates(&self, doc_id: Option<String>) -> napi::Result<Vec<UpdateRow>> {
+  pub async fn get_updates(
+    &self,
+    doc_id: Option<String>,
+    include_trashed: Option<bool>,
+  ) -> Result<Vec<UpdateRow>> {
+    let cipher = self.cipher.read().await;
     let updates = match doc_id {
+      Some(doc_id)
+        if !include_trashed.unwrap_or(false)
+          && self.is_trashed(&doc_id).await.map_err(database_error)? =>
+      {
+        Vec::new()
+      }
       Some(doc_id) => sqlx::query_as!(
         UpdateRow,
         "SELECT id, timestamp, data, doc_id FROM updates WHERE doc_id = ?",
@@ -134,39 +254,135 @@ impl SqliteConnection {
       )
       .fetch_all(&self.pool)
       .await
-      .map_err(anyhow::Error::from)?,
+      .map_err(database_error)?,
       None => sqlx::query_as!(
         UpdateRow,
         "SELECT id, timestamp, data, doc_id FROM updates WHERE doc_id is NULL",
       )
       .fetch_all(&self.pool)
       .await
-      .map_err(anyhow::Error::from)?,
+      .map_err(database_error)?,
     };
-    Ok(updates)
+    cipher.open_update_rows(updates).map_err(database_error)
   }
 
+  /// Get at most `limit` updates of a doc with id greater than `after_id`,
+  /// ordered by id. Trashed docs are filtered like in `get_updates`.
   #[napi]
-  pub async fn delete_updates(&self, doc_id: Option<String>) -> napi::Result<()> {
-    match doc_id {
+  pub async fn get_updates_page(
+    &self,
+    doc_id: Option<String>,
+    after_id: Option<i64>,
+    limit: u32,
+    include_trashed: Option<bool>,
+  ) -> Result<UpdatesPage> {
+    let cipher = self.cipher.read().await;
+    let after_id = after_id.unwrap_or(0);
+    let limit_value = limit as i64;
+    let hidden = match &doc_id {
+      Some(doc_id) if !include_trashed.unwrap_or(false) => {
+        self.is_trashed(doc_id).await.map_err(database_error)?
+      }
+      _ => false,
+    };
+    let updates = match doc_id {
+      Some(_) if hidden => Vec::new(),
+      Some(doc_id) => sqlx::query_as!(
+        UpdateRow,
+        "SELECT id, timestamp, data, doc_id FROM updates WHERE doc_id = ? AND id > ? ORDER BY id LIMIT ?",
+        doc_id,
+        after_id,
+        limit_value
+      )
+      .fetch_all(&self.pool)
+      .await
+      .map_err(database_error)?,
+      None => sqlx::query_as!(
+        UpdateRow,
+        "SELECT id, timestamp, data, doc_id FROM updates WHERE doc_id is NULL AND id > ? ORDER BY id LIMIT ?",
+        after_id,
+        limit_value
+      )
+      .fetch_all(&self.pool)
+      .await
+      .map_err(database_error)?,
+    };
+    Ok(UpdatesPage::new(
+      cipher.open_update_rows(updates).map_err(database_error)?,
+      limit,
+    ))
+  }
+
+  /// Get all updates of a doc inserted after the update with `after_id`, used
+  /// to catch up incrementally. Trashed docs are filtered like in
+  /// `get_updates`.
+  #[napi]
+  pub async fn get_updates_since(
+    &self,
+    doc_id: Option<String>,
+    after_id: i64,
+    include_trashed: Option<bool>,
+  ) -> Result<Vec<UpdateRow>> {
+    let cipher = self.cipher.read().await;
+    let hidden = match &doc_id {
+      Some(doc_id) if !include_trashed.unwrap_or(false) => {
+        self.is_trashed(doc_id).await.map_err(database_error)?
+      }
+      _ => false,
+    };
+    let updates = match doc_id {
+      Some(_) if hidden => Vec::new(),
+      Some(doc_id) => sqlx::query_as!(
+        UpdateRow,
+        "SELECT id, timestamp, data, doc_id FROM updates WHERE doc_id = ? AND id > ? ORDER BY id",
+        doc_id,
+        after_id
+      )
+      .fetch_all(&self.pool)
+      .await
+      .map_err(database_error)?,
+      None => sqlx::query_as!(
+        UpdateRow,
+        "SELECT id, timestamp, data, doc_id FROM updates WHERE doc_id is NULL AND id > ? ORDER BY id",
+        after_id
+      )
+      .fetch_all(&self.pool)
+      .await
+      .map_err(database_error)?,
+    };
+    cipher.open_update_rows(updates).map_err(database_error)
+  }
+
+  /// Delete every update of a doc together with its snapshot.
+  #[napi]
+  pub async fn delete_updates(&self, doc_id: Option<String>) -> Result<()> {
+    let mut transaction = self.pool.begin().await.map_err(database_error)?;
+    match &doc_id {
       Some(doc_id) => {
         sqlx::query!("DELETE FROM updates WHERE doc_id = ?", doc_id)
-          .execute(&self.pool)
+          .execute(&mut *transaction)
           .await
-          .map_err(anyhow::Error::from)?;
+          .map_err(database_error)?;
       }
       None => {
         sqlx::query!("DELETE FROM updates WHERE doc_id is NULL")
-          .execute(&self.pool)
+          .execute(&mut *transaction)
           .await
-          .map_err(anyhow::Error::from)?;
+          .map_err(database_error)?;
       }
     };
+    sqlx::query!("DELETE FROM snapshots WHERE doc_id IS ?", doc_id)
+      .execute(&mut *transaction)
+      .await
+      .map_err(database_error)?;
+    transaction.commit().await.map_err(database_error)?;
+    self.schedule_index(doc_id.clone());
+    self.notify(vec![ChangeEvent::update(doc_id, None)]);
     Ok(())
   }
 
   #[napi]
-  pub async fn get_updates_count(&self, doc_id: Option<String>) -> napi::Result<i64> {
+  pub async fn get_updates_count(&self, doc_id: Option<String>) -> Result<i64> {
     let count = match doc_id {
       Some(doc_id) => {
         sqlx::query!(
@@ -175,14 +391,14 @@ impl SqliteConnection {
         )
         .fetch_one(&self.pool)
         .await
-        .map_err(anyhow::Error::from)?
+        .map_err(database_error)?
         .count
       }
       None => {
         sqlx::query!("SELECT COUNT(*) as count FROM updates WHERE doc_id is NULL")
           .fetch_one(&self.pool)
           .await
-          .map_err(anyhow::Error::from)?
+          .map_err(database_error)?
           .count
       }
     };
@@ -190,20 +406,52 @@ impl SqliteConnection {
   }
 
   #[napi]
-  pub async fn get_all_updates(&self) -> napi::Result<Vec<UpdateRow>> {
+  pub async fn get_all_updates(&self) -> Result<Vec<UpdateRow>> {
+    let cipher = self.cipher.read().await;
     let updates = sqlx::query_as!(UpdateRow, "SELECT id, timestamp, data, doc_id FROM updates")
       .fetch_all(&self.pool)
       .await
-      .map_err(anyhow::Error::from)?;
-    Ok(updates)
+      .map_err(database_error)?;
+    cipher.open_update_rows(updates).map_err(database_error)
+  }
+
+  /// Same as `get_updates_page` but across every doc in the workspace.
+  #[napi]
+  pub async fn get_all_updates_page(
+    &self,
+    after_id: Option<i64>,
+    limit: u32,
+  ) -> Result<UpdatesPage> {
+    let cipher = self.cipher.read().await;
+    let after_id = after_id.unwrap_or(0);
+    let limit_value = limit as i64;
+    let updates = sqlx::query_as!(
+      UpdateRow,
+      "SELECT id, timestamp, data, doc_id FROM updates WHERE id > ? ORDER BY id LIMIT ?",
+      after_id,
+      limit_value
+    )
+    .fetch_all(&self.pool)
+    .await
+    .map_err(database_error)?;
+    Ok(UpdatesPage::new(
+      cipher.open_update_rows(updates).map_err(database_error)?,
+      limit,
+    ))
   }
 
   #[napi]
-  pub async fn insert_updates(&self, updates: Vec<InsertRow>) -> napi::Result<()> {
-    let mut transaction = self.pool.begin().await.map_err(anyhow::Error::from)?;
+  pub async fn insert_updates(&self, updates: Vec<InsertRow>) -> Result<()> {
+    let cipher = self.cipher.read().await;
+    let mut transaction = self.pool.begin().await.map_err(database_error)?;
+    let mut doc_ids = Vec::new();
+    let mut events = Vec::new();
     for InsertRow { data, doc_id } in updates {
-      let update = data.as_ref();
-      sqlx::query_as!(
+      let update = cipher
+        .seal(data.as_ref(), &RowIdentity::update(doc_id.as_deref()))
+        .map_err(database_error)?;
+      let update = update.as_ref();
+      let id = sqlx::query_as!(
         UpdateRow,
         "INSERT INTO updates (data, doc_id) VALUES ($1, $2)",
         update,
@@ -211,34 +459,55 @@ impl SqliteConnection {
       )
       .execute(&mut *transaction)
       .await
-      .map_err(anyhow::Error::from)?;
+      .map_err(database_error)?
+      .last_insert_rowid();
+      events.push(ChangeEvent::update(doc_id.clone(), Some(id)));
+      if !doc_ids.contains(&doc_id) {
+        doc_ids.push(doc_id);
+      }
+    }
+    transaction.commit().await.map_err(database_error)?;
+    drop(cipher);
+    self.notify(events);
+    for doc_id in &doc_ids {
+      // the updates are stored, a failed compaction is retried by the next
+      // insert and must not make JS retry this one
+      self.compact_if_needed(doc_id.clone()).await.ok();
     }
-    transaction.commit().await.map_err(anyhow::Error::from)?;
+    self.schedule_index(doc_ids.into_iter().flatten());
     Ok(())
   }
 
+  /// Replace every update of a doc. The snapshot of the doc is kept and the
+  /// new updates are applied on top of it, `get_updates` does not return the
+  /// content merged into it.
   #[napi]
   pub async fn replace_updates(
     &self,
     doc_id: Option<String>,
     updates: Vec<InsertRow>,
-  ) -> napi::Result<()> {
-    let mut transaction = self.pool.begin().await.map_err(anyhow::Error::from)?;
+  ) -> Result<()> {
+    let cipher = self.cipher.read().await;
+    let mut transaction = self.pool.begin().await.map_err(database_error)?;
 
-    match doc_id {
+    match &doc_id {
       Some(doc_id) => sqlx::query!("DELETE FROM updates where doc_id = ?", doc_id)
         .execute(&mut *transaction)
         .await
-        .map_err(anyhow::Error::from)?,
+        .map_err(database_error)?,
       None => sqlx::query!("DELETE FROM updates where doc_id is NULL",)
         .execute(&mut *transaction)
         .await
-        .map_err(anyhow::Error::from)?,
+        .map_err(database_error)?,
     };
 
+    let mut events = vec![ChangeEvent::update(doc_id.clone(), None)];
     for InsertRow { data, doc_id } in updates {
-      let update = data.as_ref();
-      sqlx::query_as!(
+      let update = cipher
+        .seal(data.as_ref(), &RowIdentity::update(doc_id.as_deref()))
+        .map_err(database_error)?;
+      let update = update.as_ref();
+      let id = sqlx::query_as!(
         UpdateRow,
         "INSERT INTO updates (data, doc_id) VALUES ($1, $2)",
         update,
@@ -246,122 +515,70 @@ impl SqliteConnection {
       )
       .execute(&mut *transaction)
       .await
-      .map_err(anyhow::Error::from)?;
+      .map_err(database_error)?
+      .last_insert_rowid();
+      events.push(ChangeEvent::update(doc_id, Some(id)));
     }
-    transaction.commit().await.map_err(anyhow::Error::from)?;
+    transaction.commit().await.map_err(database_error)?;
+    drop(cipher);
+    self.schedule_index(doc_id);
+    self.notify(events);
     Ok(())
   }
 
   #[napi]
-  pub async fn get_server_clock(&self, key: String) -> Option<BlobRow> {
-    sqlx::query_as!(
-      BlobRow,
-      "SELECT key, data, timestamp FROM server_clock WHERE key = ?",
-      key
-    )
-    .fetch_one(&self.pool)
-    .await
-    .ok()
+  pub async fn get_server_clock(&self, key: String) -> Result<Option<BlobRow>> {
+    self.kv_get(SERVER_CLOCK.to_string(), key).await
   }
 
   #[napi]
-  pub async fn set_server_clock(&self, key: String, data: Uint8Array) -> napi::Result<()> {
-    let data = data.as_ref();
-    sqlx::query!(
-      "INSERT INTO server_clock (key, data) VALUES ($1, $2) ON CONFLICT(key) DO UPDATE SET data = excluded.data",
-      key,
-      data,
-    )
-    .execute(&self.pool)
-    .await
-    .map_err(anyhow::Error::from)?;
-    Ok(())
+  pub async fn set_server_clock(&self, key: String, data: Uint8Array) -> Result<()> {
+    self.kv_set(SERVER_CLOCK.to_string(), key, data).await
   }
 
   #[napi]
-  pub async fn get_server_clock_keys(&self) -> napi::Result<Vec<String>> {
-    let keys = sqlx::query!("SELECT key FROM server_clock")
-      .fetch_all(&self.pool)
-      .await
-      .map(|rows| rows.into_iter().map(|row| row.key).collect())
-      .map_err(anyhow::Error::from)?;
-    Ok(keys)
+  pub async fn get_server_clock_keys(&self) -> Result<Vec<String>> {
+    self.kv_keys(SERVER_CLOCK.to_string(), None).await
   }
 
   #[napi]
-  pub async fn clear_server_clock(&self) -> napi::Result<()> {
-    sqlx::query!("DELETE FROM server_clock")
-      .execute(&self.pool)
-      .await
-      .map_err(anyhow::Error::from)?;
-    Ok(())
+  pub async fn clear_server_clock(&self) -> Result<()> {
+    self.kv_clear(SERVER_CLOCK.to_string()).await
   }
 
   #[napi]
-  pub async fn del_server_clock(&self, key: String) -> napi::Result<()> {
-    sqlx::query!("DELETE FROM server_clock WHERE key = ?", key)
-      .execute(&self.pool)
-      .await
-      .map_err(anyhow::Error::from)?;
-    Ok(())
+  pub async fn del_server_clock(&self, key: String) -> Result<()> {
+    self.kv_delete(SERVER_CLOCK.to_string(), key).await
   }
 
   #[napi]
-  pub async fn get_sync_metadata(&self, key: String) -> Option<BlobRow> {
-    sqlx::query_as!(
-      BlobRow,
-      "SELECT key, data, timestamp FROM sync_metadata WHERE key = ?",
-      key
-    )
-    .fetch_one(&self.pool)
-    .await
-    .ok()
+  pub async fn get_sync_metadata(&self, key: String) -> Result<Option<BlobRow>> {
+    self.kv_get(SYNC_METADATA.to_string(), key).await
   }
 
   #[napi]
-  pub async fn set_sync_metadata(&self, key: String, data: Uint8Array) -> napi::Result<()> {
-    let data = data.as_ref();
-    sqlx::query!(
-      "INSERT INTO sync_metadata (key, data) VALUES ($1, $2) ON CONFLICT(key) DO UPDATE SET data = excluded.data",
-      key,
-      data,
-    )
-    .execute(&self.pool)
-    .await
-    .map_err(anyhow::Error::from)?;
-    Ok(())
+  pub async fn set_sync_metadata(&self, key: String, data: Uint8Array) -> Result<()> {
+    self.kv_set(SYNC_METADATA.to_string(), key, data).await
   }
 
   #[napi]
-  pub async fn get_sync_metadata_keys(&self) -> napi::Result<Vec<String>> {
-    let keys = sqlx::query!("SELECT key FROM sync_metadata")
-      .fetch_all(&self.pool)
-      .await
-      .map(|rows| rows.into_iter().map(|row| row.key).collect())
-      .map_err(anyhow::Error::from)?;
-    Ok(keys)
+  pub async fn get_sync_metadata_keys(&self) -> Result<Vec<String>> {
+    self.kv_keys(SYNC_METADATA.to_string(), None).await
   }
 
   #[napi]
-  pub async fn clear_sync_metadata(&self) -> napi::Result<()> {
-    sqlx::query!("DELETE FROM sync_metadata")
-      .execute(&self.pool)
-      .await
-      .map_err(anyhow::Error::from)?;
-    Ok(())
+  pub async fn clear_sync_metadata(&self) -> Result<()> {
+    self.kv_clear(SYNC_METADATA.to_string()).await
   }
 
   #[napi]
-  pub async fn del_sync_metadata(&self, key: String) -> napi::Result<()> {
-    sqlx::query!("DELETE FROM sync_metadata WHERE key = ?", key)
-      .execute(&self.pool)
-      .await
-      .map_err(anyhow::Error::from)?;
-    Ok(())
+  pub async fn del_sync_metadata(&self, key: String) -> Result<()> {
+    self.kv_delete(SYNC_METADATA.to_string(), key).await
   }
 
+  /// Superseded by `upgrade`, which also keeps a backup.
   #[napi]
-  pub async fn init_version(&self) -> napi::Result<()> {
+  pub async fn init_version(&self) -> Result<()> {
     // create version_info table
     sqlx::query!(
       "CREATE TABLE IF NOT EXISTS version_info (
@@ -371,41 +588,68 @@ impl SqliteConnection {
     )
     .execute(&self.pool)
     .await
-    .map_err(anyhow::Error::from)?;
+    .map_err(database_error)?;
     // `3` is the first version that has version_info table,
     //  do not modify the version number.
     sqlx::query!("INSERT INTO version_info (version) VALUES (3)")
       .execute(&self.pool)
       .await
-      .map_err(anyhow::Error::from)?;
+      .map_err(database_error)?;
     Ok(())
   }
 
+  /// Record `version` as a new row of the migration history, the earlier rows
+  /// are kept. Superseded by `migrate`, which records every version it
+  /// applies.
   #[napi]
-  pub async fn set_version(&self, version: i32) -> napi::Result<()> {
+  pub async fn set_version(&self, version: i32) -> Result<()> {
     if version > LATEST_VERSION {
-      return Err(anyhow::Error::msg("Version is too new").into());
+      return Err(database_error(anyhow::Error::msg("Version is too new")));
     }
-    sqlx::query!("UPDATE version_info SET version = ?", version)
+    sqlx::query!("INSERT INTO version_info (version) VALUES (?)", version)
       .execute(&self.pool)
       .await
-      .map_err(anyhow::Error::from)?;
+      .map_err(database_error)?;
     Ok(())
   }
 
   #[napi]
-  pub async fn get_max_version(&self) -> napi::Result<i64> {
-    // 4 is the current version
+  pub async fn get_max_version(&self) -> Result<i64> {
+    // files created before the migration history have no rows, 4 is the last
+    // version released without it
     let version = sqlx::query!("SELECT COALESCE(MAX(version), 4) AS max_version FROM version_info")
       .fetch_one(&self.pool)
       .await
-      .map_err(anyhow::Error::from)?
+      .map_err(database_error)?
       .max_version;
     Ok(version)
   }
 
+  /// Close the connection. One returned by `ConnectionManager::open` is
+  /// given back to the manager instead, which closes it once unused.
   #[napi]
   pub async fn close(&self) {
+    match &self.lease {
+      Some(lease) => {
+        if let Err(err) = lease.release().await {
+          log_error("failed to release the connection", err);
+        }
+      }
+      None => self.shutdown().await,
+    }
+  }
+
+  pub(crate) fn leased(&self, lease: Lease) -> Self {
+    Self {
+      lease: Some(Arc::new(lease)),
+      ..self.clone()
+    }
+  }
+
+  pub(crate) async fn shutdown(&self) {
+    self.subscriptions.lock().stop();
+    self.flush_index().await;
+    self.index_queue.lock().stop();
     self.pool.close().await;
   }
 
@@ -416,90 +660,22 @@ impl SqliteConnection {
 
   #[napi]
   pub async fn validate(path: String) -> ValidationResult {
-    let pool = match SqlitePoolOptions::new()
-      .max_connections(1)
-      .connect(&path)
-      .await
-    {
-      Ok(pool) => pool,
-      Err(_) => return ValidationResult::GeneralError,
-    };
-
-    let tables_res = sqlx::query("SELECT name FROM sqlite_master WHERE type='table'")
-      .fetch_all(&pool)
-      .await;
-
-    let tables_exist = match tables_res {
-      Ok(res) => {
-        let names: Vec<String> = res.iter().map(|row| row.get(0)).collect();
-        names.contains(&"updates".to_string()) && names.contains(&"blobs".to_string())
-      }
-      Err(_) => return ValidationResult::GeneralError,
-    };
-
-    let tables_res = sqlx::query("SELECT name FROM sqlite_master WHERE type='table'")
-      .fetch_all(&pool)
-      .await;
-
-    let version_exist = match tables_res {
-      Ok(res) => {
-        let names: Vec<String> = res.iter().map(|row| row.get(0)).collect();
-        names.contains(&"version_info".to_string())
-      }
-      Err(_) => return ValidationResult::GeneralError,
-    };
-
-    let columns_res = sqlx::query("PRAGMA table_info(updates)")
-      .fetch_all(&pool)
-      .await;
-
-    let doc_id_exist = match columns_res {
-      Ok(res) => {
-        let names: Vec<String> = res.iter().map(|row| row.get(1)).collect();
-        names.contains(&"doc_id".to_string())
-      }
-      Err(_) => return ValidationResult::GeneralError,
-    };
-
-    if !tables_exist {
-      ValidationResult::MissingTables
-    } else if !doc_id_exist {
-      ValidationResult::MissingDocIdColumn
-    } else if !version_exist {
-      ValidationResult::MissingVersionColumn
-    } else {
-      ValidationResult::Valid
-    }
+    Self::get_validation_report(path).await.result
   }
 
+  /// Superseded by `upgrade`, which also keeps a backup.
   #[napi]
-  pub async fn migrate_add_doc_id(&self) -> napi::Result<()> {
-    // ignore errors
-    match sqlx::query("ALTER TABLE updates ADD COLUMN doc_id TEXT")
-      .execute(&self.pool)
-      .await
-    {
-      Ok(_) => Ok(()),
-      Err(err) => {
-        if err.to_string().contains("duplicate column name") {
-          Ok(()) // Ignore error if it's due to duplicate column
-        } else {
-          Err(anyhow::Error::from(err).into()) // Propagate other errors
-        }
-      }
-    }
-  }
-
-  pub async fn migrate_add_doc_id_index(&self) -> napi::Result<()> {
-    // ignore errors
-    match sqlx::query("CREATE INDEX IF NOT EXISTS idx_doc_id ON updates(doc_id);")
-      .execute(&self.pool)
+  pub async fn migrate_add_doc_id(&self) -> Result<()> {
+    let mut connection = self.pool.acquire().await.map_err(database_error)?;
+    if !migration::column_exists(&mut connection, "updates", "doc_id")
       .await
+      .map_err(database_error)?
     {
-      Ok(_) => Ok(()),
-      Err(err) => {
-        Err(anyhow::Error::from(err).into()) // Propagate other errors
-      }
+      sqlx::query("ALTER TABLE updates ADD COLUMN doc_id TEXT")
+        .execute(connection.as_mut())
+        .await
+        .map_err(database_error)?;
     }
+    Ok(())
   }
 }
@@ -0,0 +1,170 @@
+use std::{path::Path, str::FromStr, time::Duration};
+
+use libsqlite3_sys as ffi;
+use napi::bindgen_prelude::Uint8Array;
+use napi_derive::napi;
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions, SqliteSynchronous};
+
+/// Path of a workspace that only lives in memory, it is gone once the
+/// connection is closed.
+pub(crate) const MEMORY_PATH: &str = ":memory:";
+
+#[napi]
+pub enum JournalMode {
+  Wal,
+  Delete,
+  Off,
+}
+
+#[napi]
+pub enum SynchronousMode {
+  Off,
+  Normal,
+  Full,
+  Extra,
+}
+
+#[napi(object)]
+#[derive(Default)]
+pub struct ConnectionOptions {
+  /// Defaults to `Wal` for new databases, existing ones stay in `Wal` if they
+  /// were in it and use `Delete` otherwise.
+  pub journal_mode: Option<JournalMode>,
+  /// Defaults to `Normal` if the database is in `Wal` mode once connected and
+  /// `Full` otherwise.
+  pub synchronous: Option<SynchronousMode>,
+  /// How long to wait for a locked database, in milliseconds.
+  pub busy_timeout: Option<u32>,
+  /// Max connections of the pool, defaults to 4.
+  pub max_connections: Option<u32>,
+  /// Open the database read-only. Missing files are not created and
+  /// migrations are not run.
+  pub read_only: Option<bool>,
+  /// See `SqliteConnection::set_compaction_threshold`.
+  pub compaction_threshold: Option<u32>,
+  /// 32 bytes key to encrypt every row payload with. A plaintext workspace is
+  /// encrypted on its first connection with a key, see
+  /// `SqliteConnection::rotate_encryption_key` to change it later. Only the
+  /// payloads are encrypted, these stay readable without the key: doc ids and
+  /// the timestamps of updates and snapshots, snapshot state vectors, blob
+  /// keys, sizes and mime types, kv namespaces and keys, upload ids, doc
+  /// tombstones, the change log and the metadata of quarantined rows.
+  pub encryption_key: Option<Uint8Array>,
+}
+
+impl From<JournalMode> for SqliteJournalMode {
+  fn from(mode: JournalMode) -> Self {
+    match mode {
+      JournalMode::Wal => SqliteJournalMode::Wal,
+      JournalMode::Delete => SqliteJournalMode::Delete,
+      JournalMode::Off => SqliteJournalMode::Off,
+    }
+  }
+}
+
+impl From<SynchronousMode> for SqliteSynchronous {
+  fn from(mode: SynchronousMode) -> Self {
+    match mode {
+      SynchronousMode::Off => SqliteSynchronous::Off,
+      SynchronousMode::Normal => SqliteSynchronous::Normal,
+      SynchronousMode::Full => SqliteSynchronous::Full,
+      SynchronousMode::Extra => SqliteSynchronous::Extra,
+    }
+  }
+}
+
+impl ConnectionOptions {
+  pub(crate) fn is_read_only(&self) -> bool {
+    self.read_only.unwrap_or(false)
+  }
+
+  pub(crate) fn to_pool_options(&self, path: &str) -> SqlitePoolOptions {
+    let synchronous = self.synchronous;
+    let options = SqlitePoolOptions::new()
+      .max_connections(self.max_connections.unwrap_or(4))
+      // the journal mode of an existing file is only known once connected
+      .after_connect(move |connection, _| {
+        Box::pin(async move {
+          if synchronous.is_none() {
+            let journal_mode: String = sqlx::query_scalar("PRAGMA journal_mode")
+              .fetch_one(&mut *connection)
+              .await?;
+            let synchronous = if journal_mode.eq_ignore_ascii_case("wal") {
+              "NORMAL"
+            } else {
+              "FULL"
+            };
+            sqlx::query(&format!("PRAGMA synchronous = {synchronous}"))
+              .execute(&mut *connection)
+              .await?;
+          }
+          Ok(())
+        })
+      })
+      // an `ImmediateTransaction` can not roll back when it is dropped, do it
+      // before the connection is handed out again
+      .after_release(|connection, _| {
+        Box::pin(async move {
+          let autocommit = {
+            let mut handle = connection.lock_handle().await?;
+            // SAFETY: the connection is locked until `handle` is dropped
+            unsafe { ffi::sqlite3_get_autocommit(handle.as_raw_handle().as_ptr()) }
+          };
+          if autocommit == 0 {
+            sqlx::query("ROLLBACK").execute(&mut *connection).await?;
+          }
+          Ok(true)
+        })
+      });
+    if path != MEMORY_PATH {
+      return options;
+    }
+    // the database is dropped with its last connection, keep one open until
+    // the pool is closed
+    options
+      .min_connections(1)
+      .idle_timeout(None)
+      .max_lifetime(None)
+  }
+
+  pub(crate) fn to_connect_options(&self, path: &str) -> SqliteConnectOptions {
+    let in_memory = path == MEMORY_PATH;
+    let options = if in_memory {
+      // a database of its own, shared by the connections of the pool
+      SqliteConnectOptions::from_str(MEMORY_PATH).expect("`:memory:` is a valid database url")
+    } else {
+      SqliteConnectOptions::new().filename(path)
+    };
+    let mut options = options.foreign_keys(false).read_only(self.is_read_only());
+    if self.encryption_key.is_some() {
+      // deleted and overwritten payloads are zeroed instead of left in free
+      // pages, an encrypted workspace must not keep its former plaintext
+      options = options.pragma("secure_delete", "ON");
+    }
+
+    if let Some(busy_timeout) = self.busy_timeout {
+      options = options.busy_timeout(Duration::from_millis(busy_timeout as u64));
+    }
+
+    // only `Wal` is stored in the file, `Delete` and `Off` are set per
+    // connection, so files that earlier releases opened with `Off` now run in
+    // `Delete`. Only new files get `Wal` unless asked explicitly, switching
+    // out of it needs an exclusive lock, which fails while another window has
+    // the file open.
+    let journal_mode = match self.journal_mode {
+      Some(mode) => Some(mode),
+      None if self.is_read_only() || in_memory || Path::new(path).exists() => None,
+      None => Some(JournalMode::Wal),
+    };
+    if let Some(journal_mode) = journal_mode {
+      options = options.journal_mode(journal_mode.into());
+    }
+
+    // the default depends on the journal mode, set once connected, see
+    // `to_pool_options`
+    match self.synchronous {
+      Some(synchronous) => options.synchronous(synchronous.into()),
+      None => options,
+    }
+  }
+}
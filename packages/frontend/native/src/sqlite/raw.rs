@@ -0,0 +1,17 @@
+use std::{ffi::CStr, os::raw::c_int, ptr::NonNull};
+
+use libsqlite3_sys as ffi;
+
+use super::error::RawSqliteError;
+
+/// Build an error from the last error of a raw sqlite connection.
+pub(crate) fn sqlite_error(db: NonNull<ffi::sqlite3>, code: c_int) -> anyhow::Error {
+  // SAFETY: `db` is a valid connection, the message is owned by sqlite and
+  // copied before any other call on the connection.
+  let message = unsafe { CStr::from_ptr(ffi::sqlite3_errmsg(db.as_ptr())) };
+  RawSqliteError {
+    code,
+    message: message.to_string_lossy().into_owned(),
+  }
+  .into()
+}
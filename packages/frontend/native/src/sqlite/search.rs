@@ -0,0 +1,269 @@
+use std::{collections::HashSet, time::Duration};
+
+use napi_derive::napi;
+use sqlx::Row;
+use tokio::{task::JoinHandle, time::Instant};
+
+use super::{
+  error::{database_error, Result},
+  ydoc::{apply_updates, collect_block_texts},
+  SqliteConnection,
+};
+
+/// Default number of search results.
+const DEFAULT_SEARCH_LIMIT: u32 = 20;
+
+/// How long the indexer waits after the last write before indexing the docs
+/// written to, a burst of inserts decodes every doc once.
+const INDEX_DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// Tokens of a snippet around the matched terms.
+const SNIPPET_TOKENS: i64 = 16;
+
+#[napi(object)]
+#[derive(Default)]
+pub struct SearchOptions {
+  /// Only search the blocks of this doc.
+  pub doc_id: Option<String>,
+  /// Defaults to 20.
+  pub limit: Option<u32>,
+  /// Inserted before every matched term in snippets, defaults to `<b>`.
+  pub highlight_start: Option<String>,
+  /// Inserted after every matched term in snippets, defaults to `</b>`.
+  pub highlight_end: Option<String>,
+}
+
+#[napi(object)]
+pub struct SearchResult {
+  pub doc_id: String,
+  pub block_id: String,
+  /// Text of the block around the match, with matched terms highlighted.
+  pub snippet: String,
+  /// bm25 score of the match, lower is better.
+  pub rank: f64,
+}
+
+/// Turn user input into an FTS5 query. Quoted parts match as a phrase, every
+/// other word matches as a prefix, and all of them have to match.
+fn to_match_query(query: &str) -> Option<String> {
+  let mut parts = Vec::new();
+  // quotes are dropped by the split, so no part can break out of its quotes
+  for (index, segment) in query.split('"').enumerate() {
+    if index % 2 == 1 {
+      let phrase = segment.split_whitespace().collect::<Vec<_>>().join(" ");
+      if !phrase.is_empty() {
+        parts.push(format!(r#""{phrase}""#));
+      }
+    } else {
+      parts.extend(
+        segment
+          .split_whitespace()
+          .map(|word| format!(r#""{word}"*"#)),
+      );
+    }
+  }
+  (!parts.is_empty()).then(|| parts.join(" "))
+}
+
+/// Docs whose search index entries are out of date, indexed in the background
+/// so writes never decode a doc.
+#[derive(Default)]
+pub(crate) struct IndexQueue {
+  doc_ids: HashSet<String>,
+  last_write: Option<Instant>,
+  indexer: Option<JoinHandle<()>>,
+}
+
+impl IndexQueue {
+  pub(crate) fn stop(&mut self) {
+    self.doc_ids.clear();
+    if let Some(indexer) = self.indexer.take() {
+      indexer.abort();
+    }
+  }
+}
+
+async fn run_indexer(connection: SqliteConnection) {
+  loop {
+    let last_write = connection.index_queue.lock().last_write;
+    if let Some(deadline) = last_write.map(|last_write| last_write + INDEX_DEBOUNCE) {
+      if Instant::now() < deadline {
+        tokio::time::sleep_until(deadline).await;
+        continue;
+      }
+    }
+    let doc_ids = {
+      let mut queue = connection.index_queue.lock();
+      if queue.doc_ids.is_empty() {
+        queue.indexer = None;
+        return;
+      }
+      std::mem::take(&mut queue.doc_ids)
+    };
+    connection.index_docs(doc_ids).await;
+  }
+}
+
+#[napi]
+impl SqliteConnection {
+  /// Search the text of every doc that is not trashed. Words match as
+  /// prefixes, quoted words as a phrase. Not available for encrypted
+  /// workspaces, their text is never indexed.
+  #[napi]
+  pub async fn search(
+    &self,
+    query: String,
+    options: Option<SearchOptions>,
+  ) -> Result<Vec<SearchResult>> {
+    if self.cipher.read().await.is_enabled() {
+      return Err(database_error(anyhow::anyhow!(
+        "Search is not available for encrypted workspaces"
+      )));
+    }
+    let Some(query) = to_match_query(&query) else {
+      return Ok(Vec::new());
+    };
+    // include the docs written to since the last run of the indexer
+    self.flush_index().await;
+    let options = options.unwrap_or_default();
+    let rows = sqlx::query(
+      r#"SELECT b.doc_id, b.block_id, snippet(search_index, 0, ?, ?, '…', ?), search_index.rank
+      FROM search_index JOIN search_blocks b ON b.id = search_index.rowid
+      WHERE search_index MATCH ?
+        AND (? IS NULL OR b.doc_id = ?)
+        AND b.doc_id NOT IN (SELECT doc_id FROM doc_tombstones WHERE purged_at IS NULL AND restored_at IS NULL)
+      ORDER BY search_index.rank
+      LIMIT ?"#,
+    )
+    .bind(options.highlight_start.as_deref().unwrap_or("<b>"))
+    .bind(options.highlight_end.as_deref().unwrap_or("</b>"))
+    .bind(SNIPPET_TOKENS)
+    .bind(query)
+    .bind(&options.doc_id)
+    .bind(&options.doc_id)
+    .bind(options.limit.unwrap_or(DEFAULT_SEARCH_LIMIT))
+    .fetch_all(&self.pool)
+    .await
+    .map_err(database_error)?;
+    Ok(
+      rows
+        .into_iter()
+        .map(|row| SearchResult {
+          doc_id: row.get(0),
+          block_id: row.get(1),
+          snippet: row.get(2),
+          rank: row.get(3),
+        })
+        .collect(),
+    )
+  }
+
+  /// Extract the text of a doc again and replace its entries in the search
+  /// index.
+  #[napi]
+  pub async fn reindex_doc(&self, doc_id: String) -> Result<()> {
+    self.index_doc(&doc_id).await.map_err(database_error)?;
+    Ok(())
+  }
+
+  /// Drop the search index and build it again from every doc, returns the
+  /// number of indexed docs. Call it after decrypting a workspace, encrypted
+  /// workspaces are not indexed.
+  #[napi]
+  pub async fn rebuild_search_index(&self) -> Result<i64> {
+    sqlx::query!("DELETE FROM search_blocks")
+      .execute(&self.pool)
+      .await
+      .map_err(database_error)?;
+    // recovers an index that got out of sync with `search_blocks`
+    sqlx::query("INSERT INTO search_index (search_index) VALUES ('rebuild')")
+      .execute(&self.pool)
+      .await
+      .map_err(database_error)?;
+
+    let doc_ids = sqlx::query!(
+      r#"SELECT doc_id AS "doc_id!" FROM updates WHERE doc_id IS NOT NULL
+      UNION SELECT doc_id FROM snapshots WHERE doc_id IS NOT NULL"#
+    )
+    .fetch_all(&self.pool)
+    .await
+    .map_err(database_error)?;
+    for row in &doc_ids {
+      self.index_doc(&row.doc_id).await.map_err(database_error)?;
+    }
+    Ok(doc_ids.len() as i64)
+  }
+
+  /// Index the docs again in the background, once no write happened for
+  /// `INDEX_DEBOUNCE`.
+  pub(crate) fn schedule_index(&self, doc_ids: impl IntoIterator<Item = String>) {
+    let mut queue = self.index_queue.lock();
+    queue.doc_ids.extend(doc_ids);
+    if queue.doc_ids.is_empty() {
+      return;
+    }
+    queue.last_write = Some(Instant::now());
+    if queue.indexer.is_none() {
+      queue.indexer = Some(tokio::spawn(run_indexer(self.clone())));
+    }
+  }
+
+  /// Index every scheduled doc now.
+  pub(crate) async fn flush_index(&self) {
+    let doc_ids = std::mem::take(&mut self.index_queue.lock().doc_ids);
+    self.index_docs(doc_ids).await;
+  }
+
+  async fn index_docs(&self, doc_ids: HashSet<String>) {
+    for doc_id in doc_ids {
+      // a doc that can not be decoded keeps its previous entries,
+      // `reindex_doc` reports the error
+      self.index_doc(&doc_id).await.ok();
+    }
+  }
+
+  /// Replace the search index entries of a doc with the text of its current
+  /// updates.
+  pub(crate) async fn index_doc(&self, doc_id: &str) -> anyhow::Result<()> {
+    let cipher = self.cipher.read().await;
+    let mut texts = Vec::new();
+    if !cipher.is_enabled() {
+      let payloads = self.read_doc_payloads(&cipher, Some(doc_id)).await?;
+      texts = extract_texts(&payloads)?;
+    }
+
+    let mut transaction = self.pool.begin().await?;
+    write_search_blocks(&mut transaction, doc_id, texts).await?;
+    transaction.commit().await?;
+    Ok(())
+  }
+}
+
+fn extract_texts(payloads: &[Vec<u8>]) -> anyhow::Result<Vec<(String, String)>> {
+  if payloads.is_empty() {
+    return Ok(Vec::new());
+  }
+  let doc = apply_updates(payloads.iter().map(|data| data.as_slice()))?;
+  collect_block_texts(&doc)
+}
+
+async fn write_search_blocks(
+  connection: &mut sqlx::SqliteConnection,
+  doc_id: &str,
+  texts: Vec<(String, String)>,
+) -> anyhow::Result<()> {
+  sqlx::query!("DELETE FROM search_blocks WHERE doc_id = ?", doc_id)
+    .execute(&mut *connection)
+    .await?;
+  for (block_id, content) in texts {
+    sqlx::query!(
+      "INSERT INTO search_blocks (doc_id, block_id, content) VALUES (?, ?, ?)",
+      doc_id,
+      block_id,
+      content
+    )
+    .execute(&mut *connection)
+    .await?;
+  }
+  Ok(())
+}
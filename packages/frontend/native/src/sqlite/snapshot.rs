@@ -0,0 +1,291 @@
+use chrono::NaiveDateTime;
+use napi::bindgen_prelude::Buffer;
+use napi_derive::napi;
+
+use super::{
+  encryption::{Cipher, RowIdentity},
+  error::{database_error, Result},
+  immediate::ImmediateTransaction,
+  ydoc::{apply_updates, encode_state_vector},
+  ChangeEvent, CompactionResult, SqliteConnection, UpdateRow,
+};
+
+#[napi(object)]
+#[derive(Default)]
+pub struct LoadDocOptions {
+  /// Merge the snapshot and the newer updates into a single binary, defaults
+  /// to `false`.
+  pub merge: Option<bool>,
+  /// Load a trashed doc as well, defaults to `false`.
+  pub include_trashed: Option<bool>,
+}
+
+#[napi(object)]
+pub struct LoadedDoc {
+  /// Merged state of the doc up to `snapshot_update_id`, or of the whole doc
+  /// if loaded with `merge`.
+  pub snapshot: Option<Buffer>,
+  /// State vector of `snapshot`.
+  pub state_vector: Option<Buffer>,
+  /// Id of the last update merged into the stored snapshot.
+  pub snapshot_update_id: Option<i64>,
+  /// Time of the last update merged into the stored snapshot.
+  pub snapshot_updated_at: Option<NaiveDateTime>,
+  /// Updates newer than the snapshot ordered by id, empty if loaded with
+  /// `merge`.
+  pub updates: Vec<UpdateRow>,
+}
+
+impl LoadedDoc {
+  fn empty() -> Self {
+    Self {
+      snapshot: None,
+      state_vector: None,
+      snapshot_update_id: None,
+      snapshot_updated_at: None,
+      updates: Vec::new(),
+    }
+  }
+}
+
+#[napi]
+impl SqliteConnection {
+  /// Load a doc from its snapshot and the updates inserted after it, without
+  /// replaying the whole update log. Docs compacted into a snapshot have to be
+  /// loaded this way, `get_updates` only returns the newer updates.
+  #[napi]
+  pub async fn load_doc(
+    &self,
+    doc_id: Option<String>,
+    options: Option<LoadDocOptions>,
+  ) -> Result<LoadedDoc> {
+    let options = options.unwrap_or_default();
+    if let Some(doc_id) = &doc_id {
+      if !options.include_trashed.unwrap_or(false)
+        && self.is_trashed(doc_id).await.map_err(database_error)?
+      {
+        return Ok(LoadedDoc::empty());
+      }
+    }
+
+    let cipher = self.cipher.read().await;
+    // read both in one transaction, a snapshot written in between deletes the
+    // updates it merged
+    let mut transaction = self.pool.begin().await.map_err(database_error)?;
+    let snapshot = sqlx::query!(
+      "SELECT data, state_vector, last_update_id, updated_at FROM snapshots WHERE doc_id IS ?",
+      doc_id
+    )
+    .fetch_optional(&mut *transaction)
+    .await
+    .map_err(database_error)?;
+    let after_id = snapshot
+      .as_ref()
+      .map_or(0, |snapshot| snapshot.last_update_id);
+    let updates = sqlx::query_as!(
+      UpdateRow,
+      "SELECT id, timestamp, data, doc_id FROM updates WHERE doc_id IS ? AND id > ? ORDER BY id",
+      doc_id,
+      after_id
+    )
+    .fetch_all(&mut *transaction)
+    .await
+    .map_err(database_error)?;
+    transaction.commit().await.map_err(database_error)?;
+
+    let mut loaded = LoadedDoc {
+      updates: cipher.open_update_rows(updates).map_err(database_error)?,
+      ..LoadedDoc::empty()
+    };
+    if let Some(snapshot) = snapshot {
+      let identity = RowIdentity::snapshot(doc_id.as_deref());
+      loaded.snapshot = Some(
+        cipher
+          .open(&snapshot.data, &identity)
+          .map_err(database_error)?
+          .into_owned()
+          .into(),
+      );
+      loaded.state_vector = Some(snapshot.state_vector.into());
+      loaded.snapshot_update_id = Some(snapshot.last_update_id);
+      loaded.snapshot_updated_at = Some(snapshot.updated_at);
+    }
+
+    if options.merge.unwrap_or(false) && !loaded.updates.is_empty() {
+      let doc = apply_updates(
+        loaded
+          .snapshot
+          .iter()
+          .chain(loaded.updates.iter().map(|row| &row.data))
+          .map(|data| data.as_ref()),
+      )
+      .map_err(database_error)?;
+      let merged = doc
+        .encode_update_v1()
+        .map_err(|err| database_error(anyhow::anyhow!("failed to encode update: {err}")))?;
+      loaded.snapshot = Some(merged.into());
+      loaded.state_vector = Some(encode_state_vector(&doc).map_err(database_error)?.into());
+      loaded.updates = Vec::new();
+    }
+    Ok(loaded)
+  }
+
+  /// Merge the updates of a doc into its snapshot. Only the merged updates are
+  /// deleted, updates inserted in the meantime are kept for the next one.
+  pub(crate) async fn write_snapshot(
+    &self,
+    doc_id: Option<String>,
+  ) -> anyhow::Result<CompactionResult> {
+    let cipher = self.cipher.read().await;
+    let previous = sqlx::query!(
+      "SELECT data, last_update_id FROM snapshots WHERE doc_id IS ?",
+      doc_id
+    )
+    .fetch_optional(&self.pool)
+    .await?;
+    let after_id = previous
+      .as_ref()
+      .map_or(0, |snapshot| snapshot.last_update_id);
+    let updates = sqlx::query!(
+      "SELECT id, data, timestamp FROM updates WHERE doc_id IS ? AND id > ? ORDER BY id",
+      doc_id,
+      after_id
+    )
+    .fetch_all(&self.pool)
+    .await?;
+
+    let before_count = updates.len() as i64;
+    let before_size = updates.iter().map(|row| row.data.len() as i64).sum();
+    let Some((last_update_id, updated_at)) = updates.last().map(|row| (row.id, row.timestamp))
+    else {
+      // nothing to merge
+      return Ok(CompactionResult {
+        before_count,
+        before_size,
+        after_count: 0,
+        after_size: previous.map_or(0, |snapshot| snapshot.data.len() as i64),
+      });
+    };
+
+    let snapshot_identity = RowIdentity::snapshot(doc_id.as_deref());
+    let update_identity = RowIdentity::update(doc_id.as_deref());
+    let payloads = previous
+      .iter()
+      .map(|snapshot| cipher.open(&snapshot.data, &snapshot_identity))
+      .chain(
+        updates
+          .iter()
+          .map(|row| cipher.open(&row.data, &update_identity)),
+      )
+      .collect::<anyhow::Result<Vec<_>>>()?;
+    let doc = apply_updates(payloads.iter().map(|data| data.as_ref()))?;
+    let merged = doc
+      .encode_update_v1()
+      .map_err(|err| anyhow::anyhow!("failed to encode update: {err}"))?;
+    let merged = cipher.seal(&merged, &snapshot_identity)?;
+    let merged = merged.as_ref();
+    let state_vector = encode_state_vector(&doc)?;
+
+    // takes the write lock before reading `current`, a concurrent insert
+    // makes it wait instead of failing with `BUSY`
+    let mut transaction = ImmediateTransaction::begin(&self.pool).await?;
+    let current = sqlx::query!(
+      "SELECT last_update_id FROM snapshots WHERE doc_id IS ?",
+      doc_id
+    )
+    .fetch_optional(&mut *transaction)
+    .await?
+    .map_or(0, |snapshot| snapshot.last_update_id);
+    if current != after_id {
+      return Err(anyhow::anyhow!(
+        "Snapshot of doc {doc_id:?} was written concurrently"
+      ));
+    }
+    sqlx::query!("DELETE FROM snapshots WHERE doc_id IS ?", doc_id)
+      .execute(&mut *transaction)
+      .await?;
+    // keep the time of the latest merged update
+    sqlx::query!(
+      "INSERT INTO snapshots (doc_id, data, state_vector, last_update_id, updated_at) VALUES (?, ?, ?, ?, ?)",
+      doc_id,
+      merged,
+      state_vector,
+      last_update_id,
+      updated_at
+    )
+    .execute(&mut *transaction)
+    .await?;
+    sqlx::query!(
+      "DELETE FROM updates WHERE doc_id IS ? AND id <= ?",
+      doc_id,
+      last_update_id
+    )
+    .execute(&mut *transaction)
+    .await?;
+    let after_count = sqlx::query!(
+      r#"SELECT COUNT(*) AS "count!: i64" FROM updates WHERE doc_id IS ?"#,
+      doc_id
+    )
+    .fetch_one(&mut *transaction)
+    .await?
+    .count;
+    transaction.commit().await?;
+    self.notify(vec![ChangeEvent::update(doc_id, None)]);
+
+    Ok(CompactionResult {
+      before_count,
+      before_size,
+      after_count,
+      after_size: merged.len() as i64,
+    })
+  }
+
+  /// Decrypted snapshot and updates of a doc, in the order they have to be
+  /// applied.
+  pub(crate) async fn read_doc_payloads(
+    &self,
+    cipher: &Cipher,
+    doc_id: Option<&str>,
+  ) -> anyhow::Result<Vec<Vec<u8>>> {
+    let mut transaction = self.pool.begin().await?;
+    let payloads = read_doc_payloads(&mut transaction, cipher, doc_id).await?;
+    transaction.commit().await?;
+    Ok(payloads)
+  }
+}
+
+/// Same as `SqliteConnection::read_doc_payloads`, inside a transaction of the
+/// caller.
+pub(crate) async fn read_doc_payloads(
+  connection: &mut sqlx::SqliteConnection,
+  cipher: &Cipher,
+  doc_id: Option<&str>,
+) -> anyhow::Result<Vec<Vec<u8>>> {
+  let snapshot = sqlx::query!(
+    "SELECT data, last_update_id FROM snapshots WHERE doc_id IS ?",
+    doc_id
+  )
+  .fetch_optional(&mut *connection)
+  .await?;
+  let after_id = snapshot
+    .as_ref()
+    .map_or(0, |snapshot| snapshot.last_update_id);
+  let updates = sqlx::query!(
+    "SELECT data FROM updates WHERE doc_id IS ? AND id > ? ORDER BY id",
+    doc_id,
+    after_id
+  )
+  .fetch_all(&mut *connection)
+  .await?;
+
+  let snapshot = snapshot
+    .map(|snapshot| cipher.open(&snapshot.data, &RowIdentity::snapshot(doc_id)))
+    .transpose()?
+    .map(|data| data.into_owned());
+  let identity = RowIdentity::update(doc_id);
+  let updates = updates
+    .iter()
+    .map(|row| Ok(cipher.open(&row.data, &identity)?.into_owned()))
+    .collect::<anyhow::Result<Vec<_>>>()?;
+  Ok(snapshot.into_iter().chain(updates).collect())
+}
@@ -0,0 +1,336 @@
+use std::time::{Duration, Instant};
+
+use napi::threadsafe_function::{ThreadsafeFunction, ThreadsafeFunctionCallMode};
+use napi_derive::napi;
+use sqlx::{pool::PoolConnection, Row, Sqlite};
+
+use super::{
+  error::{database_error, Result},
+  SqliteConnection,
+};
+
+/// Default number of largest docs and blobs in the stats.
+const DEFAULT_LARGEST_LIMIT: u32 = 10;
+
+/// Default pages freed by each step of an incremental vacuum.
+const DEFAULT_PAGES_PER_STEP: u32 = 256;
+
+/// Virtual machine instructions between two calls of the progress handler
+/// while vacuuming.
+const VACUUM_PROGRESS_INSTRUCTIONS: i32 = 100_000;
+
+/// Least time between two progress reports of a full vacuum.
+const VACUUM_PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+#[napi(object)]
+pub struct TableSize {
+  /// Name of a table or index.
+  pub name: String,
+  pub size: i64,
+}
+
+#[napi(object)]
+pub struct DocSize {
+  pub doc_id: Option<String>,
+  /// Stored size of the snapshot and the updates in bytes.
+  pub size: i64,
+}
+
+#[napi(object)]
+pub struct BlobSize {
+  pub key: String,
+  /// Stored size in bytes.
+  pub size: i64,
+}
+
+#[napi(object)]
+pub struct StorageStats {
+  pub page_size: i64,
+  pub page_count: i64,
+  /// Unused pages, `vacuum` gives them back to the filesystem.
+  pub freelist_count: i64,
+  /// Size of the database in bytes, without the WAL file.
+  pub size: i64,
+  /// Tables and indexes ordered by size, largest first.
+  pub tables: Vec<TableSize>,
+  pub largest_docs: Vec<DocSize>,
+  pub largest_blobs: Vec<BlobSize>,
+}
+
+#[napi(object)]
+#[derive(Default)]
+pub struct VacuumOptions {
+  /// Switch the file to incremental auto vacuum, so `incremental_vacuum` can
+  /// free pages later without rewriting the whole file. Keeps the current
+  /// mode if not set.
+  pub incremental: Option<bool>,
+}
+
+/// Progress of a full vacuum. SQLite does not tell how much of the file is
+/// left, only that it is still working.
+#[napi(object)]
+pub struct VacuumActivity {
+  /// Grows while the file is rewritten.
+  pub steps: i64,
+  /// Milliseconds since the vacuum started.
+  pub elapsed: i64,
+}
+
+#[napi(object)]
+pub struct VacuumProgress {
+  /// Free pages left.
+  pub remaining: i64,
+  /// Free pages when the vacuum started.
+  pub total: i64,
+}
+
+#[napi(object)]
+pub struct VacuumResult {
+  pub before_size: i64,
+  pub after_size: i64,
+  /// Bytes given back to the filesystem.
+  pub reclaimed: i64,
+}
+
+impl VacuumResult {
+  fn new(before_size: i64, after_size: i64) -> Self {
+    Self {
+      before_size,
+      after_size,
+      reclaimed: before_size - after_size,
+    }
+  }
+}
+
+async fn pragma(connection: &mut PoolConnection<Sqlite>, name: &str) -> sqlx::Result<i64> {
+  sqlx::query_scalar(&format!("PRAGMA {name}"))
+    .fetch_one(&mut **connection)
+    .await
+}
+
+async fn database_size(connection: &mut PoolConnection<Sqlite>) -> sqlx::Result<i64> {
+  Ok(pragma(connection, "page_size").await? * pragma(connection, "page_count").await?)
+}
+
+/// Report the activity of statements run on `connection` to `progress`, as
+/// soon as one starts and then every 100ms at most, until the progress
+/// handler is removed.
+pub(crate) async fn report_activity(
+  connection: &mut PoolConnection<Sqlite>,
+  mut progress: impl FnMut(VacuumActivity) + Send + 'static,
+) -> sqlx::Result<()> {
+  let started = Instant::now();
+  let mut reported: Option<Instant> = None;
+  let mut steps = 0;
+  let mut handle = connection.lock_handle().await?;
+  handle.set_progress_handler(VACUUM_PROGRESS_INSTRUCTIONS, move || {
+    steps += 1;
+    if !reported.is_some_and(|reported| reported.elapsed() < VACUUM_PROGRESS_INTERVAL) {
+      reported = Some(Instant::now());
+      progress(VacuumActivity {
+        steps,
+        elapsed: started.elapsed().as_millis() as i64,
+      });
+    }
+    // keep going
+    true
+  });
+  Ok(())
+}
+
+#[napi]
+impl SqliteConnection {
+  /// Report what takes up space in the workspace file. `limit` caps the
+  /// largest docs and blobs, 10 by default.
+  #[napi]
+  pub async fn get_storage_stats(&self, limit: Option<u32>) -> Result<StorageStats> {
+    let limit = limit.unwrap_or(DEFAULT_LARGEST_LIMIT);
+    let mut connection = self.pool.acquire().await.map_err(database_error)?;
+    let page_size = pragma(&mut connection, "page_size")
+      .await
+      .map_err(database_error)?;
+    let page_count = pragma(&mut connection, "page_count")
+      .await
+      .map_err(database_error)?;
+    let freelist_count = pragma(&mut connection, "freelist_count")
+      .await
+      .map_err(database_error)?;
+
+    // the query macros can not describe virtual tables
+    let tables = sqlx::query("SELECT name, SUM(pgsize) FROM dbstat GROUP BY name ORDER BY 2 DESC")
+      .fetch_all(&mut *connection)
+      .await
+      .map_err(database_error)?
+      .into_iter()
+      .map(|row| TableSize {
+        name: row.get(0),
+        size: row.get(1),
+      })
+      .collect();
+
+    let largest_docs = sqlx::query_as!(
+      DocSize,
+      r#"SELECT doc_id AS "doc_id?", SUM(size) AS "size!: i64"
+      FROM (
+        SELECT doc_id, length(data) AS size FROM updates
+        UNION ALL
+        SELECT doc_id, length(data) FROM snapshots
+      )
+      GROUP BY doc_id
+      ORDER BY 2 DESC
+      LIMIT ?"#,
+      limit
+    )
+    .fetch_all(&mut *connection)
+    .await
+    .map_err(database_error)?;
+
+    let largest_blobs = sqlx::query_as!(
+      BlobSize,
+      r#"SELECT key, length(data) AS "size!: i64" FROM blobs ORDER BY 2 DESC LIMIT ?"#,
+      limit
+    )
+    .fetch_all(&mut *connection)
+    .await
+    .map_err(database_error)?;
+
+    Ok(StorageStats {
+      page_size,
+      page_count,
+      freelist_count,
+      size: page_size * page_count,
+      tables,
+      largest_docs,
+      largest_blobs,
+    })
+  }
+
+  /// Rewrite the workspace file without free pages. Blocks every other
+  /// access until done, and needs as much free disk space as the file takes.
+  /// `progress` is called once the rewrite starts, then every 100ms at most.
+  #[napi]
+  pub async fn vacuum(
+    &self,
+    options: Option<VacuumOptions>,
+    progress: Option<ThreadsafeFunction<VacuumActivity>>,
+  ) -> Result<VacuumResult> {
+    self
+      .vacuum_with(
+        options,
+        progress.map(|progress| {
+          move |activity: VacuumActivity| {
+            progress.call(Ok(activity), ThreadsafeFunctionCallMode::NonBlocking);
+          }
+        }),
+      )
+      .await
+  }
+
+  /// Free unused pages in steps of `pages_per_step`, 256 by default, without
+  /// rewriting the file. Other connections can use the workspace between the
+  /// steps, `progress` is called after every step. Needs a file switched to
+  /// incremental auto vacuum by `vacuum`.
+  #[napi]
+  pub async fn incremental_vacuum(
+    &self,
+    pages_per_step: Option<u32>,
+    progress: Option<ThreadsafeFunction<VacuumProgress>>,
+  ) -> Result<VacuumResult> {
+    if self.read_only {
+      return Err(database_error(anyhow::anyhow!(
+        "Can not vacuum a read-only workspace"
+      )));
+    }
+    let pages_per_step = pages_per_step.unwrap_or(DEFAULT_PAGES_PER_STEP).max(1);
+    let mut connection = self.pool.acquire().await.map_err(database_error)?;
+    // 2 is `INCREMENTAL`
+    if pragma(&mut connection, "auto_vacuum")
+      .await
+      .map_err(database_error)?
+      != 2
+    {
+      return Err(database_error(anyhow::anyhow!(
+        "Incremental vacuum is not enabled, call `vacuum` with `incremental` first"
+      )));
+    }
+
+    let before_size = database_size(&mut connection)
+      .await
+      .map_err(database_error)?;
+    let total = pragma(&mut connection, "freelist_count")
+      .await
+      .map_err(database_error)?;
+    let mut remaining = total;
+    while remaining > 0 {
+      // every statement runs in its own transaction, other connections get a
+      // turn between the steps
+      sqlx::query(&format!("PRAGMA incremental_vacuum({pages_per_step})"))
+        .execute(&mut *connection)
+        .await
+        .map_err(database_error)?;
+      let left = pragma(&mut connection, "freelist_count")
+        .await
+        .map_err(database_error)?;
+      let stalled = left >= remaining;
+      remaining = left;
+      if let Some(progress) = &progress {
+        progress.call(
+          Ok(VacuumProgress { remaining, total }),
+          ThreadsafeFunctionCallMode::NonBlocking,
+        );
+      }
+      if stalled {
+        break;
+      }
+      tokio::task::yield_now().await;
+    }
+    let after_size = database_size(&mut connection)
+      .await
+      .map_err(database_error)?;
+    Ok(VacuumResult::new(before_size, after_size))
+  }
+}
+
+impl SqliteConnection {
+  pub(crate) async fn vacuum_with(
+    &self,
+    options: Option<VacuumOptions>,
+    progress: Option<impl FnMut(VacuumActivity) + Send + 'static>,
+  ) -> Result<VacuumResult> {
+    if self.read_only {
+      return Err(database_error(anyhow::anyhow!(
+        "Can not vacuum a read-only workspace"
+      )));
+    }
+    let options = options.unwrap_or_default();
+    let mut connection = self.pool.acquire().await.map_err(database_error)?;
+    let before_size = database_size(&mut connection)
+      .await
+      .map_err(database_error)?;
+    if let Some(incremental) = options.incremental {
+      // only takes effect on the next `VACUUM`
+      let mode = if incremental { "INCREMENTAL" } else { "NONE" };
+      sqlx::query(&format!("PRAGMA auto_vacuum = {mode}"))
+        .execute(&mut *connection)
+        .await
+        .map_err(database_error)?;
+    }
+    if let Some(progress) = progress {
+      report_activity(&mut connection, progress)
+        .await
+        .map_err(database_error)?;
+    }
+    let vacuumed = sqlx::query("VACUUM").execute(&mut *connection).await;
+    // the connection goes back to the pool, other queries must not report
+    connection
+      .lock_handle()
+      .await
+      .map_err(database_error)?
+      .remove_progress_handler();
+    vacuumed.map_err(database_error)?;
+    let after_size = database_size(&mut connection)
+      .await
+      .map_err(database_error)?;
+    Ok(VacuumResult::new(before_size, after_size))
+  }
+}
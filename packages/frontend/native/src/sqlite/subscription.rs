@@ -0,0 +1,287 @@
+use std::{
+  collections::{HashMap, HashSet},
+  sync::Arc,
+  time::Duration,
+};
+
+use napi::threadsafe_function::{ThreadsafeFunction, ThreadsafeFunctionCallMode};
+use napi_derive::napi;
+use parking_lot::Mutex;
+use sqlx::Row;
+use tokio::task::JoinHandle;
+
+use super::{
+  error::{database_error, Result},
+  SqliteConnection,
+};
+
+/// How often `PRAGMA data_version` is polled for changes of other
+/// connections.
+const DATA_VERSION_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Table name of events that could not be narrowed down to a table.
+const ANY_TABLE: &str = "*";
+
+#[napi(object)]
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct ChangeEvent {
+  /// Table of the changed rows, or namespace of changed key-value entries.
+  /// `*` if the rows that changed are not known, reload everything in this
+  /// case.
+  pub table: String,
+  pub doc_id: Option<String>,
+  pub key: Option<String>,
+  /// Id of an inserted update, `None` if updates of the doc were deleted.
+  pub row_id: Option<i64>,
+}
+
+impl ChangeEvent {
+  pub(crate) fn update(doc_id: Option<String>, row_id: Option<i64>) -> Self {
+    Self {
+      table: "updates".to_string(),
+      doc_id,
+      key: None,
+      row_id,
+    }
+  }
+
+  pub(crate) fn keyed(table: &str, key: Option<String>) -> Self {
+    Self {
+      table: table.to_string(),
+      doc_id: None,
+      key,
+      row_id: None,
+    }
+  }
+
+  pub(crate) fn tombstone(doc_id: String, row_id: Option<i64>) -> Self {
+    Self {
+      table: "doc_tombstones".to_string(),
+      doc_id: Some(doc_id),
+      key: None,
+      row_id,
+    }
+  }
+
+  pub(crate) fn any() -> Self {
+    Self::keyed(ANY_TABLE, None)
+  }
+}
+
+/// Subscribers of a connection.
+#[derive(Default)]
+pub(crate) struct Subscriptions {
+  next_id: u32,
+  callbacks: HashMap<u32, ThreadsafeFunction<ChangeEvent>>,
+  poller: Option<JoinHandle<()>>,
+  /// Events already reported by this connection since the last poll, the
+  /// poller finds them in the change log as well.
+  local_events: HashSet<ChangeEvent>,
+}
+
+impl Subscriptions {
+  fn emit(&self, events: &[ChangeEvent]) {
+    for callback in self.callbacks.values() {
+      for event in events {
+        callback.call(Ok(event.clone()), ThreadsafeFunctionCallMode::NonBlocking);
+      }
+    }
+  }
+
+  /// Report changes committed through the owning connection, the poller
+  /// skips them.
+  pub(crate) fn notify(&mut self, events: &[ChangeEvent]) {
+    if self.callbacks.is_empty() {
+      return;
+    }
+    self.local_events.extend(events.iter().cloned());
+    self.emit(events);
+  }
+
+  pub(crate) fn stop(&mut self) {
+    self.callbacks.clear();
+    self.local_events.clear();
+    if let Some(poller) = self.poller.take() {
+      poller.abort();
+    }
+  }
+}
+
+/// What the poller has seen so far.
+pub(crate) struct PollState {
+  data_version: i64,
+  /// Id of the last `change_log` row seen, `None` if the file has no change
+  /// log, e.g. a read-only connection to a file that was never migrated.
+  last_change_id: Option<i64>,
+}
+
+impl PollState {
+  /// Start from the current state of the file, `connection` has to be the
+  /// one polled afterwards since `data_version` is per connection.
+  pub(crate) async fn read(connection: &mut sqlx::SqliteConnection) -> sqlx::Result<Self> {
+    let data_version: i64 = sqlx::query_scalar("PRAGMA data_version")
+      .fetch_one(&mut *connection)
+      .await?;
+    // read-only connections skip migrations, the table may not exist
+    let change_log: Option<String> =
+      sqlx::query_scalar("SELECT name FROM sqlite_master WHERE type='table' AND name='change_log'")
+        .fetch_optional(&mut *connection)
+        .await?;
+    let last_change_id = match change_log {
+      Some(_) => Some(
+        sqlx::query_scalar("SELECT COALESCE(MAX(id), 0) FROM change_log")
+          .fetch_one(&mut *connection)
+          .await?,
+      ),
+      None => None,
+    };
+    Ok(Self {
+      data_version,
+      last_change_id,
+    })
+  }
+}
+
+/// Whether a logged change was reported by the owning connection already.
+/// Clearing a namespace is reported once for all its keys, and a write that
+/// replaces a row logs the delete of the former one as well.
+fn reported(local_events: &HashSet<ChangeEvent>, event: &ChangeEvent) -> bool {
+  if local_events.contains(event) {
+    return true;
+  }
+  if event.key.is_some() {
+    let cleared = ChangeEvent {
+      key: None,
+      ..event.clone()
+    };
+    if local_events.contains(&cleared) {
+      return true;
+    }
+  }
+  event.row_id.is_none()
+    && local_events.iter().any(|local| {
+      local.table == event.table && local.doc_id == event.doc_id && local.key == event.key
+    })
+}
+
+async fn poll_changes(
+  mut connection: sqlx::SqliteConnection,
+  mut state: PollState,
+  subscriptions: Arc<Mutex<Subscriptions>>,
+) {
+  let mut interval = tokio::time::interval(DATA_VERSION_POLL_INTERVAL);
+  loop {
+    interval.tick().await;
+    // a failed poll is retried on the next tick
+    if let Ok(events) = poll_once(&mut connection, &mut state, &subscriptions).await {
+      subscriptions.lock().emit(&events);
+    }
+  }
+}
+
+/// Changes committed by other connections since the last poll.
+pub(crate) async fn poll_once(
+  connection: &mut sqlx::SqliteConnection,
+  state: &mut PollState,
+  subscriptions: &Mutex<Subscriptions>,
+) -> sqlx::Result<Vec<ChangeEvent>> {
+  // `data_version` only changes when another connection commits, the pool of
+  // the owning connection included
+  let data_version: i64 = sqlx::query_scalar("PRAGMA data_version")
+    .fetch_one(&mut *connection)
+    .await?;
+  if state.data_version == data_version {
+    return Ok(Vec::new());
+  }
+  let Some(last_change_id) = state.last_change_id else {
+    // nothing tells what changed without a change log
+    state.data_version = data_version;
+    return Ok(vec![ChangeEvent::any()]);
+  };
+
+  let changes =
+    sqlx::query("SELECT id, source, doc_id, key, row_id FROM change_log WHERE id > ? ORDER BY id")
+      .bind(last_change_id)
+      .fetch_all(&mut *connection)
+      .await?;
+
+  let local_events = std::mem::take(&mut subscriptions.lock().local_events);
+  let mut events = Vec::new();
+  let mut seen = HashSet::new();
+  if let Some(first) = changes.first() {
+    // the change log only keeps its latest rows, everything may have changed
+    // while this connection was not polling
+    if first.get::<i64, _>(0) > last_change_id + 1 {
+      events.push(ChangeEvent::any());
+    }
+  }
+  for row in changes {
+    let id: i64 = row.get(0);
+    state.last_change_id = Some(id);
+    let source: String = row.get(1);
+    let event = ChangeEvent {
+      table: source,
+      doc_id: row.get(2),
+      key: row.get(3),
+      row_id: row.get(4),
+    };
+    // a delete logs a row for every deleted row, report it once
+    if !reported(&local_events, &event) && seen.insert(event.clone()) {
+      events.push(event);
+    }
+  }
+
+  state.data_version = data_version;
+  Ok(events)
+}
+
+#[napi]
+impl SqliteConnection {
+  /// Call `callback` whenever rows change, through this connection or any
+  /// other connection to the same file. Changes of other connections are
+  /// read from the change log by polling and may be reported with a delay.
+  #[napi]
+  pub async fn subscribe(&self, callback: ThreadsafeFunction<ChangeEvent>) -> Result<u32> {
+    let needs_poller = self.subscriptions.lock().poller.is_none();
+    let poller_connection = if needs_poller {
+      // `data_version` is per connection, the poller needs one of its own
+      let mut connection = self.pool.acquire().await.map_err(database_error)?.detach();
+      let state = PollState::read(&mut connection)
+        .await
+        .map_err(database_error)?;
+      Some((connection, state))
+    } else {
+      None
+    };
+
+    let mut subscriptions = self.subscriptions.lock();
+    let id = subscriptions.next_id;
+    subscriptions.next_id += 1;
+    subscriptions.callbacks.insert(id, callback);
+    if let Some((connection, state)) = poller_connection {
+      // another call may have started the poller in the meantime
+      if subscriptions.poller.is_none() {
+        subscriptions.poller = Some(tokio::spawn(poll_changes(
+          connection,
+          state,
+          self.subscriptions.clone(),
+        )));
+      }
+    }
+    Ok(id)
+  }
+
+  #[napi]
+  pub fn unsubscribe(&self, id: u32) {
+    let mut subscriptions = self.subscriptions.lock();
+    subscriptions.callbacks.remove(&id);
+    if subscriptions.callbacks.is_empty() {
+      subscriptions.stop();
+    }
+  }
+
+  /// Report changes committed through this connection.
+  pub(crate) fn notify(&self, events: Vec<ChangeEvent>) {
+    self.subscriptions.lock().notify(&events);
+  }
+}
@@ -0,0 +1,1463 @@
+use std::{collections::HashSet, path::PathBuf, sync::Arc};
+
+use parking_lot::Mutex;
+
+use super::{
+  kv::prefix_end,
+  migration::LATEST_VERSION,
+  options::MEMORY_PATH,
+  subscription::{poll_once, PollState, Subscriptions},
+  ydoc::{apply_updates, collect_strings, encode_state_vector, merge_updates},
+  BlobGcOptions, CompactionOptions, ConnectionManager, ConnectionOptions, InsertRow,
+  IntegrityCheckOptions, ListBlobsOptions, LoadDocOptions, PurgeDocsOptions, SqliteConnection,
+  SqliteErrorCode, VacuumActivity, ValidationResult,
+};
+
+/// Workspace files as written by earlier releases, see `fixture`.
+const FIXTURES_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/__tests__/fixtures");
+
+/// A scratch file of its own for every test, removed with its directory when
+/// dropped.
+struct TempFile {
+  dir: PathBuf,
+  path: PathBuf,
+}
+
+impl TempFile {
+  fn new(name: &str) -> Self {
+    let dir = std::env::temp_dir().join(format!("affine-native-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join(name);
+    Self { dir, path }
+  }
+
+  /// Copy of a fixture, so that migrating it leaves the original untouched.
+  /// - `v1.affine`: `updates` without `doc_id` and `blobs`
+  /// - `test01.affine`: version 2, `doc_id` added
+  /// - `v3.affine`: `version_info` added
+  /// - `v4.affine`: `server_clock` and `sync_metadata` added
+  fn fixture(name: &str) -> Self {
+    let file = Self::new(name);
+    std::fs::copy(PathBuf::from(FIXTURES_DIR).join(name), &file.path).unwrap();
+    file
+  }
+
+  fn path(&self) -> String {
+    self.path.to_string_lossy().into_owned()
+  }
+}
+
+impl Drop for TempFile {
+  fn drop(&mut self) {
+    std::fs::remove_dir_all(&self.dir).ok();
+  }
+}
+
+async fn connect(path: String, options: Option<ConnectionOptions>) -> SqliteConnection {
+  let connection = SqliteConnection::new(path, options).unwrap();
+  connection.connect().await.unwrap();
+  connection
+}
+
+async fn memory() -> SqliteConnection {
+  connect(MEMORY_PATH.to_string(), None).await
+}
+
+fn row(doc_id: Option<&str>, data: &[u8]) -> InsertRow {
+  InsertRow {
+    doc_id: doc_id.map(|doc_id| doc_id.to_string()),
+    data: data.to_vec().into(),
+  }
+}
+
+async fn assert_latest(connection: &SqliteConnection) {
+  let versions = connection
+    .get_migration_history()
+    .await
+    .unwrap()
+    .into_iter()
+    .map(|record| record.version)
+    .collect::<Vec<_>>();
+  assert_eq!(versions.last(), Some(&LATEST_VERSION));
+  assert_eq!(
+    connection.get_max_version().await.unwrap(),
+    LATEST_VERSION as i64
+  );
+  assert!(connection.migrate().await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_migrate_v1() {
+  let file = TempFile::fixture("v1.affine");
+  assert!(matches!(
+    SqliteConnection::validate(file.path()).await,
+    ValidationResult::MissingDocIdColumn
+  ));
+  let connection = connect(file.path(), None).await;
+  assert_latest(&connection).await;
+  // updates written before docs had ids belong to the root doc
+  assert_eq!(connection.get_updates(None, None).await.unwrap().len(), 3);
+  let blob = connection.get_blob("blob".into()).await.unwrap().unwrap();
+  assert_eq!(blob.data.as_ref(), b"blob data");
+}
+
+#[tokio::test]
+async fn test_migrate_v2() {
+  let file = TempFile::fixture("test01.affine");
+  assert!(matches!(
+    SqliteConnection::validate(file.path()).await,
+    ValidationResult::MissingVersionColumn
+  ));
+  let connection = connect(file.path(), None).await;
+  assert_latest(&connection).await;
+  assert_eq!(connection.get_all_updates().await.unwrap().len(), 505);
+  assert!(matches!(
+    SqliteConnection::validate(file.path()).await,
+    ValidationResult::Valid
+  ));
+}
+
+#[tokio::test]
+async fn test_migrate_v3() {
+  let file = TempFile::fixture("v3.affine");
+  let connection = connect(file.path(), None).await;
+  assert_latest(&connection).await;
+  assert_eq!(connection.get_updates(None, None).await.unwrap().len(), 1);
+  assert_eq!(
+    connection
+      .get_updates(Some("doc".into()), None)
+      .await
+      .unwrap()
+      .len(),
+    2
+  );
+  assert!(connection.get_server_clock_keys().await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_migrate_v4() {
+  let file = TempFile::fixture("v4.affine");
+  let connection = connect(file.path(), None).await;
+  assert_latest(&connection).await;
+  assert_eq!(
+    connection
+      .get_updates(Some("doc".into()), None)
+      .await
+      .unwrap()
+      .len(),
+    2
+  );
+  // the sync tables moved into the key-value store
+  let clock = connection.get_server_clock("doc".into()).await.unwrap();
+  assert_eq!(clock.unwrap().data.as_ref(), b"clock");
+  let metadata = connection.get_sync_metadata("peer".into()).await.unwrap();
+  assert_eq!(metadata.unwrap().data.as_ref(), b"meta");
+  // and stay in place for earlier releases
+  let legacy: Vec<u8> = sqlx::query_scalar("SELECT data FROM server_clock WHERE key = 'doc'")
+    .fetch_one(&connection.pool)
+    .await
+    .unwrap();
+  assert_eq!(legacy, b"clock");
+}
+
+#[tokio::test]
+async fn test_kv_prefix_scan() {
+  let connection = memory().await;
+  let keys = ["a", "a/1", "a/2", "a0", "b", "\u{10ffff}"];
+  for key in keys {
+    connection
+      .kv_set("ns".into(), key.into(), key.as_bytes().to_vec().into())
+      .await
+      .unwrap();
+  }
+
+  let scanned = connection
+    .kv_scan("ns".into(), Some("a/".into()))
+    .await
+    .unwrap();
+  let scanned = scanned.iter().map(|row| row.key.as_str());
+  assert_eq!(scanned.collect::<Vec<_>>(), vec!["a/1", "a/2"]);
+  assert_eq!(
+    connection.kv_keys("ns".into(), None).await.unwrap(),
+    keys.to_vec()
+  );
+  // no key is after the last code point, the scan has no end
+  assert_eq!(
+    connection
+      .kv_keys("ns".into(), Some("\u{10ffff}".into()))
+      .await
+      .unwrap(),
+    vec!["\u{10ffff}"]
+  );
+  assert_eq!(prefix_end("a/").as_deref(), Some("a0"));
+  assert_eq!(prefix_end("\u{10ffff}"), None);
+}
+
+#[tokio::test]
+async fn test_version_handling() {
+  let connection = memory().await;
+  assert_latest(&connection).await;
+  assert!(connection.set_version(LATEST_VERSION + 1).await.is_err());
+  // the history of applied migrations is kept
+  let history = connection.get_migration_history().await.unwrap().len();
+  connection.set_version(LATEST_VERSION).await.unwrap();
+  assert_eq!(
+    connection.get_migration_history().await.unwrap().len(),
+    history + 1
+  );
+
+  // files written by a newer release are not touched
+  sqlx::query("INSERT INTO version_info (version) VALUES (?)")
+    .bind(LATEST_VERSION + 1)
+    .execute(&connection.pool)
+    .await
+    .unwrap();
+  assert_eq!(
+    connection.get_max_version().await.unwrap(),
+    LATEST_VERSION as i64 + 1
+  );
+  assert!(connection.migrate().await.is_err());
+}
+
+#[tokio::test]
+async fn test_journal_mode_kept() {
+  let file = TempFile::new("journal.affine");
+  for _ in 0..2 {
+    let connection = connect(file.path(), None).await;
+    let mode: String = sqlx::query_scalar("PRAGMA journal_mode")
+      .fetch_one(&connection.pool)
+      .await
+      .unwrap();
+    // new files start in WAL mode, reopening does not switch them back
+    assert_eq!(mode, "wal");
+    // `NORMAL` follows the mode of the file, not the requested one
+    let synchronous: i64 = sqlx::query_scalar("PRAGMA synchronous")
+      .fetch_one(&connection.pool)
+      .await
+      .unwrap();
+    assert_eq!(synchronous, 1);
+    connection.close().await;
+  }
+}
+
+#[tokio::test]
+async fn test_blob_upsert() {
+  let connection = memory().await;
+  assert!(connection.get_blob("key".into()).await.unwrap().is_none());
+
+  connection
+    .add_blob("key".into(), b"first".to_vec().into())
+    .await
+    .unwrap();
+  let first = connection.get_blob("key".into()).await.unwrap().unwrap();
+  connection
+    .add_blob("key".into(), b"second".to_vec().into())
+    .await
+    .unwrap();
+  let second = connection.get_blob("key".into()).await.unwrap().unwrap();
+  assert_eq!(second.data.as_ref(), b"second");
+  // only the data is replaced
+  assert_eq!(second.timestamp, first.timestamp);
+  assert_eq!(connection.get_blob_keys().await.unwrap(), vec!["key"]);
+
+  connection.delete_blob("key".into()).await.unwrap();
+  assert!(connection.get_blob("key".into()).await.unwrap().is_none());
+  assert!(connection.get_blob_keys().await.unwrap().is_empty());
+}
+
+/// An in-memory workspace, encrypted if `encrypted` is set.
+async fn memory_with(encrypted: bool) -> SqliteConnection {
+  let connection = memory().await;
+  if encrypted {
+    connection
+      .rotate_encryption_key(Some(vec![7; 32].into()))
+      .await
+      .unwrap();
+  }
+  connection
+}
+
+#[tokio::test]
+async fn test_blob_range() {
+  for encrypted in [false, true] {
+    let connection = memory_with(encrypted).await;
+    connection
+      .add_blob("key".into(), b"0123456789".to_vec().into())
+      .await
+      .unwrap();
+    let connection = &connection;
+    let range = move |offset, length| connection.get_blob_range("key".into(), offset, length);
+
+    assert_eq!(range(2, 3).await.unwrap().unwrap().as_ref(), b"234");
+    // clamped to the blob like a HTTP Range request
+    assert_eq!(range(7, 100).await.unwrap().unwrap().as_ref(), b"789");
+    assert!(range(20, 5).await.unwrap().unwrap().is_empty());
+    assert!(range(2, -1).await.unwrap().unwrap().is_empty());
+    assert!(connection
+      .get_blob_range("missing".into(), 0, 1)
+      .await
+      .unwrap()
+      .is_none());
+  }
+}
+
+#[tokio::test]
+async fn test_chunked_blob_upload() {
+  for encrypted in [false, true] {
+    let connection = memory_with(encrypted).await;
+    let upload_id = connection.begin_blob_upload();
+    for chunk in [b"hello ".as_slice(), b"chunked ", b"world"] {
+      connection
+        .append_blob_chunk(upload_id.clone(), chunk.to_vec().into())
+        .await
+        .unwrap();
+    }
+    // staged chunks are not a blob yet
+    assert!(connection.get_blob("key".into()).await.unwrap().is_none());
+
+    connection
+      .commit_blob_upload(upload_id.clone(), "key".into())
+      .await
+      .unwrap();
+    let blob = connection.get_blob("key".into()).await.unwrap().unwrap();
+    assert_eq!(blob.data.as_ref(), b"hello chunked world");
+    assert_eq!(
+      connection.get_blob_size("key".into()).await.unwrap(),
+      Some(19)
+    );
+    // the chunks are gone with the commit
+    assert!(connection
+      .commit_blob_upload(upload_id, "other".into())
+      .await
+      .is_err());
+  }
+}
+
+#[tokio::test]
+async fn test_import_export_blob() {
+  let dir = TempFile::new("source.bin");
+  // spans several copy chunks
+  let data = (0..3 * 1024 * 1024 + 17)
+    .map(|i| (i % 251) as u8)
+    .collect::<Vec<_>>();
+  std::fs::write(&dir.path, &data).unwrap();
+  let target = dir.dir.join("target.bin").to_string_lossy().into_owned();
+
+  for encrypted in [false, true] {
+    let connection = memory_with(encrypted).await;
+    connection
+      .import_blob("key".into(), dir.path())
+      .await
+      .unwrap();
+    assert_eq!(
+      connection.get_blob_size("key".into()).await.unwrap(),
+      Some(data.len() as i64)
+    );
+
+    assert!(connection
+      .export_blob("key".into(), target.clone())
+      .await
+      .unwrap());
+    assert_eq!(std::fs::read(&target).unwrap(), data);
+    assert!(!PathBuf::from(format!("{target}.tmp")).exists());
+    std::fs::remove_file(&target).unwrap();
+
+    assert!(!connection
+      .export_blob("missing".into(), target.clone())
+      .await
+      .unwrap());
+    assert!(!PathBuf::from(&target).exists());
+  }
+}
+
+#[tokio::test]
+async fn test_list_blobs_prefix_and_legacy_mime() {
+  let connection = memory().await;
+  for key in ["img/a", "img/b", "img0", "txt/a"] {
+    connection
+      .add_blob(key.into(), b"%PDF-1.7\n".to_vec().into())
+      .await
+      .unwrap();
+  }
+  // as written before the size and mime type were stored
+  sqlx::query("UPDATE blobs SET size = NULL, mime = NULL")
+    .execute(&connection.pool)
+    .await
+    .unwrap();
+
+  let page = connection
+    .list_blobs(Some(ListBlobsOptions {
+      prefix: Some("img/".into()),
+      ..Default::default()
+    }))
+    .await
+    .unwrap();
+  let keys = page.blobs.iter().map(|blob| blob.key.as_str());
+  assert_eq!(keys.collect::<Vec<_>>(), vec!["img/a", "img/b"]);
+  assert_eq!(page.blobs[0].mime, "application/pdf");
+
+  // the sniffed blobs are stored, the others are left for their listing
+  let stored: Vec<(String, Option<String>)> =
+    sqlx::query_as("SELECT key, mime FROM blobs ORDER BY key")
+      .fetch_all(&connection.pool)
+      .await
+      .unwrap();
+  let pdf = Some("application/pdf".to_string());
+  assert_eq!(
+    stored,
+    vec![
+      ("img/a".to_string(), pdf.clone()),
+      ("img/b".to_string(), pdf),
+      ("img0".to_string(), None),
+      ("txt/a".to_string(), None),
+    ]
+  );
+}
+
+#[tokio::test]
+async fn test_list_blobs_empty_key() {
+  let connection = memory().await;
+  for key in ["", "a", "b"] {
+    connection
+      .add_blob(key.into(), b"data".to_vec().into())
+      .await
+      .unwrap();
+  }
+  let first = connection
+    .list_blobs(Some(ListBlobsOptions {
+      limit: Some(2),
+      ..Default::default()
+    }))
+    .await
+    .unwrap();
+  let keys = first.blobs.iter().map(|blob| blob.key.as_str());
+  assert_eq!(keys.collect::<Vec<_>>(), vec!["", "a"]);
+  let second = connection
+    .list_blobs(Some(ListBlobsOptions {
+      after_key: first.next_cursor,
+      limit: Some(2),
+      ..Default::default()
+    }))
+    .await
+    .unwrap();
+  let keys = second.blobs.iter().map(|blob| blob.key.as_str());
+  assert_eq!(keys.collect::<Vec<_>>(), vec!["b"]);
+  assert_eq!(second.next_cursor, None);
+}
+
+#[tokio::test]
+async fn test_gc_blobs_quarantined_reference() {
+  let connection = memory().await;
+  for key in ["kept", "unused"] {
+    connection
+      .add_blob(key.into(), b"data".to_vec().into())
+      .await
+      .unwrap();
+  }
+  sqlx::query("UPDATE blobs SET timestamp = '2024-01-01 00:00:00'")
+    .execute(&connection.pool)
+    .await
+    .unwrap();
+  // an update that can not be decoded any more still references its blob
+  sqlx::query(
+    "INSERT INTO quarantine (source, reason, row_id, doc_id, data, timestamp)
+    VALUES ('updates', 'corrupted update', 1, 'doc', ?, CURRENT_TIMESTAMP)",
+  )
+  .bind(b"\x01\x04kept\xff".to_vec())
+  .execute(&connection.pool)
+  .await
+  .unwrap();
+
+  let result = connection
+    .gc_blobs(Some(BlobGcOptions {
+      dry_run: Some(false),
+      ..Default::default()
+    }))
+    .await
+    .unwrap();
+  assert_eq!(result.unreferenced, vec!["unused"]);
+  assert!(connection.get_blob("kept".into()).await.unwrap().is_some());
+  assert!(connection
+    .get_blob("unused".into())
+    .await
+    .unwrap()
+    .is_none());
+}
+
+#[tokio::test]
+async fn test_gc_blobs_keeps_other_roots() {
+  let connection = memory().await;
+  for key in ["avatar", "unused"] {
+    connection
+      .add_blob(key.into(), b"data".to_vec().into())
+      .await
+      .unwrap();
+  }
+  sqlx::query("UPDATE blobs SET timestamp = '2024-01-01 00:00:00'")
+    .execute(&connection.pool)
+    .await
+    .unwrap();
+  connection
+    .insert_updates(vec![row(
+      Some("doc"),
+      &root_update(1, "settings", "icon", "avatar"),
+    )])
+    .await
+    .unwrap();
+
+  let result = connection
+    .gc_blobs(Some(BlobGcOptions {
+      dry_run: Some(false),
+      ..Default::default()
+    }))
+    .await
+    .unwrap();
+  assert_eq!(result.unreferenced, vec!["unused"]);
+  assert!(connection
+    .get_blob("avatar".into())
+    .await
+    .unwrap()
+    .is_some());
+}
+
+#[tokio::test]
+async fn test_memory_isolation() {
+  let first = memory().await;
+  let second = memory().await;
+  first
+    .insert_updates(vec![row(Some("doc"), b"update")])
+    .await
+    .unwrap();
+  assert_eq!(first.get_all_updates().await.unwrap().len(), 1);
+  assert!(second.get_all_updates().await.unwrap().is_empty());
+
+  first.close().await;
+  assert!(first.is_close());
+  assert!(second.get_all_updates().await.unwrap().is_empty());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_concurrent_writes() {
+  const TASKS: usize = 8;
+  const UPDATES: usize = 10;
+
+  let file = TempFile::new("concurrent.affine");
+  let connection = Arc::new(
+    connect(
+      file.path(),
+      Some(ConnectionOptions {
+        busy_timeout: Some(10_000),
+        ..Default::default()
+      }),
+    )
+    .await,
+  );
+
+  let mut tasks = Vec::new();
+  for task in 0..TASKS {
+    let connection = connection.clone();
+    tasks.push(tokio::spawn(async move {
+      for update in 0..UPDATES {
+        let data = [task as u8, update as u8];
+        connection
+          .insert_updates(vec![row(Some("inserted"), &data), row(None, &data)])
+          .await
+          .unwrap();
+        if update % 3 == 0 {
+          // every replacement is tagged with its task and round, the root
+          // doc is not indexed so the data does not need to be an update
+          connection
+            .replace_updates(
+              None,
+              vec![
+                row(None, &[0xff, task as u8, update as u8]),
+                row(None, &[0xfe, task as u8, update as u8]),
+              ],
+            )
+            .await
+            .unwrap();
+        }
+      }
+    }));
+  }
+  for task in tasks {
+    task.await.unwrap();
+  }
+
+  let inserted = connection
+    .get_updates(Some("inserted".into()), None)
+    .await
+    .unwrap();
+  assert_eq!(inserted.len(), TASKS * UPDATES);
+
+  // exactly one replacement survived, with the inserts that came after it
+  let replaced = connection.get_updates(None, None).await.unwrap();
+  let tags = replaced
+    .iter()
+    .filter(|row| row.data.len() == 3)
+    .map(|row| (row.data[1], row.data[2]))
+    .collect::<Vec<_>>();
+  assert_eq!(tags.len(), 2);
+  assert_eq!(tags[0], tags[1]);
+  let first_id = replaced
+    .iter()
+    .filter(|row| row.data.len() == 3)
+    .map(|row| row.id)
+    .min()
+    .unwrap();
+  assert!(replaced.iter().all(|row| row.id >= first_id));
+}
+
+#[tokio::test]
+async fn test_upgrade() {
+  let file = TempFile::fixture("v1.affine");
+  let original = std::fs::read(&file.path).unwrap();
+  let result = SqliteConnection::upgrade(file.path()).await.unwrap();
+  assert!(matches!(
+    result.previous,
+    ValidationResult::MissingDocIdColumn
+  ));
+  assert_eq!(
+    result.migrations.last().map(|record| record.version),
+    Some(LATEST_VERSION)
+  );
+  // the backup is the untouched original
+  assert_eq!(
+    std::fs::read(result.backup_path.unwrap()).unwrap(),
+    original
+  );
+  assert!(matches!(
+    SqliteConnection::validate(file.path()).await,
+    ValidationResult::Valid
+  ));
+
+  let result = SqliteConnection::upgrade(file.path()).await.unwrap();
+  assert!(result.backup_path.is_none());
+  assert!(result.migrations.is_empty());
+}
+
+#[tokio::test]
+async fn test_upgrade_rollback() {
+  let file = TempFile::fixture("v4.affine");
+  // a table in the way of a later migration
+  let connection = SqliteConnection::new(file.path(), None).unwrap();
+  sqlx::query("CREATE TABLE kv (value TEXT)")
+    .execute(&connection.pool)
+    .await
+    .unwrap();
+  connection.close().await;
+  let original = std::fs::read(&file.path).unwrap();
+
+  assert!(SqliteConnection::upgrade(file.path()).await.is_err());
+  assert_eq!(std::fs::read(&file.path).unwrap(), original);
+  assert!(matches!(
+    SqliteConnection::validate(file.path()).await,
+    ValidationResult::Valid
+  ));
+}
+
+#[tokio::test]
+async fn test_rotate_key_after_transaction() {
+  let connection = memory().await;
+  let transaction = connection.begin_transaction(None).await.unwrap();
+  transaction
+    .replace_updates(None, vec![row(None, b"update")])
+    .await
+    .unwrap();
+  transaction.commit().await.unwrap();
+
+  // the handle is still alive, as it is until JS collects it
+  let rotated = tokio::time::timeout(
+    std::time::Duration::from_secs(5),
+    connection.rotate_encryption_key(Some(vec![7; 32].into())),
+  )
+  .await;
+  assert!(rotated.is_ok_and(|rotated| rotated.is_ok()));
+  assert_eq!(connection.get_updates(None, None).await.unwrap().len(), 1);
+  drop(transaction);
+}
+
+#[tokio::test]
+async fn test_idle_transaction_rolls_back() {
+  let connection = memory().await;
+  let transaction = connection.begin_transaction(Some(50)).await.unwrap();
+  transaction
+    .insert_updates(vec![row(None, b"update")])
+    .await
+    .unwrap();
+  tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+
+  // the write lock and the cipher are released without a commit
+  let rotated = tokio::time::timeout(
+    std::time::Duration::from_secs(5),
+    connection.rotate_encryption_key(Some(vec![7; 32].into())),
+  )
+  .await;
+  assert!(rotated.is_ok_and(|rotated| rotated.is_ok()));
+  assert!(connection.get_updates(None, None).await.unwrap().is_empty());
+  assert!(transaction.commit().await.is_err());
+}
+
+#[tokio::test]
+async fn test_error_code() {
+  let file = TempFile::new("workspace.affine");
+  connect(file.path(), None).await.close().await;
+  let options = ConnectionOptions {
+    read_only: Some(true),
+    ..Default::default()
+  };
+  let connection = connect(file.path(), Some(options)).await;
+
+  let err = connection
+    .add_blob("a".into(), b"blob".to_vec().into())
+    .await
+    .unwrap_err();
+  assert!(matches!(err.status, SqliteErrorCode::Readonly));
+  let err = connection
+    .set_version(LATEST_VERSION + 1)
+    .await
+    .unwrap_err();
+  assert!(matches!(err.status, SqliteErrorCode::Unknown));
+}
+
+#[tokio::test]
+async fn test_wrong_key_error_code() {
+  let file = TempFile::new("workspace.affine");
+  let key = |byte: u8| ConnectionOptions {
+    encryption_key: Some(vec![byte; 32].into()),
+    ..Default::default()
+  };
+  connect(file.path(), Some(key(1))).await.close().await;
+
+  for options in [Some(key(2)), None] {
+    let connection = SqliteConnection::new(file.path(), options).unwrap();
+    let err = connection.connect().await.unwrap_err();
+    assert!(matches!(err.status, SqliteErrorCode::NotADatabase));
+  }
+}
+
+#[tokio::test]
+async fn test_manager_shares_connection() {
+  let file = TempFile::new("workspace.affine");
+  let manager = ConnectionManager::new(None);
+  let first = manager.open(file.path(), None).await.unwrap();
+  let second = manager.open(file.path(), None).await.unwrap();
+  let read_only = ConnectionOptions {
+    read_only: Some(true),
+    ..Default::default()
+  };
+  assert!(manager.open(file.path(), Some(read_only)).await.is_err());
+
+  // closing a shared connection only gives it back, once
+  first.close().await;
+  first.close().await;
+  assert!(!second.is_close());
+  assert_eq!(manager.close_idle().await, 0);
+  second.close().await;
+  assert_eq!(manager.close_idle().await, 1);
+  assert!(second.is_close());
+}
+
+#[tokio::test]
+async fn test_manager_memory_not_shared() {
+  let manager = ConnectionManager::new(None);
+  let first = manager.open(MEMORY_PATH.into(), None).await.unwrap();
+  let second = manager.open(MEMORY_PATH.into(), None).await.unwrap();
+  first
+    .insert_updates(vec![row(None, b"update")])
+    .await
+    .unwrap();
+  assert!(second.get_all_updates().await.unwrap().is_empty());
+  assert!(manager.open_paths().await.is_empty());
+
+  first.close().await;
+  assert!(first.is_close());
+  assert!(!second.is_close());
+}
+
+#[tokio::test]
+async fn test_rotation_reports_skipped_quarantine() {
+  let connection = memory().await;
+  connection
+    .rotate_encryption_key(Some(vec![7; 32].into()))
+    .await
+    .unwrap();
+  let id = sqlx::query(
+    "INSERT INTO quarantine (source, reason, row_id, doc_id, data, timestamp)
+    VALUES ('updates', 'corrupted update', 1, 'doc', ?, CURRENT_TIMESTAMP)",
+  )
+  .bind(b"not sealed".to_vec())
+  .execute(&connection.pool)
+  .await
+  .unwrap()
+  .last_insert_rowid();
+
+  let rotated = connection
+    .rotate_encryption_key(Some(vec![8; 32].into()))
+    .await
+    .unwrap();
+  assert_eq!(rotated.skipped_quarantine_ids, vec![id]);
+}
+
+#[tokio::test]
+async fn test_encrypted_payload_bound_to_row() {
+  let connection = memory().await;
+  connection
+    .rotate_encryption_key(Some(vec![7; 32].into()))
+    .await
+    .unwrap();
+  for (key, data) in [("a", b"first".as_slice()), ("b", b"second!")] {
+    connection
+      .add_blob(key.into(), data.to_vec().into())
+      .await
+      .unwrap();
+  }
+
+  let blobs = connection.list_blobs(None).await.unwrap().blobs;
+  let sizes = blobs.iter().map(|blob| blob.size);
+  assert_eq!(sizes.collect::<Vec<_>>(), vec![5, 7]);
+
+  // a payload copied into another row does not decrypt as that row
+  sqlx::query("UPDATE blobs SET data = (SELECT data FROM blobs WHERE key = 'b') WHERE key = 'a'")
+    .execute(&connection.pool)
+    .await
+    .unwrap();
+  assert!(connection.get_blob("a".into()).await.is_err());
+  let b = connection.get_blob("b".into()).await.unwrap().unwrap();
+  assert_eq!(b.data.as_ref(), b"second!");
+}
+
+#[tokio::test]
+async fn test_change_log() {
+  let connection = memory().await;
+  connection
+    .insert_updates(vec![row(Some("doc"), b"update")])
+    .await
+    .unwrap();
+  for data in [b"first", b"again"] {
+    connection
+      .add_blob("key".into(), data.to_vec().into())
+      .await
+      .unwrap();
+  }
+  connection
+    .kv_set("ns".into(), "key".into(), b"value".to_vec().into())
+    .await
+    .unwrap();
+  connection.delete_updates(Some("doc".into())).await.unwrap();
+
+  let changes: Vec<(String, Option<String>, Option<String>, Option<i64>)> =
+    sqlx::query_as("SELECT source, doc_id, key, row_id FROM change_log ORDER BY id")
+      .fetch_all(&connection.pool)
+      .await
+      .unwrap();
+  let update_id = changes[0].3;
+  assert!(update_id.is_some());
+  assert_eq!(
+    changes,
+    vec![
+      ("updates".into(), Some("doc".into()), None, update_id),
+      ("blobs".into(), None, Some("key".into()), None),
+      // an upsert of an existing blob is logged as well
+      ("blobs".into(), None, Some("key".into()), None),
+      ("ns".into(), None, Some("key".into()), None),
+      ("updates".into(), Some("doc".into()), None, None),
+    ]
+  );
+}
+
+#[tokio::test]
+async fn test_poll_changes_of_other_connection() {
+  let file = TempFile::new("workspace.affine");
+  let connection = connect(file.path(), None).await;
+  let other = connect(file.path(), None).await;
+  let subscriptions = Mutex::new(Subscriptions::default());
+  let mut poller = connection.pool.acquire().await.unwrap().detach();
+  let mut state = PollState::read(&mut poller).await.unwrap();
+  assert!(poll_once(&mut poller, &mut state, &subscriptions)
+    .await
+    .unwrap()
+    .is_empty());
+
+  other
+    .insert_updates(vec![row(Some("doc"), b"update")])
+    .await
+    .unwrap();
+  for key in ["a", "b"] {
+    other
+      .kv_set("ns".into(), key.into(), b"value".to_vec().into())
+      .await
+      .unwrap();
+  }
+  other.kv_clear("ns".into()).await.unwrap();
+  other.trash_doc("doc".into()).await.unwrap();
+
+  let events = poll_once(&mut poller, &mut state, &subscriptions)
+    .await
+    .unwrap()
+    .into_iter()
+    .map(|event| (event.table, event.doc_id, event.key, event.row_id.is_some()))
+    .collect::<Vec<_>>();
+  assert_eq!(
+    events,
+    vec![
+      ("updates".into(), Some("doc".into()), None, true),
+      ("ns".into(), None, Some("a".into()), false),
+      ("ns".into(), None, Some("b".into()), false),
+      ("doc_tombstones".into(), Some("doc".into()), None, true),
+    ]
+  );
+  // nothing changed since
+  assert!(poll_once(&mut poller, &mut state, &subscriptions)
+    .await
+    .unwrap()
+    .is_empty());
+}
+
+#[tokio::test]
+async fn test_backup_to() {
+  let file = TempFile::new("workspace.affine");
+  let connection = connect(file.path(), None).await;
+  connection
+    .insert_updates((0..10_000).map(|_| row(Some("doc"), &[0; 64])).collect())
+    .await
+    .unwrap();
+  // the indexer must not write to the workspace while it is backed up
+  connection.flush_index().await;
+
+  let backup = file
+    .dir
+    .join("backup.affine")
+    .to_string_lossy()
+    .into_owned();
+  let mut remaining = Vec::new();
+  connection
+    .backup_with(backup.clone(), 1, |progress| {
+      remaining.push(progress.remaining)
+    })
+    .await
+    .unwrap();
+  assert!(remaining.len() > 1);
+  assert!(remaining.windows(2).all(|pair| pair[0] > pair[1]));
+  assert_eq!(remaining.last(), Some(&0));
+
+  let copy = file.dir.join("copy.affine").to_string_lossy().into_owned();
+  let activities = Arc::new(Mutex::new(Vec::new()));
+  let reported = activities.clone();
+  connection
+    .vacuum_into_with(
+      copy.clone(),
+      Some(move |activity: VacuumActivity| reported.lock().push(activity.steps)),
+    )
+    .await
+    .unwrap();
+  assert!(!activities.lock().is_empty());
+
+  for path in [backup, copy] {
+    let restored = connect(path, None).await;
+    assert_eq!(
+      restored
+        .get_updates(Some("doc".into()), None)
+        .await
+        .unwrap()
+        .len(),
+      10_000
+    );
+  }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_backup_gives_up_under_writes() {
+  let file = TempFile::new("workspace.affine");
+  let connection = connect(file.path(), None).await;
+  let other = connect(file.path(), None).await;
+  connection
+    .insert_updates((0..10_000).map(|_| row(Some("doc"), &[0; 64])).collect())
+    .await
+    .unwrap();
+
+  // another connection writes after every step, so the backup keeps starting
+  // over
+  let backup = file.dir.join("backup.affine");
+  let mut writes = 0u32;
+  let result = connection
+    .backup_with(backup.to_string_lossy().into_owned(), 1, |_| {
+      writes += 1;
+      tokio::task::block_in_place(|| {
+        tokio::runtime::Handle::current().block_on(other.kv_set(
+          "ns".into(),
+          writes.to_string(),
+          b"value".to_vec().into(),
+        ))
+      })
+      .unwrap();
+    })
+    .await;
+  assert!(result.is_err());
+  assert!(!backup.exists());
+  assert!(!file.dir.join("backup.affine.tmp").exists());
+}
+
+#[tokio::test]
+async fn test_vacuum() {
+  let file = TempFile::new("workspace.affine");
+  let connection = connect(file.path(), None).await;
+  for doc_id in ["kept", "deleted"] {
+    connection
+      .insert_updates((0..10_000).map(|_| row(Some(doc_id), &[0; 64])).collect())
+      .await
+      .unwrap();
+  }
+  connection
+    .delete_updates(Some("deleted".into()))
+    .await
+    .unwrap();
+  let before = connection.get_storage_stats(None).await.unwrap();
+  assert!(before.freelist_count > 0);
+
+  let activities = Arc::new(Mutex::new(Vec::new()));
+  let reported = activities.clone();
+  let result = connection
+    .vacuum_with(
+      None,
+      Some(move |activity: VacuumActivity| reported.lock().push(activity.steps)),
+    )
+    .await
+    .unwrap();
+  assert!(!activities.lock().is_empty());
+
+  let after = connection.get_storage_stats(None).await.unwrap();
+  assert_eq!(after.freelist_count, 0);
+  assert!(after.page_count < before.page_count);
+  assert_eq!(result.before_size, before.size);
+  assert_eq!(result.after_size, after.size);
+  assert!(result.reclaimed > 0);
+  assert_eq!(
+    connection
+      .get_updates(Some("kept".into()), None)
+      .await
+      .unwrap()
+      .len(),
+    10_000
+  );
+}
+
+#[tokio::test]
+async fn test_restore_and_purge_doc() {
+  let connection = memory().await;
+  connection
+    .insert_updates(vec![row(Some("doc"), b"update")])
+    .await
+    .unwrap();
+
+  connection.trash_doc("doc".into()).await.unwrap();
+  assert!(connection.restore_doc("doc".into()).await.unwrap());
+  assert!(!connection.restore_doc("doc".into()).await.unwrap());
+  assert!(connection.get_trashed_docs().await.unwrap().is_empty());
+  let deletions = connection.get_doc_deletions_since(None).await.unwrap();
+  assert_eq!(deletions.len(), 1);
+  assert!(deletions[0].restored_at.is_some());
+  assert_eq!(
+    connection
+      .get_updates(Some("doc".into()), None)
+      .await
+      .unwrap()
+      .len(),
+    1
+  );
+
+  connection.trash_doc("doc".into()).await.unwrap();
+  let trashed = connection.get_doc_deletions_since(None).await.unwrap();
+  assert_eq!(trashed.len(), 1);
+  assert!(trashed[0].id > deletions[0].id);
+  assert!(trashed[0].restored_at.is_none());
+  connection
+    .purge_docs(Some(PurgeDocsOptions {
+      retention_period: Some(0),
+    }))
+    .await
+    .unwrap();
+
+  // updates synced after the purge bring the doc back
+  connection
+    .insert_updates(vec![row(Some("doc"), b"synced")])
+    .await
+    .unwrap();
+  let updates = connection
+    .get_updates(Some("doc".into()), None)
+    .await
+    .unwrap();
+  assert_eq!(updates.len(), 1);
+  assert_eq!(updates[0].data.as_ref(), b"synced");
+}
+
+/// A Y update of `client` setting `key` of the root map `meta` to `value`.
+fn meta_update(client: u8, key: &str, value: &str) -> Vec<u8> {
+  root_update(client, "meta", key, value)
+}
+
+/// A Y update of `client` setting `key` of the root map `root` to `value`.
+fn root_update(client: u8, root: &str, key: &str, value: &str) -> Vec<u8> {
+  // one client with a single item at clock 0
+  let mut update = vec![1, 1, client, 0];
+  // no origins, a parent sub and `ContentAny`
+  update.push(0x28);
+  // parent is the root type `root`
+  update.extend([1, root.len() as u8]);
+  update.extend(root.as_bytes());
+  update.push(key.len() as u8);
+  update.extend(key.as_bytes());
+  // a single string
+  update.extend([1, 119, value.len() as u8]);
+  update.extend(value.as_bytes());
+  // empty delete set
+  update.push(0);
+  update
+}
+
+#[tokio::test]
+async fn test_replace_updates_keeps_snapshot() {
+  let connection = memory().await;
+  connection
+    .insert_updates(vec![
+      row(Some("doc"), &meta_update(1, "first", "hello")),
+      row(Some("doc"), &meta_update(2, "second", "world")),
+    ])
+    .await
+    .unwrap();
+  connection
+    .compact_updates(
+      Some("doc".into()),
+      Some(CompactionOptions {
+        snapshot: Some(true),
+      }),
+    )
+    .await
+    .unwrap();
+  connection
+    .insert_updates(vec![row(Some("doc"), &meta_update(3, "third", "again"))])
+    .await
+    .unwrap();
+
+  // what a caller trimming the updates of a doc does
+  let updates = connection
+    .get_updates(Some("doc".into()), None)
+    .await
+    .unwrap();
+  assert_eq!(updates.len(), 1);
+  let merged = merge_updates(updates.iter().map(|row| row.data.as_ref())).unwrap();
+  connection
+    .replace_updates(Some("doc".into()), vec![row(Some("doc"), &merged)])
+    .await
+    .unwrap();
+
+  let loaded = connection
+    .load_doc(
+      Some("doc".into()),
+      Some(LoadDocOptions {
+        merge: Some(true),
+        ..Default::default()
+      }),
+    )
+    .await
+    .unwrap();
+  let doc = apply_updates([loaded.snapshot.unwrap().as_ref()]).unwrap();
+  let mut strings = HashSet::new();
+  assert!(collect_strings(&doc, &mut strings));
+  for value in ["hello", "world", "again"] {
+    assert!(strings.contains(value), "{value} is lost");
+  }
+}
+
+#[tokio::test]
+async fn test_get_doc_at_compacted() {
+  let connection = memory().await;
+  connection
+    .insert_updates(vec![
+      row(Some("doc"), &meta_update(1, "first", "hello")),
+      row(Some("doc"), &meta_update(2, "second", "world")),
+    ])
+    .await
+    .unwrap();
+  sqlx::query(
+    "UPDATE updates SET timestamp = CASE WHEN id = (SELECT MIN(id) FROM updates)
+    THEN '2024-01-01 00:00:00' ELSE '2024-01-02 00:00:00' END",
+  )
+  .execute(&connection.pool)
+  .await
+  .unwrap();
+  let between = chrono::NaiveDate::from_ymd_opt(2024, 1, 1)
+    .unwrap()
+    .and_hms_opt(12, 0, 0)
+    .unwrap();
+  let doc = connection
+    .get_doc_at(Some("doc".into()), between)
+    .await
+    .unwrap()
+    .unwrap();
+  let mut strings = HashSet::new();
+  assert!(collect_strings(
+    &apply_updates([doc.as_ref()]).unwrap(),
+    &mut strings
+  ));
+  assert!(strings.contains("hello"));
+  assert!(!strings.contains("world"));
+
+  connection
+    .compact_updates(Some("doc".into()), None)
+    .await
+    .unwrap();
+  // the compacted row holds both updates, it can not be split at `between`
+  assert!(connection
+    .get_doc_at(Some("doc".into()), between)
+    .await
+    .is_err());
+  let after = between + chrono::Duration::days(2);
+  assert!(connection
+    .get_doc_at(Some("doc".into()), after)
+    .await
+    .unwrap()
+    .is_some());
+}
+
+#[tokio::test]
+async fn test_list_docs() {
+  let connection = memory().await;
+  connection
+    .insert_updates(vec![
+      row(None, b"root"),
+      row(Some("a"), b"first"),
+      row(Some("a"), b"second"),
+      row(Some("b"), b"third"),
+    ])
+    .await
+    .unwrap();
+  connection.trash_doc("b".into()).await.unwrap();
+
+  let docs = connection.list_docs().await.unwrap();
+  let summary = docs
+    .iter()
+    .map(|doc| {
+      (
+        doc.doc_id.as_deref(),
+        doc.updates_count,
+        doc.size,
+        doc.trashed,
+      )
+    })
+    .collect::<Vec<_>>();
+  assert_eq!(
+    summary,
+    vec![
+      (None, 1, 4, false),
+      (Some("a"), 2, 11, false),
+      (Some("b"), 1, 5, true),
+    ]
+  );
+}
+
+#[tokio::test]
+async fn test_updates_page() {
+  let connection = memory().await;
+  let empty = connection
+    .get_updates_page(Some("doc".into()), None, 2, None)
+    .await
+    .unwrap();
+  assert!(empty.updates.is_empty());
+  assert_eq!(empty.next_cursor, None);
+
+  connection
+    .insert_updates(vec![
+      row(Some("doc"), b"first"),
+      row(Some("doc"), b"second"),
+      row(Some("doc"), b"third"),
+      row(Some("doc"), b"fourth"),
+    ])
+    .await
+    .unwrap();
+  // the cursor is the id, rows sharing a timestamp are neither skipped nor
+  // repeated
+  sqlx::query("UPDATE updates SET timestamp = '2024-01-01 00:00:00'")
+    .execute(&connection.pool)
+    .await
+    .unwrap();
+
+  let first = connection
+    .get_updates_page(Some("doc".into()), None, 2, None)
+    .await
+    .unwrap();
+  assert_eq!(first.updates.len(), 2);
+  assert_eq!(first.next_cursor, Some(first.updates[1].id));
+  // a full last page still has a cursor, the page after it is empty
+  let second = connection
+    .get_updates_page(Some("doc".into()), first.next_cursor, 2, None)
+    .await
+    .unwrap();
+  assert_eq!(second.updates[0].data.as_ref(), b"third");
+  assert_eq!(second.updates[1].data.as_ref(), b"fourth");
+  let last = connection
+    .get_updates_page(Some("doc".into()), second.next_cursor, 2, None)
+    .await
+    .unwrap();
+  assert!(last.updates.is_empty());
+  assert_eq!(last.next_cursor, None);
+
+  let last_id = second.updates[1].id;
+  assert!(connection
+    .get_updates_since(Some("doc".into()), last_id, None)
+    .await
+    .unwrap()
+    .is_empty());
+  assert_eq!(
+    connection
+      .get_updates_since(Some("doc".into()), first.updates[0].id, None)
+      .await
+      .unwrap()
+      .len(),
+    3
+  );
+
+  connection.trash_doc("doc".into()).await.unwrap();
+  assert!(connection
+    .get_updates_page(Some("doc".into()), None, 2, None)
+    .await
+    .unwrap()
+    .updates
+    .is_empty());
+  assert!(connection
+    .get_updates_since(Some("doc".into()), 0, None)
+    .await
+    .unwrap()
+    .is_empty());
+  assert_eq!(
+    connection
+      .get_updates_since(Some("doc".into()), 0, Some(true))
+      .await
+      .unwrap()
+      .len(),
+    4
+  );
+}
+
+#[tokio::test]
+async fn test_integrity_repair() {
+  let connection = memory().await;
+  connection
+    .insert_updates(vec![
+      row(Some("doc"), &meta_update(1, "first", "hello")),
+      row(Some("doc"), b"not an update"),
+      row(Some(""), &meta_update(2, "second", "world")),
+    ])
+    .await
+    .unwrap();
+  connection
+    .add_blob("empty".into(), Vec::new().into())
+    .await
+    .unwrap();
+
+  let report = connection.check_integrity(None).await.unwrap();
+  assert!(report.errors.is_empty());
+  assert_eq!(report.corrupted_updates.len(), 1);
+  assert_eq!(report.unexpected_doc_id_updates.len(), 1);
+  assert_eq!(report.empty_blobs, vec!["empty".to_string()]);
+  // only reported without `repair`
+  assert_eq!(report.quarantined, 0);
+  assert_eq!(connection.get_all_updates().await.unwrap().len(), 3);
+
+  let repaired = connection
+    .check_integrity(Some(IntegrityCheckOptions {
+      repair: Some(true),
+      ..Default::default()
+    }))
+    .await
+    .unwrap();
+  assert_eq!(repaired.quarantined, 3);
+  let quarantined: Vec<(String, String, Option<i64>, Option<String>)> =
+    sqlx::query_as("SELECT source, reason, row_id, key FROM quarantine ORDER BY id")
+      .fetch_all(&connection.pool)
+      .await
+      .unwrap();
+  assert_eq!(
+    quarantined,
+    vec![
+      (
+        "updates".into(),
+        "corrupted update".into(),
+        Some(report.corrupted_updates[0]),
+        None
+      ),
+      (
+        "updates".into(),
+        "unexpected doc id".into(),
+        Some(report.unexpected_doc_id_updates[0]),
+        None
+      ),
+      (
+        "blobs".into(),
+        "empty blob".into(),
+        None,
+        Some("empty".into())
+      ),
+    ]
+  );
+  // the rest of the workspace still loads
+  let updates = connection
+    .get_updates(Some("doc".into()), None)
+    .await
+    .unwrap();
+  assert_eq!(updates.len(), 1);
+  assert!(connection.get_blob("empty".into()).await.unwrap().is_none());
+  assert!(connection.check_integrity(None).await.unwrap().is_healthy());
+}
+
+#[tokio::test]
+async fn test_diff_since() {
+  let connection = memory().await;
+  let first = meta_update(1, "first", "hello");
+  connection
+    .insert_updates(vec![
+      row(Some("doc"), &first),
+      row(Some("doc"), &meta_update(2, "second", "world")),
+    ])
+    .await
+    .unwrap();
+  let state_vector = connection
+    .get_state_vector(Some("doc".into()))
+    .await
+    .unwrap();
+
+  // a peer that only has the first update gets the second one
+  let peer = encode_state_vector(&apply_updates([first.as_slice()]).unwrap()).unwrap();
+  let diff = connection
+    .diff_since(Some("doc".into()), Some(peer.into()))
+    .await
+    .unwrap();
+  let mut strings = HashSet::new();
+  assert!(collect_strings(
+    &apply_updates([diff.as_ref()]).unwrap(),
+    &mut strings
+  ));
+  assert!(strings.contains("world"));
+  assert!(!strings.contains("hello"));
+  let synced = apply_updates([first.as_slice(), diff.as_ref()]).unwrap();
+  assert_eq!(encode_state_vector(&synced).unwrap(), state_vector.as_ref());
+
+  // the stored state vector of a snapshot is the same
+  connection
+    .compact_updates(
+      Some("doc".into()),
+      Some(CompactionOptions {
+        snapshot: Some(true),
+      }),
+    )
+    .await
+    .unwrap();
+  assert_eq!(
+    connection
+      .get_state_vector(Some("doc".into()))
+      .await
+      .unwrap()
+      .as_ref(),
+    state_vector.as_ref()
+  );
+}
@@ -0,0 +1,223 @@
+use chrono::{Duration, NaiveDateTime, Utc};
+use napi_derive::napi;
+
+use super::{
+  error::{database_error, Result},
+  immediate::ImmediateTransaction,
+  ChangeEvent, SqliteConnection,
+};
+
+/// Default retention period of trashed docs in seconds, 30 days.
+const DEFAULT_RETENTION_PERIOD: u32 = 30 * 24 * 60 * 60;
+
+#[napi(object)]
+pub struct DocTombstone {
+  /// Grows with every trash, purge and restore, pass the largest one seen as
+  /// `after_id` to `get_doc_deletions_since` to fetch only newer changes.
+  pub id: i64,
+  pub doc_id: String,
+  pub deleted_at: NaiveDateTime,
+  /// `None` while the doc is in the trash and can still be restored.
+  pub purged_at: Option<NaiveDateTime>,
+  /// Set once the doc was taken out of the trash again.
+  pub restored_at: Option<NaiveDateTime>,
+}
+
+#[napi(object)]
+#[derive(Default)]
+pub struct PurgeDocsOptions {
+  /// Keep docs trashed in the last `retention_period` seconds, defaults to 30
+  /// days. `0` purges every trashed doc.
+  pub retention_period: Option<u32>,
+}
+
+#[napi]
+impl SqliteConnection {
+  /// Move a doc into the trash, its updates are kept until it is purged but
+  /// hidden from `get_updates`. Trashing a trashed doc does nothing, a purged
+  /// or restored doc is trashed again.
+  #[napi]
+  pub async fn trash_doc(&self, doc_id: String) -> Result<()> {
+    // reads the tombstone before writing it, take the write lock first
+    let mut transaction = ImmediateTransaction::begin(&self.pool)
+      .await
+      .map_err(database_error)?;
+    if is_trashed(&mut transaction, &doc_id)
+      .await
+      .map_err(database_error)?
+    {
+      return Ok(());
+    }
+    // replace the former tombstone so it gets a new id and shows up in
+    // `get_doc_deletions_since` again
+    sqlx::query!("DELETE FROM doc_tombstones WHERE doc_id = ?", doc_id)
+      .execute(&mut *transaction)
+      .await
+      .map_err(database_error)?;
+    let id = sqlx::query!("INSERT INTO doc_tombstones (doc_id) VALUES (?)", doc_id)
+      .execute(&mut *transaction)
+      .await
+      .map_err(database_error)?
+      .last_insert_rowid();
+    transaction.commit().await.map_err(database_error)?;
+    self.notify(vec![ChangeEvent::tombstone(doc_id, Some(id))]);
+    Ok(())
+  }
+
+  /// Take a doc out of the trash, returns `false` if it is not in the trash or
+  /// was purged already. The tombstone is kept with `restored_at` set, so the
+  /// restore can be synced.
+  #[napi]
+  pub async fn restore_doc(&self, doc_id: String) -> Result<bool> {
+    let mut transaction = ImmediateTransaction::begin(&self.pool)
+      .await
+      .map_err(database_error)?;
+    let Some(tombstone) = sqlx::query!(
+      "SELECT deleted_at FROM doc_tombstones WHERE doc_id = ? AND purged_at IS NULL AND restored_at IS NULL",
+      doc_id
+    )
+    .fetch_optional(&mut *transaction)
+    .await
+    .map_err(database_error)?
+    else {
+      return Ok(false);
+    };
+    // re-insert the tombstone so it gets a new id and shows up in
+    // `get_doc_deletions_since` again
+    sqlx::query!("DELETE FROM doc_tombstones WHERE doc_id = ?", doc_id)
+      .execute(&mut *transaction)
+      .await
+      .map_err(database_error)?;
+    let id = sqlx::query!(
+      "INSERT INTO doc_tombstones (doc_id, deleted_at, restored_at) VALUES (?, ?, CURRENT_TIMESTAMP)",
+      doc_id,
+      tombstone.deleted_at
+    )
+    .execute(&mut *transaction)
+    .await
+    .map_err(database_error)?
+    .last_insert_rowid();
+    transaction.commit().await.map_err(database_error)?;
+    self.notify(vec![ChangeEvent::tombstone(doc_id, Some(id))]);
+    Ok(true)
+  }
+
+  /// List the docs in the trash, ordered by the time they were trashed.
+  #[napi]
+  pub async fn get_trashed_docs(&self) -> Result<Vec<DocTombstone>> {
+    let tombstones = sqlx::query_as!(
+      DocTombstone,
+      r#"SELECT id AS "id!", doc_id, deleted_at, purged_at AS "purged_at: NaiveDateTime", restored_at AS "restored_at: NaiveDateTime"
+      FROM doc_tombstones WHERE purged_at IS NULL AND restored_at IS NULL ORDER BY id"#
+    )
+    .fetch_all(&self.pool)
+    .await
+    .map_err(database_error)?;
+    Ok(tombstones)
+  }
+
+  /// List docs trashed, purged or restored after the tombstone with
+  /// `after_id`, ordered by id.
+  #[napi]
+  pub async fn get_doc_deletions_since(&self, after_id: Option<i64>) -> Result<Vec<DocTombstone>> {
+    let after_id = after_id.unwrap_or(0);
+    let tombstones = sqlx::query_as!(
+      DocTombstone,
+      r#"SELECT id AS "id!", doc_id, deleted_at, purged_at AS "purged_at: NaiveDateTime", restored_at AS "restored_at: NaiveDateTime"
+      FROM doc_tombstones WHERE id > ? ORDER BY id"#,
+      after_id
+    )
+    .fetch_all(&self.pool)
+    .await
+    .map_err(database_error)?;
+    Ok(tombstones)
+  }
+
+  /// Delete the updates of docs trashed before the retention period, their
+  /// tombstones are kept so the deletion can still be synced. Returns the ids
+  /// of the purged docs.
+  #[napi]
+  pub async fn purge_docs(&self, options: Option<PurgeDocsOptions>) -> Result<Vec<String>> {
+    let options = options.unwrap_or_default();
+    let retention_period = options.retention_period.unwrap_or(DEFAULT_RETENTION_PERIOD);
+    let deadline = Utc::now().naive_utc() - Duration::seconds(retention_period as i64);
+
+    let mut transaction = ImmediateTransaction::begin(&self.pool)
+      .await
+      .map_err(database_error)?;
+    let expired = sqlx::query!(
+      "SELECT doc_id, deleted_at FROM doc_tombstones WHERE purged_at IS NULL AND restored_at IS NULL AND deleted_at <= ? ORDER BY id",
+      deadline
+    )
+    .fetch_all(&mut *transaction)
+    .await
+    .map_err(database_error)?;
+
+    let mut events = Vec::new();
+    let mut purged = Vec::new();
+    for tombstone in expired {
+      sqlx::query!("DELETE FROM updates WHERE doc_id = ?", tombstone.doc_id)
+        .execute(&mut *transaction)
+        .await
+        .map_err(database_error)?;
+      sqlx::query!("DELETE FROM snapshots WHERE doc_id = ?", tombstone.doc_id)
+        .execute(&mut *transaction)
+        .await
+        .map_err(database_error)?;
+      sqlx::query!(
+        "DELETE FROM search_blocks WHERE doc_id = ?",
+        tombstone.doc_id
+      )
+      .execute(&mut *transaction)
+      .await
+      .map_err(database_error)?;
+      // re-insert the tombstone so it gets a new id and shows up in
+      // `get_doc_deletions_since` again
+      sqlx::query!(
+        "DELETE FROM doc_tombstones WHERE doc_id = ?",
+        tombstone.doc_id
+      )
+      .execute(&mut *transaction)
+      .await
+      .map_err(database_error)?;
+      let id = sqlx::query!(
+        "INSERT INTO doc_tombstones (doc_id, deleted_at, purged_at) VALUES (?, ?, CURRENT_TIMESTAMP)",
+        tombstone.doc_id,
+        tombstone.deleted_at
+      )
+      .execute(&mut *transaction)
+      .await
+      .map_err(database_error)?
+      .last_insert_rowid();
+      events.push(ChangeEvent::update(Some(tombstone.doc_id.clone()), None));
+      events.push(ChangeEvent::tombstone(tombstone.doc_id.clone(), Some(id)));
+      purged.push(tombstone.doc_id);
+    }
+    transaction.commit().await.map_err(database_error)?;
+
+    if !events.is_empty() {
+      self.notify(events);
+    }
+    Ok(purged)
+  }
+
+  pub(crate) async fn is_trashed(&self, doc_id: &str) -> anyhow::Result<bool> {
+    let mut connection = self.pool.acquire().await?;
+    is_trashed(&mut connection, doc_id).await
+  }
+}
+
+/// Whether the doc is in the trash. Purged docs are not, updates synced after
+/// the purge bring them back.
+pub(crate) async fn is_trashed(
+  connection: &mut sqlx::SqliteConnection,
+  doc_id: &str,
+) -> anyhow::Result<bool> {
+  let tombstone = sqlx::query!(
+    "SELECT id FROM doc_tombstones WHERE doc_id = ? AND purged_at IS NULL AND restored_at IS NULL",
+    doc_id
+  )
+  .fetch_optional(&mut *connection)
+  .await?;
+  Ok(tombstone.is_some())
+}
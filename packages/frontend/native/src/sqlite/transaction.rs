@@ -0,0 +1,410 @@
+use std::{
+  sync::{Arc, Weak},
+  time::Duration,
+};
+
+use napi::bindgen_prelude::Uint8Array;
+use napi_derive::napi;
+use tokio::{sync::OwnedRwLockReadGuard, time::Instant};
+
+use super::{
+  blob::write_blob,
+  encryption::{Cipher, RowIdentity},
+  error::{database_error, log_error, Result},
+  immediate::ImmediateTransaction,
+  kv::{SERVER_CLOCK, SYNC_METADATA},
+  tombstone::is_trashed,
+  BlobRow, ChangeEvent, InsertRow, SqliteConnection, UpdateRow,
+};
+
+/// How long a transaction may stay idle before it is rolled back, in
+/// milliseconds. A forgotten handle would hold the write lock until it is
+/// garbage collected.
+const DEFAULT_IDLE_TIMEOUT: u32 = 30_000;
+
+type SharedState = Arc<tokio::sync::Mutex<Option<State>>>;
+
+struct State {
+  transaction: ImmediateTransaction,
+  /// Held until the transaction is finished, the key can not be rotated while
+  /// it is open.
+  cipher: OwnedRwLockReadGuard<Cipher>,
+  /// Reported to subscribers once committed.
+  events: Vec<ChangeEvent>,
+  /// Docs to index again once committed.
+  doc_ids: Vec<String>,
+  /// Start of the last operation, see `roll_back_when_idle`.
+  last_used: Instant,
+}
+
+impl State {
+  fn touch(&mut self, doc_id: Option<String>) {
+    if let Some(doc_id) = doc_id {
+      if !self.doc_ids.contains(&doc_id) {
+        self.doc_ids.push(doc_id);
+      }
+    }
+  }
+}
+
+const FINISHED: &str = "Transaction is already committed, rolled back or timed out";
+
+fn active(state: &mut Option<State>) -> Result<&mut State> {
+  let state = state
+    .as_mut()
+    .ok_or_else(|| database_error(anyhow::anyhow!(FINISHED)))?;
+  state.last_used = Instant::now();
+  Ok(state)
+}
+
+/// Roll the transaction back once no operation started for `idle_timeout`.
+/// Only holds a weak reference, a collected handle still rolls back right
+/// away.
+async fn roll_back_when_idle(
+  state: Weak<tokio::sync::Mutex<Option<State>>>,
+  idle_timeout: Duration,
+) {
+  loop {
+    let Some(state) = state.upgrade() else {
+      return;
+    };
+    let deadline = {
+      let mut state = state.lock().await;
+      let Some(active) = state.as_ref() else {
+        return;
+      };
+      let deadline = active.last_used + idle_timeout;
+      if Instant::now() >= deadline {
+        if let Some(idle) = state.take() {
+          if let Err(err) = idle.transaction.rollback().await {
+            log_error("failed to roll back an idle transaction", err);
+          }
+        }
+        return;
+      }
+      deadline
+    };
+    drop(state);
+    tokio::time::sleep_until(deadline).await;
+  }
+}
+
+/// Handle of a transaction started by `begin_transaction`. Rolled back if it
+/// is garbage collected or stays idle for its timeout before `commit` is
+/// called. Trashing, restoring and snapshotting docs are not part of the
+/// transaction, call them on the connection once it is committed.
+#[napi]
+pub struct SqliteTransaction {
+  state: SharedState,
+  connection: SqliteConnection,
+}
+
+#[napi]
+impl SqliteConnection {
+  /// Start a transaction, every operation of the returned handle is applied
+  /// atomically on `commit`. Writes through other handles or the connection
+  /// itself wait for it up to the busy timeout, 5 seconds by default, and fail
+  /// with `BUSY` after that. It is rolled back once no operation started for
+  /// `idle_timeout` milliseconds, defaults to 30 seconds. The handle holds the
+  /// encryption key until it is finished, `rotate_encryption_key` waits for it
+  /// and every other call of the connection waits behind a pending rotation.
+  #[napi]
+  pub async fn begin_transaction(&self, idle_timeout: Option<u32>) -> Result<SqliteTransaction> {
+    let cipher = self.cipher.clone().read_owned().await;
+    // takes the write lock right away, reads before the first write can not
+    // fail with `BUSY` once another connection writes
+    let transaction = ImmediateTransaction::begin(&self.pool)
+      .await
+      .map_err(database_error)?;
+    let state = Arc::new(tokio::sync::Mutex::new(Some(State {
+      transaction,
+      cipher,
+      events: Vec::new(),
+      doc_ids: Vec::new(),
+      last_used: Instant::now(),
+    })));
+    let idle_timeout = idle_timeout.unwrap_or(DEFAULT_IDLE_TIMEOUT);
+    tokio::spawn(roll_back_when_idle(
+      Arc::downgrade(&state),
+      Duration::from_millis(idle_timeout.into()),
+    ));
+    Ok(SqliteTransaction {
+      state,
+      connection: self.clone(),
+    })
+  }
+}
+
+#[napi]
+impl SqliteTransaction {
+  #[napi]
+  pub async fn add_blob(&self, key: String, blob: Uint8Array) -> Result<()> {
+    let mut state = self.state.lock().await;
+    let state = active(&mut state)?;
+    write_blob(&mut state.transaction, &state.cipher, &key, blob.as_ref())
+      .await
+      .map_err(database_error)?;
+    state.events.push(ChangeEvent::keyed("blobs", Some(key)));
+    Ok(())
+  }
+
+  #[napi]
+  pub async fn get_blob(&self, key: String) -> Result<Option<BlobRow>> {
+    let mut state = self.state.lock().await;
+    let state = active(&mut state)?;
+    let row = sqlx::query_as!(
+      BlobRow,
+      "SELECT key, data, timestamp FROM blobs WHERE key = ?",
+      key
+    )
+    .fetch_optional(&mut *state.transaction)
+    .await
+    .map_err(database_error)?;
+    row
+      .map(|row| state.cipher.open_blob_row(row))
+      .transpose()
+      .map_err(database_error)
+  }
+
+  #[napi]
+  pub async fn delete_blob(&self, key: String) -> Result<()> {
+    let mut state = self.state.lock().await;
+    let state = active(&mut state)?;
+    sqlx::query!("DELETE FROM blobs WHERE key = ?", key)
+      .execute(&mut *state.transaction)
+      .await
+      .map_err(database_error)?;
+    state.events.push(ChangeEvent::keyed("blobs", Some(key)));
+    Ok(())
+  }
+
+  /// Same as `SqliteConnection::get_updates`, including the writes of this
+  /// transaction.
+  #[napi]
+  pub async fn get_updates(
+    &self,
+    doc_id: Option<String>,
+    include_trashed: Option<bool>,
+  ) -> Result<Vec<UpdateRow>> {
+    let mut state = self.state.lock().await;
+    let state = active(&mut state)?;
+    if let Some(doc_id) = &doc_id {
+      if !include_trashed.unwrap_or(false) {
+        if is_trashed(&mut state.transaction, doc_id)
+          .await
+          .map_err(database_error)?
+        {
+          return Ok(Vec::new());
+        }
+      }
+    }
+    let updates = sqlx::query_as!(
+      UpdateRow,
+      "SELECT id, timestamp, data, doc_id FROM updates WHERE doc_id IS ? ORDER BY id",
+      doc_id
+    )
+    .fetch_all(&mut *state.transaction)
+    .await
+    .map_err(database_error)?;
+    state
+      .cipher
+      .open_update_rows(updates)
+      .map_err(database_error)
+  }
+
+  /// Insert updates, the compaction threshold is only checked by inserts of
+  /// the connection.
+  #[napi]
+  pub async fn insert_updates(&self, updates: Vec<InsertRow>) -> Result<()> {
+    let mut state = self.state.lock().await;
+    let state = active(&mut state)?;
+    for InsertRow { data, doc_id } in updates {
+      let update = state
+        .cipher
+        .seal(data.as_ref(), &RowIdentity::update(doc_id.as_deref()))
+        .map_err(database_error)?;
+      let update = update.as_ref();
+      let id = sqlx::query!(
+        "INSERT INTO updates (data, doc_id) VALUES ($1, $2)",
+        update,
+        doc_id
+      )
+      .execute(&mut *state.transaction)
+      .await
+      .map_err(database_error)?
+      .last_insert_rowid();
+      state
+        .events
+        .push(ChangeEvent::update(doc_id.clone(), Some(id)));
+      state.touch(doc_id);
+    }
+    Ok(())
+  }
+
+  /// Delete every update of a doc together with its snapshot.
+  #[napi]
+  pub async fn delete_updates(&self, doc_id: Option<String>) -> Result<()> {
+    let mut state = self.state.lock().await;
+    let state = active(&mut state)?;
+    sqlx::query!("DELETE FROM updates WHERE doc_id IS ?", doc_id)
+      .execute(&mut *state.transaction)
+      .await
+      .map_err(database_error)?;
+    sqlx::query!("DELETE FROM snapshots WHERE doc_id IS ?", doc_id)
+      .execute(&mut *state.transaction)
+      .await
+      .map_err(database_error)?;
+    state.events.push(ChangeEvent::update(doc_id.clone(), None));
+    state.touch(doc_id);
+    Ok(())
+  }
+
+  /// Replace every update of a doc, same as
+  /// `SqliteConnection::replace_updates`.
+  #[napi]
+  pub async fn replace_updates(
+    &self,
+    doc_id: Option<String>,
+    updates: Vec<InsertRow>,
+  ) -> Result<()> {
+    let mut state = self.state.lock().await;
+    let state = active(&mut state)?;
+    sqlx::query!("DELETE FROM updates WHERE doc_id IS ?", doc_id)
+      .execute(&mut *state.transaction)
+      .await
+      .map_err(database_error)?;
+    state.events.push(ChangeEvent::update(doc_id.clone(), None));
+    for InsertRow { data, doc_id } in updates {
+      let update = state
+        .cipher
+        .seal(data.as_ref(), &RowIdentity::update(doc_id.as_deref()))
+        .map_err(database_error)?;
+      let update = update.as_ref();
+      let id = sqlx::query!(
+        "INSERT INTO updates (data, doc_id) VALUES ($1, $2)",
+        update,
+        doc_id
+      )
+      .execute(&mut *state.transaction)
+      .await
+      .map_err(database_error)?
+      .last_insert_rowid();
+      state
+        .events
+        .push(ChangeEvent::update(doc_id.clone(), Some(id)));
+      state.touch(doc_id);
+    }
+    state.touch(doc_id);
+    Ok(())
+  }
+
+  #[napi]
+  pub async fn kv_get(&self, namespace: String, key: String) -> Result<Option<BlobRow>> {
+    let mut state = self.state.lock().await;
+    let state = active(&mut state)?;
+    let row = sqlx::query_as!(
+      BlobRow,
+      "SELECT key, data, timestamp FROM kv WHERE namespace = ? AND key = ?",
+      namespace,
+      key
+    )
+    .fetch_optional(&mut *state.transaction)
+    .await
+    .map_err(database_error)?;
+    row
+      .map(|row| state.cipher.open_kv_row(&namespace, row))
+      .transpose()
+      .map_err(database_error)
+  }
+
+  #[napi]
+  pub async fn kv_set(&self, namespace: String, key: String, data: Uint8Array) -> Result<()> {
+    let mut state = self.state.lock().await;
+    let state = active(&mut state)?;
+    let data = state
+      .cipher
+      .seal(data.as_ref(), &RowIdentity::kv(&namespace, &key))
+      .map_err(database_error)?;
+    let data = data.as_ref();
+    sqlx::query!(
+      "INSERT INTO kv (namespace, key, data) VALUES ($1, $2, $3) ON CONFLICT(namespace, key) DO UPDATE SET data = excluded.data",
+      namespace,
+      key,
+      data
+    )
+    .execute(&mut *state.transaction)
+    .await
+    .map_err(database_error)?;
+    state.events.push(ChangeEvent::keyed(&namespace, Some(key)));
+    Ok(())
+  }
+
+  #[napi]
+  pub async fn kv_delete(&self, namespace: String, key: String) -> Result<()> {
+    let mut state = self.state.lock().await;
+    let state = active(&mut state)?;
+    sqlx::query!(
+      "DELETE FROM kv WHERE namespace = ? AND key = ?",
+      namespace,
+      key
+    )
+    .execute(&mut *state.transaction)
+    .await
+    .map_err(database_error)?;
+    state.events.push(ChangeEvent::keyed(&namespace, Some(key)));
+    Ok(())
+  }
+
+  #[napi]
+  pub async fn get_server_clock(&self, key: String) -> Result<Option<BlobRow>> {
+    self.kv_get(SERVER_CLOCK.to_string(), key).await
+  }
+
+  #[napi]
+  pub async fn set_server_clock(&self, key: String, data: Uint8Array) -> Result<()> {
+    self.kv_set(SERVER_CLOCK.to_string(), key, data).await
+  }
+
+  #[napi]
+  pub async fn del_server_clock(&self, key: String) -> Result<()> {
+    self.kv_delete(SERVER_CLOCK.to_string(), key).await
+  }
+
+  #[napi]
+  pub async fn get_sync_metadata(&self, key: String) -> Result<Option<BlobRow>> {
+    self.kv_get(SYNC_METADATA.to_string(), key).await
+  }
+
+  #[napi]
+  pub async fn set_sync_metadata(&self, key: String, data: Uint8Array) -> Result<()> {
+    self.kv_set(SYNC_METADATA.to_string(), key, data).await
+  }
+
+  #[napi]
+  pub async fn del_sync_metadata(&self, key: String) -> Result<()> {
+    self.kv_delete(SYNC_METADATA.to_string(), key).await
+  }
+
+  /// Apply every operation of this transaction, the handle can not be used
+  /// afterwards.
+  #[napi]
+  pub async fn commit(&self) -> Result<()> {
+    let Some(state) = self.state.lock().await.take() else {
+      return Err(database_error(anyhow::anyhow!(FINISHED)));
+    };
+    state.transaction.commit().await.map_err(database_error)?;
+    self.connection.notify(state.events);
+    self.connection.schedule_index(state.doc_ids);
+    Ok(())
+  }
+
+  /// Discard every operation of this transaction, does nothing if it is
+  /// already finished.
+  #[napi]
+  pub async fn rollback(&self) -> Result<()> {
+    if let Some(state) = self.state.lock().await.take() {
+      state.transaction.rollback().await.map_err(database_error)?;
+    }
+    Ok(())
+  }
+}
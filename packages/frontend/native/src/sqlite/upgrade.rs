@@ -0,0 +1,166 @@
+use std::path::Path;
+
+use anyhow::Context;
+use napi_derive::napi;
+use sqlx::Row;
+
+use super::{
+  error::{database_error, Result},
+  migration::LATEST_VERSION,
+  MigrationRecord, SqliteConnection, ValidationReport, ValidationResult,
+};
+
+/// Tables whose rows have to survive an upgrade unchanged.
+const PRESERVED_TABLES: &[&str] = &["updates", "blobs"];
+
+/// Suffixes of the files sqlite keeps next to a database.
+const COMPANION_SUFFIXES: &[&str] = &["-wal", "-shm"];
+
+#[napi(object)]
+pub struct UpgradeResult {
+  /// Result of `validate` before the upgrade.
+  pub previous: ValidationResult,
+  /// Copy of the original file, kept after a successful upgrade. `None` if
+  /// the file was already up to date.
+  pub backup_path: Option<String>,
+  /// Migrations applied by the upgrade.
+  pub migrations: Vec<MigrationRecord>,
+}
+
+fn row_count(report: &ValidationReport, table: &str) -> i64 {
+  report
+    .row_counts
+    .iter()
+    .find(|row| row.table == table)
+    .map_or(0, |row| row.count)
+}
+
+/// Copy `from` to `to` together with its write-ahead log, so no committed
+/// write is lost. Stale companions of `to` are removed.
+async fn copy_database(from: &str, to: &str) -> anyhow::Result<()> {
+  tokio::fs::copy(from, to)
+    .await
+    .with_context(|| format!("failed to copy {from} to {to}"))?;
+  for suffix in COMPANION_SUFFIXES {
+    let (from, to) = (format!("{from}{suffix}"), format!("{to}{suffix}"));
+    tokio::fs::remove_file(&to).await.ok();
+    // the shared memory index is rebuilt from the log
+    if *suffix == "-wal" && Path::new(&from).exists() {
+      tokio::fs::copy(&from, &to)
+        .await
+        .with_context(|| format!("failed to copy {from} to {to}"))?;
+    }
+  }
+  Ok(())
+}
+
+/// Run every migration on the file at `path` and check the result against
+/// the report taken before.
+async fn migrate_file(
+  path: &str,
+  before: &ValidationReport,
+) -> anyhow::Result<Vec<MigrationRecord>> {
+  let connection = SqliteConnection::new(path.to_string(), None)?;
+  let migrated = async {
+    let migrations = connection.migrate().await?;
+    let integrity: String = sqlx::query("PRAGMA integrity_check")
+      .fetch_one(&connection.pool)
+      .await
+      .map_err(database_error)?
+      .get(0);
+    if integrity != "ok" {
+      return Err(database_error(anyhow::anyhow!(
+        "integrity check failed: {integrity}"
+      )));
+    }
+    Result::Ok(migrations)
+  }
+  .await;
+  // the file has to be released before it can be verified or restored
+  connection.close().await;
+  let migrations = migrated.map_err(|err| anyhow::anyhow!("{err}"))?;
+
+  let after = SqliteConnection::get_validation_report(path.to_string()).await;
+  if !matches!(after.result, ValidationResult::Valid) {
+    anyhow::bail!(
+      "workspace is still invalid after migrating: {}",
+      after
+        .error
+        .as_deref()
+        .unwrap_or("missing tables or columns")
+    );
+  }
+  if after.schema_version != Some(LATEST_VERSION as i64) {
+    anyhow::bail!(
+      "workspace is at version {:?} after migrating, expected {LATEST_VERSION}",
+      after.schema_version
+    );
+  }
+  for table in PRESERVED_TABLES {
+    let (expected, actual) = (row_count(before, table), row_count(&after, table));
+    if expected != actual {
+      anyhow::bail!("{table} has {actual} rows after migrating, expected {expected}");
+    }
+  }
+  Ok(migrations)
+}
+
+#[napi]
+impl SqliteConnection {
+  /// Bring a workspace file written by an older release up to the latest
+  /// version, replaces fixing `MissingDocIdColumn` and `MissingVersionColumn`
+  /// by hand. The original file is copied to a backup first and restored if
+  /// any migration or the verification of the result fails. The file must
+  /// not be open while it is upgraded.
+  #[napi]
+  pub async fn upgrade(path: String) -> Result<UpgradeResult> {
+    let before = Self::get_validation_report(path.clone()).await;
+    match before.result {
+      ValidationResult::MissingTables | ValidationResult::GeneralError => {
+        return Err(database_error(anyhow::anyhow!(
+          "Can not upgrade {path}: {}",
+          before.error.as_deref().unwrap_or("missing tables")
+        )));
+      }
+      ValidationResult::Valid if before.schema_version == Some(LATEST_VERSION as i64) => {
+        return Ok(UpgradeResult {
+          previous: before.result,
+          backup_path: None,
+          migrations: Vec::new(),
+        });
+      }
+      _ => {}
+    }
+    if before.schema_version > Some(LATEST_VERSION as i64) {
+      return Err(database_error(anyhow::anyhow!(
+          "Can not upgrade {path}: version {:?} is newer than the latest supported version {LATEST_VERSION}",
+          before.schema_version
+        )));
+    }
+
+    let backup_path = format!(
+      "{path}.{}.backup",
+      chrono::Utc::now().format("%Y%m%d%H%M%S")
+    );
+    copy_database(&path, &backup_path)
+      .await
+      .map_err(database_error)?;
+
+    match migrate_file(&path, &before).await {
+      Ok(migrations) => Ok(UpgradeResult {
+        previous: before.result,
+        backup_path: Some(backup_path),
+        migrations,
+      }),
+      Err(err) => {
+        copy_database(&backup_path, &path)
+          .await
+          .with_context(|| format!("failed to restore {path} from {backup_path}"))
+          .map_err(database_error)?;
+        Err(database_error(anyhow::anyhow!(
+          "Upgrade of {path} failed and was rolled back: {err:#}"
+        )))
+      }
+    }
+  }
+}
@@ -0,0 +1,204 @@
+use anyhow::Context;
+use napi_derive::napi;
+use sqlx::{
+  sqlite::{Sqlite, SqlitePoolOptions},
+  Pool, Row,
+};
+use tokio::io::AsyncReadExt;
+
+use super::{SqliteConnection, ValidationResult};
+
+/// Every sqlite database file starts with this header.
+const SQLITE_HEADER: &[u8; 16] = b"SQLite format 3\0";
+
+/// Tables and columns a workspace file needs before it can be migrated, newer
+/// tables are created by the migrations on connect.
+const REQUIRED_SCHEMA: &[(&str, &[&str])] = &[
+  ("updates", &["id", "data", "timestamp", "doc_id"]),
+  ("blobs", &["key", "data", "timestamp"]),
+  ("version_info", &["version", "timestamp"]),
+];
+
+#[napi(object)]
+pub struct TableRowCount {
+  pub table: String,
+  pub count: i64,
+}
+
+#[napi(object)]
+pub struct ValidationReport {
+  pub result: ValidationResult,
+  /// Latest version in `version_info`, `None` if the file is not versioned.
+  pub schema_version: Option<i64>,
+  pub missing_tables: Vec<String>,
+  /// Missing columns of existing tables, in `table.column` form.
+  pub missing_columns: Vec<String>,
+  pub row_counts: Vec<TableRowCount>,
+  /// Size of the file in bytes.
+  pub file_size: Option<i64>,
+  /// Whether the file starts with the sqlite header.
+  pub is_sqlite: bool,
+  /// Version of the sqlite library reading the file.
+  pub sqlite_version: Option<String>,
+  pub page_size: Option<i64>,
+  pub page_count: Option<i64>,
+  pub journal_mode: Option<String>,
+  pub encoding: Option<String>,
+  /// Why the file could not be read, set if `result` is `GeneralError`.
+  pub error: Option<String>,
+}
+
+impl ValidationReport {
+  fn new() -> Self {
+    Self {
+      result: ValidationResult::GeneralError,
+      schema_version: None,
+      missing_tables: Vec::new(),
+      missing_columns: Vec::new(),
+      row_counts: Vec::new(),
+      file_size: None,
+      is_sqlite: false,
+      sqlite_version: None,
+      page_size: None,
+      page_count: None,
+      journal_mode: None,
+      encoding: None,
+      error: None,
+    }
+  }
+
+  fn is_missing(&self, table: &str, column: Option<&str>) -> bool {
+    match column {
+      Some(column) => self
+        .missing_columns
+        .iter()
+        .any(|missing| missing == &format!("{table}.{column}")),
+      None => self.missing_tables.iter().any(|missing| missing == table),
+    }
+  }
+
+  fn result(&self) -> ValidationResult {
+    if self.is_missing("updates", None) || self.is_missing("blobs", None) {
+      ValidationResult::MissingTables
+    } else if self.is_missing("updates", Some("doc_id")) {
+      ValidationResult::MissingDocIdColumn
+    } else if self.is_missing("version_info", None) {
+      ValidationResult::MissingVersionColumn
+    } else {
+      ValidationResult::Valid
+    }
+  }
+
+  async fn inspect_file(&mut self, path: &str) -> anyhow::Result<()> {
+    let mut file = tokio::fs::File::open(path)
+      .await
+      .with_context(|| format!("failed to open {path}"))?;
+    self.file_size = Some(file.metadata().await?.len() as i64);
+    let mut header = [0; SQLITE_HEADER.len()];
+    self.is_sqlite = file.read_exact(&mut header).await.is_ok() && &header == SQLITE_HEADER;
+    Ok(())
+  }
+
+  async fn inspect_database(&mut self, pool: &Pool<Sqlite>) -> anyhow::Result<()> {
+    self.sqlite_version = Some(
+      sqlx::query("SELECT sqlite_version()")
+        .fetch_one(pool)
+        .await?
+        .get(0),
+    );
+    self.page_size = Some(pragma(pool, "page_size").await?.get(0));
+    self.page_count = Some(pragma(pool, "page_count").await?.get(0));
+    self.journal_mode = Some(pragma(pool, "journal_mode").await?.get(0));
+    self.encoding = Some(pragma(pool, "encoding").await?.get(0));
+
+    let tables: Vec<String> = sqlx::query(
+      "SELECT name FROM sqlite_master WHERE type='table' AND name NOT LIKE 'sqlite_%' ORDER BY name",
+    )
+    .fetch_all(pool)
+    .await?
+    .iter()
+    .map(|row| row.get(0))
+    .collect();
+
+    for (table, columns) in REQUIRED_SCHEMA {
+      if !tables.iter().any(|name| name == table) {
+        self.missing_tables.push(table.to_string());
+        continue;
+      }
+      let existing: Vec<String> = sqlx::query("SELECT name FROM pragma_table_info(?)")
+        .bind(table)
+        .fetch_all(pool)
+        .await?
+        .iter()
+        .map(|row| row.get(0))
+        .collect();
+      self.missing_columns.extend(
+        columns
+          .iter()
+          .filter(|column| !existing.iter().any(|name| name == *column))
+          .map(|column| format!("{table}.{column}")),
+      );
+    }
+
+    for table in tables {
+      let count = sqlx::query(&format!(
+        r#"SELECT COUNT(*) FROM "{}""#,
+        table.replace('"', r#""""#)
+      ))
+      .fetch_one(pool)
+      .await?
+      .get(0);
+      if table == "version_info" {
+        self.schema_version = sqlx::query("SELECT MAX(version) FROM version_info")
+          .fetch_one(pool)
+          .await?
+          .get(0);
+      }
+      self.row_counts.push(TableRowCount { table, count });
+    }
+    Ok(())
+  }
+}
+
+async fn pragma(pool: &Pool<Sqlite>, name: &str) -> sqlx::Result<sqlx::sqlite::SqliteRow> {
+  sqlx::query(&format!("PRAGMA {name}")).fetch_one(pool).await
+}
+
+#[napi]
+impl SqliteConnection {
+  /// Inspect a workspace file before importing it and report every problem
+  /// found, `validate` only returns the first one.
+  #[napi]
+  pub async fn get_validation_report(path: String) -> ValidationReport {
+    let mut report = ValidationReport::new();
+    if let Err(err) = report.inspect_file(&path).await {
+      report.error = Some(format!("{err:#}"));
+      return report;
+    }
+    // sqlite treats an empty file as an empty database
+    if !report.is_sqlite && report.file_size != Some(0) {
+      report.error = Some(format!("{path} is not a sqlite database"));
+      return report;
+    }
+
+    let pool = match SqlitePoolOptions::new()
+      .max_connections(1)
+      .connect(&path)
+      .await
+    {
+      Ok(pool) => pool,
+      Err(err) => {
+        report.error = Some(err.to_string());
+        return report;
+      }
+    };
+    let inspected = report.inspect_database(&pool).await;
+    pool.close().await;
+
+    match inspected {
+      Ok(()) => report.result = report.result(),
+      Err(err) => report.error = Some(format!("{err:#}")),
+    }
+    report
+  }
+}
@@ -0,0 +1,128 @@
+use std::collections::HashSet;
+
+use y_octo::{Any, CrdtRead, CrdtWrite, Doc, RawDecoder, RawEncoder, StateVector, Value};
+
+/// Apply updates in form like `Y.applyUpdate(doc, update)` way.
+pub(crate) fn apply_updates<'a, I>(updates: I) -> anyhow::Result<Doc>
+where
+  I: IntoIterator<Item = &'a [u8]>,
+{
+  let mut doc = Doc::default();
+  for update in updates {
+    doc
+      .apply_update_from_binary_v1(update)
+      .map_err(|err| anyhow::anyhow!("failed to apply update: {err}"))?;
+  }
+  Ok(doc)
+}
+
+/// Merge updates in form like `Y.applyUpdate(doc, update)` way and return the
+/// result binary.
+pub(crate) fn merge_updates<'a, I>(updates: I) -> anyhow::Result<Vec<u8>>
+where
+  I: IntoIterator<Item = &'a [u8]>,
+{
+  apply_updates(updates)?
+    .encode_update_v1()
+    .map_err(|err| anyhow::anyhow!("failed to encode update: {err}"))
+}
+
+/// Encode the state vector of a doc in form like `Y.encodeStateVector(doc)`.
+pub(crate) fn encode_state_vector(doc: &Doc) -> anyhow::Result<Vec<u8>> {
+  let mut encoder = RawEncoder::default();
+  doc
+    .get_state_vector()
+    .write(&mut encoder)
+    .map_err(|err| anyhow::anyhow!("failed to encode state vector: {err}"))?;
+  Ok(encoder.into_inner())
+}
+
+/// Decode a state vector encoded by `Y.encodeStateVector(doc)`.
+pub(crate) fn decode_state_vector(state_vector: &[u8]) -> anyhow::Result<StateVector> {
+  StateVector::read(&mut RawDecoder::new(state_vector.to_vec()))
+    .map_err(|err| anyhow::anyhow!("failed to decode state vector: {err}"))
+}
+
+/// Collect every string stored anywhere in the doc, including map keys and
+/// values nested in maps, arrays and objects of every root type. Blobs are
+/// referenced by key from props like `prop:sourceId` and `avatar`, but
+/// collecting all of them keeps the result conservative for unknown flavours.
+/// Returns `false` if a root type can not be read as a map, the strings of the
+/// doc are unknown then.
+pub(crate) fn collect_strings(doc: &Doc, strings: &mut HashSet<String>) -> bool {
+  for name in doc.keys() {
+    match doc.get_map(&name) {
+      Ok(map) => collect_value_strings(Value::Map(map), strings),
+      Err(_) => return false,
+    }
+  }
+  true
+}
+
+fn collect_value_strings(value: Value, strings: &mut HashSet<String>) {
+  match value {
+    Value::Any(any) => collect_any_strings(any, strings),
+    Value::Map(map) => {
+      for (key, value) in map.iter() {
+        strings.insert(key.to_string());
+        collect_value_strings(value, strings);
+      }
+    }
+    Value::Array(array) => {
+      for value in array.iter() {
+        collect_value_strings(value, strings);
+      }
+    }
+    Value::Text(text) => {
+      strings.insert(text.to_string());
+    }
+    _ => {}
+  }
+}
+
+fn collect_any_strings(any: Any, strings: &mut HashSet<String>) {
+  match any {
+    Any::String(value) => {
+      strings.insert(value);
+    }
+    Any::Object(object) => {
+      for (key, value) in object {
+        strings.insert(key);
+        collect_any_strings(value, strings);
+      }
+    }
+    Any::Array(values) => {
+      for value in values {
+        collect_any_strings(value, strings);
+      }
+    }
+    _ => {}
+  }
+}
+
+/// Collect the rich text of every block keyed by block id, blocks without text
+/// are skipped. Texts of a block with several rich text props are joined by
+/// new lines.
+pub(crate) fn collect_block_texts(doc: &Doc) -> anyhow::Result<Vec<(String, String)>> {
+  let blocks = doc
+    .get_map("blocks")
+    .map_err(|err| anyhow::anyhow!("failed to read blocks: {err}"))?;
+  let mut texts = Vec::new();
+  for (block_id, block) in blocks.iter() {
+    if let Value::Map(block) = block {
+      let text = block
+        .iter()
+        .filter_map(|(_, prop)| match prop {
+          Value::Text(text) => Some(text.to_string()),
+          _ => None,
+        })
+        .filter(|text| !text.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n");
+      if !text.is_empty() {
+        texts.push((block_id, text));
+      }
+    }
+  }
+  Ok(texts)
+}